@@ -0,0 +1,8 @@
+// only the command line, it builds without the gui so it runs where there's no display
+fn main() {
+    let code = v_customizer::cli::main(std::env::args().skip(1)).unwrap_or_else(|| {
+        eprintln!("{}", v_customizer::cli::USAGE);
+        2
+    });
+    std::process::exit(code);
+}
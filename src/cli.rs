@@ -0,0 +1,846 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::json;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::sca::cache::Cache;
+use crate::sca::discovery;
+use crate::sca::install::{self, Install, Output};
+use crate::sca::journal::Recovery;
+use crate::sca::layers::{Field, Layer, Level, Source, FIELDS};
+use crate::sca::profile::{Profile, Profiles};
+use crate::sca::scheduler::{OnError, Status};
+use crate::sca::worker::{Event, Options, Worker};
+use crate::sca::{Class, ExistingOrigin, Sca};
+use crate::vpk::VpkReader;
+
+pub const USAGE: &str = "Usage: v_customizer [--tf <folder>] [--studiomdl <exe>] [--sca <folder>] \
+[--runner <runner>] [--jobs <count>] [--cache <folder>] <command>
+commands: generate, set, reset, existing-origin, weapons, profile, save, load, orphans, \
+recover, list, extract, verify, clear-cache";
+
+// takes the path overrides out of args and runs the command after them, returns the exit code,
+// None if there's no command, the gui opens then
+pub fn main<I: IntoIterator<Item = String>>(args: I) -> Option<i32> {
+    let (overrides, rest) = match discovery::Overrides::from_args(args) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            attach_console();
+            eprintln!("{}", error);
+            return Some(2);
+        }
+    };
+    discovery::set_cli_overrides(overrides);
+    if rest.is_empty() {
+        return None;
+    }
+    attach_console();
+    match run(&rest) {
+        Ok(()) => Some(0),
+        Err(error) => {
+            eprintln!("{:#}", error);
+            Some(1)
+        }
+    }
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let command = args.first().ok_or_else(|| anyhow!(USAGE))?;
+    match command.as_str() {
+        "recover" => recover(&args[1..]),
+        "list" => list(&args[1..]),
+        "extract" => extract(&args[1..]),
+        "verify" => verify(&args[1..]),
+        "clear-cache" => clear_cache(&args[1..]),
+        "weapons" => weapons(&args[1..]),
+        "set" => set(&args[1..]),
+        "reset" => reset(&args[1..]),
+        "existing-origin" => existing_origin(&args[1..]),
+        "save" => save(&args[1..]),
+        "load" => load(&args[1..]),
+        "generate" => generate(&args[1..]),
+        "profile" => profile(&args[1..]),
+        "orphans" => orphans(&args[1..]),
+        command => bail!("Unknown command {}", command),
+    }
+}
+
+// v_customizer recover [--roll-back | --finish]
+fn recover(args: &[String]) -> Result<()> {
+    let recovery = Recovery::scan()?;
+    if recovery.is_empty() {
+        println!("Nothing to recover");
+        return Ok(());
+    }
+    for line in recovery.describe() {
+        println!("{}", line);
+    }
+    match args.first().map(String::as_str) {
+        None => {
+            println!();
+            println!("Run `v_customizer recover --roll-back` to undo the unfinished runs");
+            if recovery.can_finish() {
+                println!("or `v_customizer recover --finish` to install what they built");
+            }
+        }
+        Some("--roll-back") => {
+            recovery.roll_back()?;
+            println!("Rolled back");
+        }
+        Some("--finish") => {
+            recovery.finish()?;
+            println!("Finished");
+        }
+        Some(arg) => bail!("Unknown argument {}", arg),
+    }
+    Ok(())
+}
+
+// v_customizer clear-cache
+fn clear_cache(args: &[String]) -> Result<()> {
+    if let Some(arg) = args.first() {
+        bail!("Unknown argument {}", arg);
+    }
+    match discovery::cache_folder()? {
+        Some(folder) => {
+            let removed = Cache::clear(&folder)?;
+            println!(
+                "Removed {} cached models from {}",
+                removed,
+                folder.display()
+            );
+        }
+        None => println!("The compile cache is turned off"),
+    }
+    Ok(())
+}
+
+// v_customizer list [--vpk <file>]
+fn list(args: &[String]) -> Result<()> {
+    let (vpk, args) = open_vpk(args)?;
+    if let Some(arg) = args.first() {
+        bail!("Unknown argument {}", arg);
+    }
+    let mut total = 0;
+    for entry in vpk.entries() {
+        println!("{:>10} {:08x} {}", entry.size(), entry.crc, entry.path);
+        total += entry.size();
+    }
+    println!("{} files, {} bytes", vpk.entries().len(), total);
+    Ok(())
+}
+
+// v_customizer extract <destination> [path in the vpk] [--vpk <file>]
+// without a path everything gets extracted, a folder extracts everything below it
+fn extract(args: &[String]) -> Result<()> {
+    let (vpk, args) = open_vpk(args)?;
+    let (destination, folder) = match args.as_slice() {
+        [destination] => (destination, ""),
+        [destination, folder] => (destination, folder.as_str()),
+        _ => bail!("Usage: v_customizer extract <destination> [path in the vpk] [--vpk <file>]"),
+    };
+    let count = vpk.extract(folder, destination)?;
+    println!("Extracted {} files to {}", count, destination);
+    Ok(())
+}
+
+// v_customizer verify [--vpk <file>]
+fn verify(args: &[String]) -> Result<()> {
+    let (vpk, args) = open_vpk(args)?;
+    if let Some(arg) = args.first() {
+        bail!("Unknown argument {}", arg);
+    }
+    let problems = vpk.verify();
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!(
+            "{} of {} files in {} are broken",
+            problems.len(),
+            vpk.entries().len(),
+            vpk.path().display()
+        );
+    }
+    println!("All {} files are fine", vpk.entries().len());
+    Ok(())
+}
+
+// takes out --vpk <file>, the installed vpk is the default
+fn open_vpk(args: &[String]) -> Result<(VpkReader, Vec<String>)> {
+    let (path, rest) = take_value(args, "--vpk")?;
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => Sca::installed_vpk()?,
+    };
+    Ok((VpkReader::open(path)?, rest))
+}
+
+// v_customizer weapons [--json] [--profile <name>]
+// every class and animation of the SCA folder, with the offsets the profile has for them,
+// values that come from the global or class layer say so
+fn weapons(args: &[String]) -> Result<()> {
+    let (sca, profile, args) = open_profile(args)?;
+    let (json, args) = take_switch(&args, "--json");
+    if let Some(arg) = args.first() {
+        bail!("Unknown argument {}", arg);
+    }
+    if json {
+        let orphans = sca
+            .orphans
+            .iter()
+            .map(|orphan| json!({ "class": orphan.class, "name": orphan.name, "layer": orphan.layer }))
+            .collect::<Vec<_>>();
+        let weapons = json!({
+            "profile": profile.name,
+            "existing_origin": sca.existing_origin,
+            "global": sca.global,
+            "classes": sca.folders,
+            "orphans": orphans,
+        });
+        println!("{}", serde_json::to_string_pretty(&weapons)?);
+        return Ok(());
+    }
+    println!(
+        "existing $origin: {}",
+        existing_origin_name(sca.existing_origin)
+    );
+    if !sca.global.is_empty() {
+        println!("all weapons: {}", sca.global.describe());
+    }
+    for class in &sca.folders {
+        match class.layer.is_empty() {
+            true => println!("{}", class.class),
+            false => println!("{}: {}", class.class, class.layer.describe()),
+        }
+        for animation in &class.animations {
+            let level = Level::Weapon(class.class, animation.name.clone());
+            let (origin, sources) = sca.effective(&level);
+            let values = FIELDS
+                .iter()
+                .zip(sources)
+                .filter(|(_, source)| *source != Source::Default)
+                .map(|(field, source)| match source {
+                    Source::Weapon => format!("{}={}", field.name(), field.get(&origin)),
+                    _ => format!("{}={} ({})", field.name(), field.get(&origin), source),
+                })
+                .collect::<Vec<String>>();
+            let line = format!("  {:<24} {}", animation.name, values.join(" "));
+            println!("{}", line.trim_end());
+        }
+    }
+    if !sca.orphans.is_empty() {
+        println!("not in the SCA folder");
+        for orphan in &sca.orphans {
+            let name = format!("{} {}", orphan.class, orphan.name);
+            println!("  {:<24} {}", name, orphan.layer.describe());
+        }
+    }
+    Ok(())
+}
+
+// v_customizer orphans [move <class> <name> <animation> | drop <class> <name>] [--profile <name>]
+// lists the offsets of weapons that aren't in the SCA folder anymore, or moves them to an
+// animation of the same class or drops them
+fn orphans(args: &[String]) -> Result<()> {
+    let (mut sca, profile, args) = open_profile(args)?;
+    let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+    let find = |sca: &Sca, class: &str, name: &str| -> Result<usize> {
+        let class = class.parse::<Class>()?;
+        sca.orphans
+            .iter()
+            .position(|orphan| orphan.class == class && orphan.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("{} {} has no orphaned offsets", class, name))
+    };
+    match args.as_slice() {
+        [] => {
+            for orphan in &sca.orphans {
+                let name = format!("{} {}", orphan.class, orphan.name);
+                println!("{:<24} {}", name, orphan.layer.describe());
+            }
+            return Ok(());
+        }
+        ["move", class, name, animation] => {
+            let index = find(&sca, class, name)?;
+            // folder names are matched in any case, like set does
+            let animation = sca
+                .folders
+                .iter()
+                .filter(|folder| folder.class == sca.orphans[index].class)
+                .flat_map(|folder| folder.animations.iter())
+                .find(|found| found.name.eq_ignore_ascii_case(animation))
+                .map_or_else(|| animation.to_string(), |found| found.name.clone());
+            sca.remap_orphan(index, &animation)?;
+            println!("Moved {} {} to {}", class, name, animation);
+        }
+        ["drop", class, name] => {
+            let index = find(&sca, class, name)?;
+            sca.drop_orphan(index);
+            println!("Dropped {} {}", class, name);
+        }
+        _ => bail!(
+            "Usage: v_customizer orphans [move <class> <name> <animation> | drop <class> <name>]"
+        ),
+    }
+    profile.save(&sca)
+}
+
+// v_customizer set <class|all> [animation] <field>=<value>... [--profile <name>]
+// all sets the layer every weapon gets, a class the one of its weapons and an animation its own,
+// fields are x, y, z, yaw, pitch, roll and scale, <field>=inherit takes one out of the layer
+fn set(args: &[String]) -> Result<()> {
+    let (mut sca, profile, args) = open_profile(args)?;
+    let level = set_values(&mut sca, &args)?;
+    profile.save(&sca)?;
+    println!("Set {} in {}", level, profile.name);
+    Ok(())
+}
+
+// the part of set that doesn't touch the profile, returns the layer that changed
+fn set_values(sca: &mut Sca, args: &[String]) -> Result<Level> {
+    let (values, target) = args
+        .iter()
+        .partition::<Vec<&String>, _>(|arg| arg.contains('='));
+    if values.is_empty() {
+        bail!("Usage: v_customizer set <class|all> [animation] <field>=<value>...");
+    }
+    let level = level(sca, &target)?;
+    let layer = sca
+        .layer_mut(&level)
+        .ok_or_else(|| anyhow!("{} isn't in the SCA folder", level))?;
+    for value in &values {
+        set_field(layer, value)?;
+    }
+    Ok(level)
+}
+
+// v_customizer reset [<class|all> [animation]] [--profile <name>]
+// clears one layer, without arguments every layer so every weapon is back at its default
+fn reset(args: &[String]) -> Result<()> {
+    let (mut sca, profile, args) = open_profile(args)?;
+    let level = reset_values(&mut sca, &args)?;
+    profile.save(&sca)?;
+    match level {
+        Some(level) => println!("Reset {} in {}", level, profile.name),
+        None => println!("Reset every weapon in {}", profile.name),
+    }
+    Ok(())
+}
+
+// the part of reset that doesn't touch the profile, None if every layer got cleared
+fn reset_values(sca: &mut Sca, args: &[String]) -> Result<Option<Level>> {
+    if args.is_empty() {
+        sca.reset_all_origin();
+        return Ok(None);
+    }
+    let level = level(sca, args)?;
+    sca.layer_mut(&level)
+        .ok_or_else(|| anyhow!("{} isn't in the SCA folder", level))?
+        .clear();
+    Ok(Some(level))
+}
+
+// v_customizer existing-origin [compose|replace|abort] [--profile <name>]
+// shows or changes what happens to qcs that already have an $origin
+fn existing_origin(args: &[String]) -> Result<()> {
+    let (mut sca, profile, args) = open_profile(args)?;
+    match args.as_slice() {
+        [] => println!("{}", existing_origin_name(sca.existing_origin)),
+        [mode] => {
+            sca.existing_origin = match mode.to_ascii_lowercase().as_str() {
+                "compose" => ExistingOrigin::Compose,
+                "replace" => ExistingOrigin::Replace,
+                "abort" => ExistingOrigin::Abort,
+                _ => bail!("{} isn't compose, replace or abort", mode),
+            };
+            profile.save(&sca)?;
+        }
+        _ => bail!("Usage: v_customizer existing-origin [compose|replace|abort]"),
+    }
+    Ok(())
+}
+
+// v_customizer profile [new <name> | copy <name> <copy> | rename <name> <new name> |
+//                      delete <name> | use <name>]
+// lists the profiles without arguments, the active one has a *
+fn profile(args: &[String]) -> Result<()> {
+    let profiles = Profiles::open()?;
+    let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+    match args.as_slice() {
+        [] => {
+            let active = profiles.active()?;
+            for name in profiles.names()? {
+                let marker = if name == active { "*" } else { " " };
+                println!("{} {}", marker, name);
+            }
+        }
+        ["new", name] => {
+            profiles.create(name, &Sca::new()?)?;
+            println!("Created {}", name);
+        }
+        ["copy", name, copy] => {
+            profiles.duplicate(name, copy)?;
+            println!("Copied {} to {}", name, copy);
+        }
+        ["rename", name, new_name] => {
+            profiles.rename(name, new_name)?;
+            println!("Renamed {} to {}", name, new_name);
+        }
+        ["delete", name] => {
+            profiles.delete(name)?;
+            println!("Deleted {}", name);
+        }
+        ["use", name] => {
+            profiles.set_active(name)?;
+            println!("Using {}", name);
+        }
+        _ => bail!(
+            "Usage: v_customizer profile [new <name> | copy <name> <copy> | rename <name> <new name> | delete <name> | use <name>]"
+        ),
+    }
+    Ok(())
+}
+
+// v_customizer save <file> [--profile <name>]
+// writes the profile to a file outside the config folder, to share it
+fn save(args: &[String]) -> Result<()> {
+    let (sca, profile, args) = open_profile(args)?;
+    let destination = match args.as_slice() {
+        [destination] => destination,
+        _ => bail!("Usage: v_customizer save <file>"),
+    };
+    Profile::new(&profile.name, &sca).write(destination)?;
+    println!("Saved to {}", destination);
+    Ok(())
+}
+
+// v_customizer load <file> [--profile <name>]
+// replaces the offsets of the profile with the ones in file
+fn load(args: &[String]) -> Result<()> {
+    let (mut sca, profile, args) = open_profile(args)?;
+    let source = match args.as_slice() {
+        [source] => source,
+        _ => bail!("Usage: v_customizer load <file>"),
+    };
+    for warning in Profile::read(source)?.apply(&mut sca) {
+        eprintln!("{}", warning);
+    }
+    profile.save(&sca)?;
+    println!("Loaded {} into {}", source, profile.name);
+    Ok(())
+}
+
+// v_customizer generate [--keep-going] [--patch] [--loose] [--json] [--profile <name>]
+// progress goes to stdout, with --json it goes to stderr and stdout only gets the summary
+// with --keep-going whatever compiled gets installed and the failures are in the summary
+fn generate(args: &[String]) -> Result<()> {
+    let (sca, _, args) = open_profile(args)?;
+    let (json, args) = take_switch(&args, "--json");
+    let options = generate_options(&args)?;
+    if options.install == Install::Replace && sca.get_selected_classes().is_empty() {
+        bail!("Every weapon is at its default, there's nothing to generate");
+    }
+    if !Recovery::scan()?.is_empty() {
+        bail!("An earlier run didn't finish, run `v_customizer recover` first");
+    }
+    let keep_going = options.on_error == OnError::KeepGoing;
+    let progress = |line: String| {
+        if json {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    };
+    let worker = Worker::spawn(sca, options, || {});
+    let (mut total, mut compiled) = (0, 0);
+    let mut cached = HashSet::new();
+    let mut items = Vec::new();
+    let end = loop {
+        let event = match worker.recv() {
+            Some(event) => event,
+            None => break Event::Failed("The generate stopped without finishing".to_owned()),
+        };
+        match event {
+            Event::Started { total: count } => {
+                total = count;
+                progress(format!("Compiling {} models", total));
+            }
+            // without --keep-going the first failure ends the run and gets printed then
+            Event::Output { log, .. } if !log.success() && keep_going => {
+                progress(format!("{} didn't compile: {}", log.weapon, log.failure()))
+            }
+            Event::Cached { index, .. } => {
+                cached.insert(index);
+            }
+            Event::Compiled { index, weapon, .. } => {
+                compiled += 1;
+                let from = if cached.contains(&index) {
+                    " (cached)"
+                } else {
+                    ""
+                };
+                progress(format!("[{}/{}] {}{}", compiled, total, weapon, from));
+            }
+            Event::Summary(summary) => {
+                // nobody is there to retry, what compiled gets installed
+                items = summary;
+                worker.package();
+            }
+            Event::Verifying => progress("Checking the models".to_owned()),
+            Event::Packaging => progress("Installing".to_owned()),
+            event if event.is_final() => break event,
+            _ => {}
+        }
+    };
+    let weapon = |index: usize| {
+        items
+            .iter()
+            .find(|item| item.index == index)
+            .map_or("", |item| item.weapon.as_str())
+    };
+    let failed = items
+        .iter()
+        .filter_map(|item| match &item.status {
+            Status::Failed(error) => Some(json!({ "weapon": item.weapon, "error": error })),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let skipped = items
+        .iter()
+        .filter_map(|item| match item.status {
+            Status::Skipped(needs) => {
+                Some(json!({ "weapon": item.weapon, "needs": weapon(needs) }))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let installed = match end {
+        Event::Done => install::installed()?,
+        _ => None,
+    };
+    let (result, error) = match &end {
+        Event::Done => ("done", None),
+        Event::Cancelled => ("cancelled", Some("Cancelled".to_owned())),
+        Event::Failed(error) => ("failed", Some(error.clone())),
+        _ => unreachable!(),
+    };
+    if json {
+        let summary = json!({
+            "result": result,
+            "error": error,
+            "total": total,
+            "compiled": compiled,
+            "cached": cached.len(),
+            "failed": failed,
+            "skipped": skipped,
+            "installed": installed,
+        });
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        for item in &items {
+            match &item.status {
+                Status::Succeeded => {}
+                Status::Failed(error) => println!("failed: {}: {}", item.weapon, error),
+                Status::Skipped(needs) => {
+                    println!("skipped: {}, needs {}", item.weapon, weapon(*needs))
+                }
+            }
+        }
+        if let Some(installed) = &installed {
+            println!(
+                "Installed {} models, {} from the cache, to {}",
+                compiled,
+                cached.len(),
+                installed.display()
+            );
+        }
+    }
+    if let Some(error) = error {
+        bail!(error);
+    }
+    if !failed.is_empty() || !skipped.is_empty() {
+        bail!(
+            "{} weapons didn't compile and were left out",
+            failed.len() + skipped.len()
+        );
+    }
+    Ok(())
+}
+
+fn generate_options(args: &[String]) -> Result<Options> {
+    let mut options = Options {
+        on_error: OnError::Stop,
+        install: Install::Replace,
+        output: Output::Vpk,
+    };
+    for arg in args {
+        match arg.as_str() {
+            "--keep-going" => options.on_error = OnError::KeepGoing,
+            "--patch" => options.install = Install::Patch,
+            "--loose" => options.output = Output::Loose,
+            _ => bail!("Unknown argument {}", arg),
+        }
+    }
+    Ok(options)
+}
+
+// the profile a command works on
+struct Active {
+    profiles: Profiles,
+    name: String,
+}
+
+impl Active {
+    fn save(&self, sca: &Sca) -> Result<()> {
+        self.profiles.save(&self.name, sca)
+    }
+}
+
+// the SCA folder with the offsets of --profile <name> or the active profile,
+// which doesn't have to exist yet
+fn open_profile(args: &[String]) -> Result<(Sca, Active, Vec<String>)> {
+    let (name, rest) = take_value(args, "--profile")?;
+    let profiles = Profiles::open()?;
+    let name = match name {
+        Some(name) => name,
+        None => profiles.active()?,
+    };
+    let mut sca = Sca::new()?;
+    if profiles.exists(&name) {
+        profiles.load(&name)?.apply(&mut sca);
+        if !sca.orphans.is_empty() {
+            eprintln!(
+                "{} has offsets for {} weapons that aren't in the SCA folder, see `v_customizer orphans`",
+                name,
+                sca.orphans.len()
+            );
+        }
+    }
+    Ok((sca, Active { profiles, name }, rest))
+}
+
+// the layer target names, which is "all", a class or a class and an animation
+fn level<S: AsRef<str>>(sca: &Sca, target: &[S]) -> Result<Level> {
+    let target = target.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+    match target.as_slice() {
+        ["all"] => Ok(Level::Global),
+        [class] => Ok(Level::Class(class.parse()?)),
+        [class, animation] => {
+            let class = class.parse::<Class>()?;
+            sca.folders
+                .iter()
+                .filter(|folder| folder.class == class)
+                .flat_map(|folder| folder.animations.iter())
+                .find(|found| found.name.eq_ignore_ascii_case(animation))
+                .map(|found| Level::Weapon(class, found.name.clone()))
+                .ok_or_else(|| anyhow!("{} {} isn't in the SCA folder", class, animation))
+        }
+        _ => bail!("Name a class and animation, a class or all"),
+    }
+}
+
+// field=value or field=inherit
+fn set_field(layer: &mut Layer, arg: &str) -> Result<()> {
+    let (field, value) = arg.split_once('=').unwrap_or((arg, ""));
+    let field = field.parse::<Field>()?;
+    let value = match value {
+        "inherit" => None,
+        value => Some(
+            value
+                .parse::<f32>()
+                .map_err(|_| anyhow!("{} isn't a number", value))?,
+        ),
+    };
+    layer.set(field, value);
+    Ok(())
+}
+
+fn existing_origin_name(existing_origin: ExistingOrigin) -> &'static str {
+    match existing_origin {
+        ExistingOrigin::Compose => "compose",
+        ExistingOrigin::Replace => "replace",
+        ExistingOrigin::Abort => "abort",
+    }
+}
+
+// takes out flag <value> or flag=<value>
+fn take_value(args: &[String], flag: &str) -> Result<(Option<String>, Vec<String>)> {
+    let mut value = None;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix(flag) {
+            Some("") => {
+                let next = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", flag))?;
+                value = Some(next.clone());
+            }
+            Some(inline) if inline.starts_with('=') => value = Some(inline[1..].to_owned()),
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Ok((value, rest))
+}
+
+// takes out flag, true if it was there
+fn take_switch(args: &[String], flag: &str) -> (bool, Vec<String>) {
+    let rest = args
+        .iter()
+        .filter(|arg| *arg != flag)
+        .cloned()
+        .collect::<Vec<String>>();
+    (rest.len() != args.len(), rest)
+}
+
+// the release build has no console of its own, attach to the one it was started from
+#[cfg(windows)]
+fn attach_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sca::test_sca;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_owned).collect()
+    }
+
+    fn pistol() -> Level {
+        Level::Weapon(Class::Scout, "Pistol".to_owned())
+    }
+
+    #[test]
+    fn takes_values_and_switches() {
+        let (value, rest) = take_value(&args("set scout --profile Mine x=1"), "--profile").unwrap();
+        assert_eq!(value.as_deref(), Some("Mine"));
+        assert_eq!(rest, args("set scout x=1"));
+        let (value, rest) = take_value(&args("--profile=My profile"), "--profile").unwrap();
+        assert_eq!(value.as_deref(), Some("My"));
+        assert_eq!(rest, args("profile"));
+        let (value, rest) = take_value(&args("reset scout"), "--profile").unwrap();
+        assert_eq!(value, None);
+        assert_eq!(rest, args("reset scout"));
+        assert!(take_value(&args("reset --profile"), "--profile").is_err());
+        // only the whole flag counts
+        let (value, rest) = take_value(&args("--profiles x"), "--profile").unwrap();
+        assert_eq!(value, None);
+        assert_eq!(rest, args("--profiles x"));
+
+        assert_eq!(
+            take_switch(&args("--patch --json --loose"), "--json"),
+            (true, args("--patch --loose"))
+        );
+        assert_eq!(
+            take_switch(&args("--patch"), "--json"),
+            (false, args("--patch"))
+        );
+    }
+
+    #[test]
+    fn parses_generate_options() {
+        let options = generate_options(&[]).unwrap();
+        assert_eq!(options.on_error, OnError::Stop);
+        assert_eq!(options.install, Install::Replace);
+        assert_eq!(options.output, Output::Vpk);
+        let options = generate_options(&args("--loose --keep-going --patch")).unwrap();
+        assert_eq!(options.on_error, OnError::KeepGoing);
+        assert_eq!(options.install, Install::Patch);
+        assert_eq!(options.output, Output::Loose);
+        assert!(generate_options(&args("--fast")).is_err());
+    }
+
+    #[test]
+    fn parses_levels() {
+        let sca = test_sca();
+        assert_eq!(level(&sca, &["all"]).unwrap(), Level::Global);
+        assert_eq!(
+            level(&sca, &["soldier"]).unwrap(),
+            Level::Class(Class::Soldier)
+        );
+        // folder names match in any case and come out as they are on disk
+        assert_eq!(level(&sca, &["Scout", "PISTOL"]).unwrap(), pistol());
+        assert!(level(&sca, &["scout", "Shotgun"]).is_err());
+        assert!(level(&sca, &["hoovy"]).is_err());
+        assert!(level::<&str>(&sca, &[]).is_err());
+        assert!(level(&sca, &["scout", "Pistol", "x"]).is_err());
+    }
+
+    #[test]
+    fn parses_fields() {
+        let mut layer = Layer::default();
+        set_field(&mut layer, "x=1.5").unwrap();
+        set_field(&mut layer, "yaw=-10").unwrap();
+        assert_eq!(layer.x, Some(1.5));
+        assert_eq!(layer.yaw, Some(-10.0));
+        set_field(&mut layer, "x=inherit").unwrap();
+        assert_eq!(layer.x, None);
+        assert!(set_field(&mut layer, "x=far").is_err());
+        assert!(set_field(&mut layer, "x=").is_err());
+        assert!(set_field(&mut layer, "width=2").is_err());
+        assert!(set_field(&mut layer, "x").is_err());
+    }
+
+    #[test]
+    fn sets_layers() {
+        let mut sca = test_sca();
+        let level = set_values(&mut sca, &args("scout pistol x=1 z=-2")).unwrap();
+        assert_eq!(level, pistol());
+        assert_eq!(sca.layer(&pistol()).unwrap().describe(), "x=1 z=-2");
+        set_values(&mut sca, &args("all scale=1.1")).unwrap();
+        set_values(&mut sca, &args("scout x=inherit yaw=5")).unwrap();
+        assert_eq!(sca.global.scale, Some(1.1));
+        assert_eq!(sca.folders[0].layer.yaw, Some(5.0));
+        // needs at least one value and a target
+        assert!(set_values(&mut sca, &args("scout")).is_err());
+        assert!(set_values(&mut sca, &args("x=1")).is_err());
+        assert!(set_values(&mut sca, &args("scout pistol x=one")).is_err());
+    }
+
+    #[test]
+    fn sets_only_classes_the_sca_has() {
+        let mut sca = test_sca();
+        let error = set_values(&mut sca, &args("heavy x=1")).unwrap_err();
+        assert_eq!(error.to_string(), "Heavy isn't in the SCA folder");
+        assert!(reset_values(&mut sca, &args("heavy")).is_err());
+        assert!(sca.folders.iter().all(|folder| folder.layer.is_empty()));
+    }
+
+    #[test]
+    fn resets_layers() {
+        let mut sca = test_sca();
+        set_values(&mut sca, &args("all x=1")).unwrap();
+        set_values(&mut sca, &args("scout y=2")).unwrap();
+        set_values(&mut sca, &args("scout pistol z=3")).unwrap();
+        assert_eq!(
+            reset_values(&mut sca, &args("scout")).unwrap(),
+            Some(Level::Class(Class::Scout))
+        );
+        assert!(sca.folders[0].layer.is_empty());
+        assert_eq!(sca.global.x, Some(1.0));
+        assert_eq!(sca.layer(&pistol()).unwrap().z, Some(3.0));
+        assert!(reset_values(&mut sca, &[]).unwrap().is_none());
+        assert!(sca.global.is_empty());
+        assert!(sca.layer(&pistol()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn needs_a_known_command() {
+        assert!(run(&[]).is_err());
+        assert!(run(&args("frobnicate")).is_err());
+        assert_eq!(main(args("--jobs")), Some(2));
+    }
+}
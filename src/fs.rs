@@ -0,0 +1,92 @@
+use anyhow::Result;
+
+use std::path::{Path, PathBuf};
+
+// every file below folder, skipping the folders descend says no to, in no particular order
+pub fn walk<F: FnMut(&Path) -> bool>(folder: &Path, mut descend: F) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![folder.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                files.push(path);
+            } else if descend(&path) {
+                pending.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+// every file below folder
+pub fn files(folder: &Path) -> Result<Vec<PathBuf>> {
+    walk(folder, |_| true)
+}
+
+// path below base with / between folders, the way vpks and keys spell them on every os,
+// paths outside of base stay whole
+pub fn relative_path(path: &Path, base: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// copies what's in from into to, which gets created if it isn't there
+pub fn copy_folder(from: &Path, to: &Path) -> Result<()> {
+    transfer(from, to, &|from, to| std::fs::copy(from, to).map(|_| ()))
+}
+
+// like copy_folder, but renames the files, so both have to be on the same drive
+pub fn move_folder(from: &Path, to: &Path) -> Result<()> {
+    transfer(from, to, &|from, to| std::fs::rename(from, to))
+}
+
+// true if both paths lead to the same existing file, even when they're spelled differently
+#[cfg(unix)]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// an empty folder of its own for a test, in the system's temp folder
+#[cfg(test)]
+pub fn test_folder(name: &str) -> PathBuf {
+    let folder =
+        std::env::temp_dir().join(format!("v_customizer_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&folder);
+    std::fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
+fn transfer(
+    from: &Path,
+    to: &Path,
+    file: &dyn Fn(&Path, &Path) -> std::io::Result<()>,
+) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let path = entry?.path();
+        let destination = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            transfer(&path, &destination, file)?;
+        } else {
+            file(&path, &destination)?;
+        }
+    }
+    Ok(())
+}
@@ -1 +1,7 @@
+pub mod cli;
+pub mod fs;
+pub mod mdl;
+pub mod qc;
 pub mod sca;
+pub mod smd;
+pub mod vpk;
@@ -1,64 +1,140 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use eframe::egui::{CtxRef, Ui};
-use eframe::epi::{Frame, Storage};
+use eframe::egui::{CtxRef, Key, Ui};
+use eframe::epi::{Frame, RepaintSignal, Storage};
 use eframe::{egui, epi};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use v_customizer::cli;
 use v_customizer::sca;
-use v_customizer::sca::Origin;
+use v_customizer::sca::diagnostics::{CompileLog, Severity};
+use v_customizer::sca::install::{Install, Output};
+use v_customizer::sca::journal::Recovery;
+use v_customizer::sca::layers::{Field, Level, Source, FIELDS};
+use v_customizer::sca::profile::{Profiles, DEFAULT_PROFILE_NAME};
+use v_customizer::sca::recover;
+use v_customizer::sca::scheduler::{OnError, Status};
+use v_customizer::sca::worker::{Event, Item, Options, Worker};
+use v_customizer::sca::ExistingOrigin;
+
+// which layer the sliders edit
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq)]
+enum Scope {
+    All,
+    // the class shown in the list
+    Class,
+    // the selected animation
+    Weapon,
+}
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)]
 struct App {
-    origin: sca::Origin,
-    apply_to_all: bool,
+    scope: Scope,
     current_class: sca::Class,
     selected_class: sca::Class,
     selected_animation: String,
     status: String,
+    // vpk to import settings or recover offsets from, the installed one when empty
+    import_path: String,
+    // compile what can be compiled and ask what to do about the failures at the end
+    keep_going: bool,
+    // patch the installed vpk instead of building it again
+    patch_install: bool,
+    // install loose files instead of a vpk
+    loose_output: bool,
+    // only read, from before profiles, the offsets are saved in the active profile now
+    #[serde(skip_serializing)]
     sca: sca::Sca,
+    #[serde(skip)]
+    profiles: Option<Profiles>,
+    // the active profile
+    #[serde(skip)]
+    profile: String,
+    #[serde(skip)]
+    profile_names: Vec<String>,
+    // what new, duplicated and renamed profiles get called
+    #[serde(skip)]
+    new_profile_name: String,
+    // the orphans window got put off until the next profile load
+    #[serde(skip)]
+    orphans_later: bool,
     // progress bar
-    compiling: bool,
     progress_bar_progress: f32,
     total_items: usize,
     items_completed: usize,
-    items: Vec<PathBuf>,
+    // how many of those came from the compile cache
+    #[serde(skip)]
+    items_cached: usize,
+    #[serde(skip)]
+    worker: Option<Worker>,
+    // what an earlier run left behind
+    #[serde(skip)]
+    recovery: Option<Recovery>,
+    // studiomdl output of the last generate
+    #[serde(skip)]
+    logs: Vec<CompileLog>,
+    #[serde(skip)]
+    show_log: bool,
+    // how every qc came out, the last time something didn't compile
+    #[serde(skip)]
+    summary: Option<Vec<Item>>,
+    // the worker waits for a retry, package or discard
+    #[serde(skip)]
+    awaiting_decision: bool,
 }
 
 impl Default for App {
     fn default() -> Self {
         App {
-            origin: sca::Origin {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-                z_rot: 0.0,
-            },
-            apply_to_all: false,
+            scope: Scope::Weapon,
             current_class: sca::Class::default(),
             selected_class: sca::Class::default(),
             selected_animation: "".to_owned(),
             status: "".to_owned(),
-            compiling: false,
+            import_path: "".to_owned(),
+            keep_going: false,
+            patch_install: false,
+            loose_output: false,
             progress_bar_progress: 0.0,
             total_items: 0,
             items_completed: 0,
-            items: Vec::new(),
+            items_cached: 0,
+            worker: None,
+            recovery: None,
+            logs: Vec::new(),
+            show_log: false,
+            summary: None,
+            awaiting_decision: false,
             sca: sca::Sca::default(),
+            profiles: None,
+            profile: "".to_owned(),
+            profile_names: Vec::new(),
+            new_profile_name: "".to_owned(),
+            orphans_later: false,
         }
     }
 }
 
 impl epi::App for App {
-    fn update(&mut self, ctx: &CtxRef, _frame: &mut Frame<'_>) {
+    fn update(&mut self, ctx: &CtxRef, frame: &mut Frame<'_>) {
+        self.poll_worker();
+        self.show_recovery(ctx);
+        self.show_log(ctx);
+        self.show_summary(ctx);
+        self.show_orphans(ctx);
+        self.undo_keys(ctx);
         egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.set_enabled(self.worker.is_none() && self.profiles.is_some());
+                self.show_profiles(ui);
+            });
             ui.horizontal_top(|ui| {
-                ui.set_enabled(!self.compiling);
+                ui.set_enabled(self.worker.is_none());
                 ui.group(|ui| {
                     ui.set_max_width(260f32);
                     ui.set_max_height(ui.available_height() - 25.0);
                     ui.set_min_height(ui.available_height());
-                    ui.set_enabled(!self.apply_to_all);
                     egui::ScrollArea::vertical().show(ui, |ui| {
                         ui.columns(2, |colum| {
                             for folder in &self.sca.folders {
@@ -99,85 +175,91 @@ impl epi::App for App {
                     ui.set_min_height(ui.available_height());
                     ui.vertical(|ui| {
                         ui.horizontal(|ui| {
-                            ui.add(egui::Checkbox::new(&mut self.apply_to_all, "Apply to all"))
-                        });
-                        if self.apply_to_all {
-                            Self::add_rangers(ui, &mut self.origin);
-                        } else {
-                            for folder in self.sca.folders.iter_mut() {
-                                if folder.class == self.selected_class {
-                                    for animation in folder.animations.iter_mut() {
-                                        if animation.name == self.selected_animation {
-                                            Self::add_rangers(ui, &mut animation.origin);
-                                            break;
-                                        }
-                                    }
-                                    break;
+                            ui.selectable_value(&mut self.scope, Scope::All, "All weapons");
+                            ui.selectable_value(
+                                &mut self.scope,
+                                Scope::Class,
+                                self.current_class.to_string(),
+                            );
+                            ui.selectable_value(
+                                &mut self.scope,
+                                Scope::Weapon,
+                                &self.selected_animation,
+                            );
+                            ui.with_layout(egui::Layout::right_to_left(), |ui| {
+                                let redo = egui::Button::new("Redo");
+                                if ui.add_enabled(self.sca.history.can_redo(), redo).clicked() {
+                                    self.sca.redo();
                                 }
+                                let undo = egui::Button::new("Undo");
+                                if ui.add_enabled(self.sca.history.can_undo(), undo).clicked() {
+                                    self.sca.undo();
+                                }
+                            });
+                        });
+                        let level = self.level();
+                        Self::add_layer(ui, &mut self.sca, &level);
+                        if self.scope == Scope::Weapon {
+                            let baseline = self
+                                .sca
+                                .folders
+                                .iter()
+                                .filter(|folder| folder.class == self.selected_class)
+                                .flat_map(|folder| folder.animations.iter())
+                                .find(|animation| animation.name == self.selected_animation)
+                                .and_then(|animation| animation.baseline);
+                            if let Some(baseline) = baseline {
+                                ui.label(format!(
+                                    "SCA default: x {} y {} z {} yaw {}",
+                                    baseline.x, baseline.y, baseline.z, baseline.yaw
+                                ));
                             }
                         }
+                        egui::ComboBox::from_label("Existing $origin")
+                            .selected_text(self.sca.existing_origin)
+                            .show_ui(ui, |ui| {
+                                for mode in [
+                                    ExistingOrigin::Compose,
+                                    ExistingOrigin::Replace,
+                                    ExistingOrigin::Abort,
+                                ] {
+                                    ui.selectable_value(
+                                        &mut self.sca.existing_origin,
+                                        mode,
+                                        mode.to_string(),
+                                    );
+                                }
+                            });
                         ui.separator();
                         ui.vertical_centered_justified(|ui| {
                             if ui.button("Reset").clicked() {
-                                if self.apply_to_all {
-                                    self.origin.reset();
-                                } else {
-                                    self.sca.reset_origin(
-                                        &self.selected_class,
-                                        &self.selected_animation,
-                                    );
-                                }
+                                self.sca.clear_layer(&self.level());
                             }
                             if ui.button("Reset all").clicked() {
                                 self.sca.reset_all_origin();
                             }
+                            if ui.button("Import from VPK").clicked() {
+                                self.import_vpk();
+                            }
+                            if ui.button("Recover from models").clicked() {
+                                self.recover_offsets();
+                            }
+                            ui.add(
+                                egui::TextEdit::singleline(&mut self.import_path)
+                                    .hint_text("installed vpk"),
+                            );
 
+                            ui.checkbox(&mut self.keep_going, "Keep going on errors");
+                            ui.checkbox(&mut self.patch_install, "Only update what changed");
+                            ui.checkbox(&mut self.loose_output, "Loose files instead of a vpk");
                             if ui.button("Generate").clicked() {
-                                if self.apply_to_all {
-                                    self.sca.apply_to_all_origin(&self.origin);
-                                }
-                                if self.sca.get_selected_classes().is_empty() {
+                                self.sca.resolve();
+                                // a patch can also just take weapons out
+                                if self.sca.get_selected_classes().is_empty() && !self.patch_install
+                                {
                                     self.status = "No origin is modified".to_owned();
-                                    return;
-                                } else {
-                                    if let Err(e) = self.sca.copy_sca() {
-                                        self.status = e.to_string();
-                                        return;
-                                    }
-                                    if let Err(e) = self.sca.append_origins() {
-                                        self.status = e.to_string();
-                                        return;
-                                    }
-                                    if !self.compiling {
-                                        self.compiling = true;
-                                        self.progress_bar_progress = 0.0;
-                                        self.items_completed = 0;
-                                        let anim_qcs = match self.sca.get_temp_folder_qcs(false) {
-                                            Ok(ok) => ok,
-                                            Err(error) => {
-                                                self.status = error.to_string();
-                                                self.compiling = false;
-                                                return;
-                                            }
-                                        };
-                                        let class_qcs = match self.sca.get_selected_class_qcs() {
-                                            Ok(ok) => ok,
-                                            Err(error) => {
-                                                self.status = error.to_string();
-                                                self.compiling = false;
-                                                return;
-                                            }
-                                        };
-                                        self.items = vec![anim_qcs, class_qcs]
-                                            .into_iter()
-                                            .flatten()
-                                            .collect::<Vec<PathBuf>>();
-                                        self.total_items = self.items.len();
-                                        match sca::Sca::create_temp_models_folder() {
-                                            Ok(_) => {}
-                                            Err(error) => self.status = error.to_string(),
-                                        }
-                                    }
+                                } else if self.worker.is_none() {
+                                    self.start_generate(frame.repaint_signal());
                                 }
                             }
                         });
@@ -186,55 +268,22 @@ impl epi::App for App {
             });
             ui.separator();
             ui.horizontal(|ui| {
-                if self.compiling {
-                    let item = match self.items.pop() {
-                        None => {
-                            self.status = "Compiling done!".to_owned();
-                            self.compiling = false;
-                            self.progress_bar_progress = 0.0;
-                            self.items_completed = 0;
-                            ctx.request_repaint();
-                            match App::delete_temp_folder() {
-                                Ok(_) => {}
-                                Err(error) => {
-                                    self.status = format!(
-                                        "Compiling done! But failed to delete temp folder: {}",
-                                        error.to_string()
-                                    )
-                                }
-                            };
-                            match sca::Sca::convert_to_vpk() {
-                                Ok(_) => {}
-                                Err(error) => self.status = error.to_string(),
-                            }
-                            match sca::Sca::delete_temp_models_folder() {
-                                Ok(_) => {}
-                                Err(error) => self.status = error.to_string(),
-                            }
-                            return;
-                        }
-                        Some(i) => i,
-                    };
-                    match sca::Sca::compile(&item) {
-                        Ok(_) => {
-                            self.items_completed += 1;
-                            self.progress_bar_progress =
-                                self.items_completed as f32 / self.total_items as f32;
-                        }
-                        Err(error) => {
-                            self.status = error.to_string();
-                            return;
-                        }
-                    }
+                if ui.selectable_label(self.show_log, "Log").clicked() {
+                    self.show_log = !self.show_log;
+                }
+                if let Some(worker) = &self.worker {
                     ui.add(
                         egui::ProgressBar::new(self.progress_bar_progress)
                             .animate(true)
                             .desired_width(100.0),
                     );
-                    self.status = format!(
-                        "Compiling {}...",
-                        item.file_name().unwrap().to_str().unwrap()
-                    );
+                    if ui
+                        .add_enabled(!worker.is_cancelled(), egui::Button::new("Cancel"))
+                        .clicked()
+                    {
+                        worker.cancel();
+                        self.status = "Cancelling...".to_owned();
+                    }
                 }
                 ui.label(&self.status);
             });
@@ -250,7 +299,7 @@ impl epi::App for App {
             }
             Err(e) => self.status = e.to_string(),
         }
-        self.sca = match sca::Sca::new() {
+        let scanned = match sca::Sca::new() {
             Ok(ok) => {
                 self.status = "Status: OK".to_owned();
                 ok
@@ -272,19 +321,30 @@ impl epi::App for App {
                 Some(data) => *self = data,
             }
         }
+        // before profiles the offsets were saved with the window, they become the first profile
+        let mut saved = std::mem::replace(&mut self.sca, scanned);
+        saved.layers_from_origins();
+        self.open_profiles(&saved);
+        match Recovery::scan() {
+            Ok(recovery) if !recovery.is_empty() => self.recovery = Some(recovery),
+            Ok(_) => {}
+            Err(error) => self.status = format!("Can't check for unfinished runs: {}", error),
+        }
     }
 
     fn save(&mut self, storage: &mut dyn epi::Storage) {
         epi::set_value(storage, epi::APP_KEY, self);
+        if let Some(profiles) = &self.profiles {
+            if let Err(error) = self.save_profile(profiles) {
+                self.status = format!("Can't save the profile: {:#}", error);
+            }
+        }
     }
 
-    // removes temp folder
-    #[allow(unused_must_use)]
+    // cancels a running generate and waits for its workspace to be cleaned up
     fn on_exit(&mut self) {
-        App::delete_temp_folder();
-        match sca::Sca::delete_temp_models_folder() {
-            Ok(_) => {}
-            Err(error) => self.status = error.to_string(),
+        if let Some(worker) = self.worker.take() {
+            worker.stop();
         }
     }
 
@@ -294,40 +354,611 @@ impl epi::App for App {
 }
 
 impl App {
-    fn delete_temp_folder() -> anyhow::Result<()> {
-        let temp_folder = sca::Sca::exe_folder().unwrap().join(sca::TEMP_FOLDER_NAME);
-        if Path::new(&temp_folder).is_dir() {
-            std::fs::remove_dir_all(temp_folder)?;
+    // hands a copy of sca to the worker thread, the gui only shows its progress
+    fn start_generate(&mut self, repaint_signal: Arc<dyn RepaintSignal>) {
+        self.status = "Preparing...".to_owned();
+        self.progress_bar_progress = 0.0;
+        self.total_items = 0;
+        self.items_completed = 0;
+        self.items_cached = 0;
+        self.logs.clear();
+        self.summary = None;
+        self.awaiting_decision = false;
+        let options = Options {
+            on_error: if self.keep_going {
+                OnError::KeepGoing
+            } else {
+                OnError::Stop
+            },
+            install: if self.patch_install {
+                Install::Patch
+            } else {
+                Install::Replace
+            },
+            output: if self.loose_output {
+                Output::Loose
+            } else {
+                Output::Vpk
+            },
+        };
+        self.worker = Some(Worker::spawn(self.sca.clone(), options, move || {
+            repaint_signal.request_repaint()
+        }));
+    }
+
+    // opens the profile used last, the first time around it gets made from saved
+    fn open_profiles(&mut self, saved: &sca::Sca) {
+        let result = Profiles::open().and_then(|profiles| {
+            if profiles.names()?.is_empty() {
+                profiles.create(DEFAULT_PROFILE_NAME, saved)?;
+            }
+            let warnings = self.load_profile(&profiles, &profiles.active()?)?;
+            self.profiles = Some(profiles);
+            Ok(warnings)
+        });
+        match result {
+            Ok(warnings) if warnings.is_empty() => {}
+            Ok(warnings) => {
+                self.status = profile_status(format!("Loaded {}", self.profile), warnings)
+            }
+            Err(error) => self.status = format!("Can't open the profiles: {:#}", error),
         }
-        Ok(())
     }
-    fn add_rangers(ui: &mut Ui, origin: &mut Origin) {
-        ui.add(
-            egui::Slider::new(&mut origin.x, -20f32..=20f32)
-                .clamp_to_range(false)
-                .text("x"),
-        );
-        ui.add(
-            egui::Slider::new(&mut origin.y, -20f32..=20f32)
-                .clamp_to_range(false)
-                .text("y"),
-        );
-        ui.add(
-            egui::Slider::new(&mut origin.z, -20f32..=20f32)
-                .clamp_to_range(false)
-                .text("z"),
-        );
+
+    // replaces the offsets with the ones of the profile and makes it the active one
+    fn load_profile(&mut self, profiles: &Profiles, name: &str) -> anyhow::Result<Vec<String>> {
+        let warnings = profiles.load(name)?.apply(&mut self.sca);
+        profiles.set_active(name)?;
+        self.profile = name.to_owned();
+        self.profile_names = profiles.names()?;
+        self.orphans_later = false;
+        Ok(warnings)
+    }
+
+    fn save_profile(&self, profiles: &Profiles) -> anyhow::Result<()> {
+        // without an SCA folder there are no weapons, saving would lose every offset
+        if self.sca.folders.is_empty() {
+            return Ok(());
+        }
+        profiles.save(&self.profile, &self.sca)
+    }
+
+    fn show_profiles(&mut self, ui: &mut Ui) {
+        let mut switch_to = None;
+        egui::ComboBox::from_label("Profile")
+            .selected_text(&self.profile)
+            .show_ui(ui, |ui| {
+                for name in &self.profile_names {
+                    if ui.selectable_label(*name == self.profile, name).clicked() {
+                        switch_to = Some(name.clone());
+                    }
+                }
+            });
         ui.add(
-            egui::Slider::new(&mut origin.z_rot, -20f32..=20f32)
-                .clamp_to_range(false)
-                .text("z rot"),
+            egui::TextEdit::singleline(&mut self.new_profile_name)
+                .hint_text("new name")
+                .desired_width(100.0),
         );
+        let new = ui.button("New").clicked();
+        let duplicate = ui.button("Duplicate").clicked();
+        let rename = ui.button("Rename").clicked();
+        let delete = ui
+            .add_enabled(self.profile_names.len() > 1, egui::Button::new("Delete"))
+            .clicked();
+        let profiles = match self.profiles.take() {
+            Some(profiles) => profiles,
+            None => return,
+        };
+        let name = self.new_profile_name.trim().to_owned();
+        let result = if let Some(switch_to) = switch_to.filter(|name| *name != self.profile) {
+            self.switch_profile(&profiles, &switch_to)
+        } else if new {
+            // a new profile starts with every weapon at its default
+            let mut fresh = self.sca.clone();
+            fresh.reset_all_origin();
+            fresh.existing_origin = ExistingOrigin::default();
+            profiles
+                .create(&name, &fresh)
+                .and_then(|()| self.switch_profile(&profiles, &name))
+        } else if duplicate {
+            self.save_profile(&profiles)
+                .and_then(|()| profiles.duplicate(&self.profile, &name))
+                .and_then(|()| self.switch_profile(&profiles, &name))
+        } else if rename {
+            self.save_profile(&profiles)
+                .and_then(|()| profiles.rename(&self.profile, &name))
+                .and_then(|()| {
+                    self.status = format!("Renamed {} to {}", self.profile, name);
+                    self.profile = name.clone();
+                    self.profile_names = profiles.names()?;
+                    Ok(())
+                })
+        } else if delete {
+            profiles.delete(&self.profile).and_then(|()| {
+                let deleted = std::mem::take(&mut self.profile);
+                let warnings = self.load_profile(&profiles, &profiles.active()?)?;
+                self.status = profile_status(format!("Deleted {}", deleted), warnings);
+                Ok(())
+            })
+        } else {
+            Ok(())
+        };
+        if let Err(error) = result {
+            self.status = format!("{:#}", error);
+        } else if new || duplicate || rename {
+            self.new_profile_name.clear();
+        }
+        self.profiles = Some(profiles);
+    }
+
+    // keeps the changes to the active profile before loading name
+    fn switch_profile(&mut self, profiles: &Profiles, name: &str) -> anyhow::Result<()> {
+        self.save_profile(profiles)?;
+        let warnings = self.load_profile(profiles, name)?;
+        self.status = profile_status(format!("Switched to {}", name), warnings);
+        Ok(())
+    }
+
+    fn import_path(&self) -> anyhow::Result<PathBuf> {
+        match self.import_path.trim() {
+            "" => sca::Sca::installed_vpk(),
+            path => Ok(path.into()),
+        }
+    }
+
+    // loads the settings a vpk was built with into a new profile and switches to it,
+    // the active one stays as it was
+    fn import_vpk(&mut self) {
+        let profiles = match self.profiles.take() {
+            Some(profiles) => profiles,
+            None => {
+                self.status = "Importing needs the profiles folder".to_owned();
+                return;
+            }
+        };
+        self.status = match self.import_into_profile(&profiles) {
+            Ok((name, warnings)) => {
+                profile_status(format!("Imported the settings into {}", name), warnings)
+            }
+            Err(error) => format!("{:#}", error),
+        };
+        self.profiles = Some(profiles);
+        self.orphans_later = false;
+    }
+
+    // returns the new profile and what didn't fit this SCA
+    fn import_into_profile(
+        &mut self,
+        profiles: &Profiles,
+    ) -> anyhow::Result<(String, Vec<String>)> {
+        let path = self.import_path()?;
+        let mut imported = self.sca.clone();
+        let warnings = imported.import_vpk(&path)?;
+        let name = self.switch_to_new_profile(profiles, "Imported from", &path, &imported)?;
+        Ok((name, warnings))
+    }
+
+    // for packs that were built without settings, works them out from the compiled models
+    // and switches to a new profile with them, the active one stays as it was
+    fn recover_offsets(&mut self) {
+        let profiles = match self.profiles.take() {
+            Some(profiles) => profiles,
+            None => {
+                self.status = "Recovering needs the profiles folder".to_owned();
+                return;
+            }
+        };
+        self.status = match self.recover_into_profile(&profiles) {
+            Ok((name, notes)) => profile_status(
+                format!("Recovered the offsets from the models into {}", name),
+                notes,
+            ),
+            Err(error) => format!("{:#}", error),
+        };
+        self.profiles = Some(profiles);
+    }
+
+    // returns the new profile and what couldn't be recovered
+    fn recover_into_profile(
+        &mut self,
+        profiles: &Profiles,
+    ) -> anyhow::Result<(String, Vec<String>)> {
+        let path = self.import_path()?;
+        let (recovered, notes) = recover::recover(&self.sca, &path)?;
+        let name = self.switch_to_new_profile(profiles, "Recovered from", &path, &recovered)?;
+        Ok((name, notes))
+    }
+
+    // saves sca as a profile named after the pack it came from and switches to it
+    fn switch_to_new_profile(
+        &mut self,
+        profiles: &Profiles,
+        prefix: &str,
+        path: &Path,
+        sca: &sca::Sca,
+    ) -> anyhow::Result<String> {
+        let file = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        let name = profiles.free_name(&format!("{} {}", prefix, file));
+        profiles.create(&name, sca)?;
+        self.switch_profile(profiles, &name)?;
+        Ok(name)
+    }
+
+    fn poll_worker(&mut self) {
+        let events = match &self.worker {
+            Some(worker) => worker.poll(),
+            None => return,
+        };
+        for event in events {
+            if event.is_final() {
+                self.worker = None;
+                self.awaiting_decision = false;
+                self.progress_bar_progress = 0.0;
+                self.items_completed = 0;
+            }
+            match event {
+                Event::Started { total } => self.total_items = total,
+                Event::Compiling { qc, .. } => {
+                    self.status = format!(
+                        "Compiling {}...",
+                        qc.file_name().unwrap_or_default().to_string_lossy()
+                    )
+                }
+                Event::Output { log, .. } => {
+                    if !log.success() {
+                        self.show_log = true;
+                    }
+                    self.logs.push(log);
+                }
+                Event::Cached { .. } => self.items_cached += 1,
+                Event::Compiled { .. } => {
+                    self.items_completed += 1;
+                    self.progress_bar_progress =
+                        self.items_completed as f32 / self.total_items as f32;
+                }
+                Event::Summary(items) => {
+                    self.status = "Some weapons didn't compile".to_owned();
+                    self.summary = Some(items);
+                    self.awaiting_decision = true;
+                }
+                Event::Verifying => self.status = "Checking the models...".to_owned(),
+                Event::Packaging => self.status = "Installing...".to_owned(),
+                Event::Done => {
+                    let left_out = self.summary.as_ref().map_or(0, |items| {
+                        items
+                            .iter()
+                            .filter(|item| item.status != Status::Succeeded)
+                            .count()
+                    });
+                    self.status = if left_out == 0 {
+                        "Compiling done!".to_owned()
+                    } else {
+                        format!("Compiling done, without {} that didn't compile", left_out)
+                    };
+                    if self.items_cached > 0 {
+                        self.status += &format!(" {} reused from earlier runs", self.items_cached);
+                    }
+                }
+                Event::Failed(error) => self.status = error,
+                Event::Cancelled => self.status = "Cancelled".to_owned(),
+            }
+        }
+    }
+
+    // every compile of the last generate, failed ones open with their errors
+    fn show_log(&mut self, ctx: &CtxRef) {
+        let logs = &self.logs;
+        egui::Window::new("Compile log")
+            .open(&mut self.show_log)
+            .default_height(400.0)
+            .show(ctx, |ui| {
+                if logs.is_empty() {
+                    ui.label("Nothing got compiled yet");
+                }
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (index, log) in logs.iter().enumerate() {
+                        let title = format!(
+                            "{}: {}, {} errors, {} warnings",
+                            log.weapon,
+                            if log.success() { "compiled" } else { "failed" },
+                            log.count(Severity::Error),
+                            log.count(Severity::Warning)
+                        );
+                        egui::CollapsingHeader::new(title)
+                            .id_source(index)
+                            .default_open(!log.success())
+                            .show(ui, |ui| {
+                                for diagnostic in &log.diagnostics {
+                                    let color = match diagnostic.severity {
+                                        Severity::Error => egui::Color32::RED,
+                                        Severity::Warning => egui::Color32::YELLOW,
+                                    };
+                                    ui.colored_label(color, diagnostic.to_string());
+                                }
+                                egui::CollapsingHeader::new("studiomdl output")
+                                    .id_source((index, "output"))
+                                    .show(ui, |ui| {
+                                        ui.monospace(&log.stdout);
+                                        if !log.stderr.is_empty() {
+                                            ui.monospace(&log.stderr);
+                                        }
+                                    });
+                            });
+                    }
+                });
+            });
+    }
+
+    // what compiled and what didn't, failures can be retried one by one before packaging
+    fn show_summary(&mut self, ctx: &CtxRef) {
+        let (items, worker) = match (&self.summary, &self.worker) {
+            (Some(items), Some(worker)) if self.awaiting_decision => (items, worker),
+            _ => return,
+        };
+        let count =
+            |wanted: fn(&Status) -> bool| items.iter().filter(|item| wanted(&item.status)).count();
+        let mut retry = None;
+        let mut package = false;
+        let mut discard = false;
+        egui::Window::new("Summary")
+            .collapsible(false)
+            .default_height(400.0)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} compiled, {} failed, {} skipped",
+                    count(|status| *status == Status::Succeeded),
+                    count(|status| matches!(status, Status::Failed(_))),
+                    count(|status| matches!(status, Status::Skipped(_)))
+                ));
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for item in items {
+                            match &item.status {
+                                Status::Succeeded => {}
+                                Status::Failed(error) => {
+                                    ui.horizontal(|ui| {
+                                        if ui.button("Retry").clicked() {
+                                            retry = Some(item.index);
+                                        }
+                                        ui.colored_label(egui::Color32::RED, &item.weapon);
+                                    });
+                                    ui.label(error);
+                                }
+                                Status::Skipped(failed) => {
+                                    let failed = items
+                                        .iter()
+                                        .find(|other| other.index == *failed)
+                                        .map_or("", |other| other.weapon.as_str());
+                                    ui.horizontal(|ui| {
+                                        // retries the failed one first
+                                        if ui.button("Retry").clicked() {
+                                            retry = Some(item.index);
+                                        }
+                                        ui.label(format!(
+                                            "{}: skipped, it needs {}",
+                                            item.weapon, failed
+                                        ));
+                                    });
+                                }
+                            }
+                        }
+                    });
+                ui.separator();
+                ui.horizontal(|ui| {
+                    package = ui.button("Package what compiled").clicked();
+                    discard = ui.button("Discard").clicked();
+                });
+            });
+        if let Some(index) = retry {
+            worker.retry(index);
+            self.status = "Retrying...".to_owned();
+            self.awaiting_decision = false;
+        } else if package {
+            worker.package();
+            self.awaiting_decision = false;
+        } else if discard {
+            worker.cancel();
+            self.status = "Cancelling...".to_owned();
+            self.awaiting_decision = false;
+        }
+    }
+
+    // offsets for weapons the SCA folder doesn't have, they can go to another weapon of the class
+    fn show_orphans(&mut self, ctx: &CtxRef) {
+        if self.sca.orphans.is_empty() || self.orphans_later || self.worker.is_some() {
+            return;
+        }
+        let mut remap = None;
+        let mut drop = None;
+        let mut drop_all = false;
+        let mut later = false;
+        let sca = &self.sca;
+        egui::Window::new("Weapons that aren't there anymore")
+            .collapsible(false)
+            .default_height(300.0)
+            .show(ctx, |ui| {
+                ui.label("The profile has offsets for weapons that aren't in the SCA folder.");
+                ui.label("Move them to a weapon of the same class or drop them.");
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        for (index, orphan) in sca.orphans.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "{} {}: {}",
+                                    orphan.class,
+                                    orphan.name,
+                                    orphan.layer.describe()
+                                ));
+                                egui::ComboBox::from_id_source(("orphan", index))
+                                    .selected_text("Move to")
+                                    .show_ui(ui, |ui| {
+                                        let animations = sca
+                                            .folders
+                                            .iter()
+                                            .filter(|folder| folder.class == orphan.class)
+                                            .flat_map(|folder| folder.animations.iter());
+                                        for animation in animations {
+                                            if ui.selectable_label(false, &animation.name).clicked()
+                                            {
+                                                remap = Some((index, animation.name.clone()));
+                                            }
+                                        }
+                                    });
+                                if ui.button("Drop").clicked() {
+                                    drop = Some(index);
+                                }
+                            });
+                        }
+                    });
+                ui.separator();
+                ui.horizontal(|ui| {
+                    drop_all = ui.button("Drop all").clicked();
+                    later = ui.button("Later").clicked();
+                });
+            });
+        if let Some((index, animation)) = remap {
+            if let Err(error) = self.sca.remap_orphan(index, &animation) {
+                self.status = format!("{:#}", error);
+            }
+        } else if let Some(index) = drop {
+            self.sca.drop_orphan(index);
+        } else if drop_all {
+            self.sca.orphans.clear();
+        }
+        self.orphans_later = later;
+    }
+
+    // lists what earlier runs left behind and lets the user roll back or finish them
+    fn show_recovery(&mut self, ctx: &CtxRef) {
+        let recovery = match &self.recovery {
+            Some(recovery) => recovery,
+            None => return,
+        };
+        let mut roll_back = false;
+        let mut finish = false;
+        let mut later = false;
+        egui::Window::new("Unfinished runs")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("The last time v_customizer didn't get to clean up after itself:");
+                for line in recovery.describe() {
+                    ui.label(line);
+                }
+                ui.separator();
+                ui.horizontal(|ui| {
+                    roll_back = ui.button("Roll back").clicked();
+                    finish = ui
+                        .add_enabled(recovery.can_finish(), egui::Button::new("Finish"))
+                        .clicked();
+                    later = ui.button("Later").clicked();
+                });
+            });
+        let result = if roll_back {
+            self.recovery.take().unwrap().roll_back()
+        } else if finish {
+            self.recovery.take().unwrap().finish()
+        } else {
+            if later {
+                self.recovery = None;
+            }
+            return;
+        };
+        self.status = match result {
+            Ok(()) => "Recovered the unfinished runs".to_owned(),
+            Err(error) => format!("Recovering failed: {}", error),
+        };
+    }
+
+    // the layer the sliders edit
+    fn level(&self) -> Level {
+        match self.scope {
+            Scope::All => Level::Global,
+            Scope::Class => Level::Class(self.current_class),
+            Scope::Weapon => Level::Weapon(self.selected_class, self.selected_animation.clone()),
+        }
+    }
+
+    // ctrl+z undoes and ctrl+y or ctrl+shift+z redoes, unless a text field has the keyboard
+    fn undo_keys(&mut self, ctx: &CtxRef) {
+        if self.worker.is_some() || ctx.wants_keyboard_input() {
+            return;
+        }
+        let input = ctx.input();
+        if !input.modifiers.command {
+            return;
+        }
+        if input.key_pressed(Key::Y) || input.modifiers.shift && input.key_pressed(Key::Z) {
+            self.sca.redo();
+        } else if input.key_pressed(Key::Z) {
+            self.sca.undo();
+        }
+    }
+
+    // a slider for every value at level, the ones the layer doesn't set show what they inherit
+    // and from where, moving one sets it in the layer and x takes it out again
+    fn add_layer(ui: &mut Ui, sca: &mut sca::Sca, level: &Level) {
+        let (origin, sources) = sca.effective(level);
+        let own = match level {
+            Level::Global => Source::Global,
+            Level::Class(_) => Source::Class,
+            Level::Weapon(..) => Source::Weapon,
+        };
+        if sca.layer(level).is_none() {
+            return;
+        }
+        for (field, source) in FIELDS.iter().zip(sources) {
+            ui.horizontal(|ui| {
+                let mut value = field.get(&origin);
+                let slider = match field {
+                    Field::Scale => egui::Slider::new(&mut value, 0.25f32..=2f32),
+                    _ => egui::Slider::new(&mut value, -20f32..=20f32).clamp_to_range(false),
+                };
+                let response = ui.add(slider.text(field.name()));
+                // a whole drag undoes at once
+                if response.changed() && response.dragged() {
+                    sca.drag_field(level, *field, value);
+                } else if response.changed() {
+                    sca.set_field(level, *field, Some(value));
+                }
+                if response.drag_released() {
+                    sca.end_drag();
+                }
+                if source == own {
+                    if ui.small_button("x").on_hover_text("Inherit").clicked() {
+                        sca.set_field(level, *field, None);
+                    }
+                } else {
+                    ui.add(egui::Label::new(source.to_string()).weak());
+                }
+            });
+        }
+    }
+}
+
+// what a profile change did, with the weapons of the profile the SCA folder doesn't have
+fn profile_status(done: String, warnings: Vec<String>) -> String {
+    if warnings.is_empty() {
+        done
+    } else {
+        format!("{}, but: {}", done, warnings.join(", "))
     }
 }
 
 fn main() {
+    if let Some(code) = cli::main(std::env::args().skip(1)) {
+        std::process::exit(code);
+    }
     let app = App::default();
-    let mut native_options = eframe::NativeOptions::default();
-    native_options.initial_window_size = Some(eframe::egui::Vec2::new(560f32, 550f32));
+    let native_options = eframe::NativeOptions {
+        initial_window_size: Some(eframe::egui::Vec2::new(560f32, 550f32)),
+        ..Default::default()
+    };
     eframe::run_native(Box::new(app), native_options);
 }
@@ -0,0 +1,289 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use crate::smd::Matrix;
+
+const SIGNATURE: &[u8; 4] = b"IDST";
+// the versions that share the header and bone layout read here, tf2 uses 48
+const VERSIONS: std::ops::RangeInclusive<i32> = 44..=49;
+const CHECKSUM_OFFSET: usize = 8;
+const NAME_OFFSET: usize = 12;
+const NAME_LENGTH: usize = 64;
+const BONE_COUNT_OFFSET: usize = 156;
+const BONE_INDEX_OFFSET: usize = 160;
+// size of mstudiobone_t
+const BONE_SIZE: usize = 216;
+
+// the parts of a compiled model this program cares about
+#[derive(Clone, Debug)]
+pub struct Mdl {
+    pub version: i32,
+    // shared with the .vvd and .vtx files compiled with it
+    pub checksum: i32,
+    // the $modelname it was compiled with
+    pub name: String,
+    pub bones: Vec<Bone>,
+}
+
+// a bone in its reference pose, relative to its parent or to the model for root bones
+#[derive(Clone, Debug)]
+pub struct Bone {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub position: [f32; 3],
+    // radians, same order as smd skeletons
+    pub rotation: [f32; 3],
+}
+
+impl Bone {
+    pub fn matrix(&self) -> Matrix {
+        Matrix::from_euler(self.rotation[0], self.rotation[1], self.rotation[2])
+    }
+}
+
+impl Mdl {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.get(..4) != Some(&SIGNATURE[..]) {
+            bail!("It isn't an mdl");
+        }
+        let version = i32_at(data, 4)?;
+        if !VERSIONS.contains(&version) {
+            bail!("Unsupported mdl version {}", version);
+        }
+        let checksum = i32_at(data, CHECKSUM_OFFSET)?;
+        let name = string_at(data, NAME_OFFSET, NAME_LENGTH)?;
+        let count = i32_at(data, BONE_COUNT_OFFSET)?;
+        let index = i32_at(data, BONE_INDEX_OFFSET)?;
+        if count < 0 || index < 0 {
+            bail!("The bone table is broken");
+        }
+        let bones = (0..count as usize)
+            .map(|i| bone_at(data, index as usize + i * BONE_SIZE, count as usize))
+            .collect::<Result<Vec<Bone>>>()
+            .context("The bone table is broken")?;
+        Ok(Mdl {
+            version,
+            checksum,
+            name,
+            bones,
+        })
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("Can't parse {}", path.display()))
+    }
+
+    pub fn bone(&self, name: &str) -> Option<&Bone> {
+        self.bones
+            .iter()
+            .find(|bone| bone.name.eq_ignore_ascii_case(name))
+    }
+}
+
+// the bytes of a model with only the header and bones parse reads, for tests of the code
+// that reads compiled models
+#[cfg(test)]
+impl Mdl {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = BONE_INDEX_OFFSET + 4;
+        let mut data = vec![0; header + self.bones.len() * BONE_SIZE];
+        data[..4].copy_from_slice(SIGNATURE);
+        data[4..8].copy_from_slice(&self.version.to_le_bytes());
+        data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&self.checksum.to_le_bytes());
+        let name = stored_name(&self.name);
+        data[NAME_OFFSET..NAME_OFFSET + name.len()].copy_from_slice(name.as_bytes());
+        let count = self.bones.len() as i32;
+        data[BONE_COUNT_OFFSET..BONE_COUNT_OFFSET + 4].copy_from_slice(&count.to_le_bytes());
+        data[BONE_INDEX_OFFSET..header].copy_from_slice(&(header as i32).to_le_bytes());
+        for (i, bone) in self.bones.iter().enumerate() {
+            let start = header + i * BONE_SIZE;
+            let name_offset = (data.len() - start) as i32;
+            let parent = bone.parent.map_or(-1, |parent| parent as i32);
+            data[start..start + 4].copy_from_slice(&name_offset.to_le_bytes());
+            data[start + 4..start + 8].copy_from_slice(&parent.to_le_bytes());
+            for (offset, vector) in [(32, bone.position), (60, bone.rotation)] {
+                for (j, value) in vector.iter().enumerate() {
+                    let at = start + offset + j * 4;
+                    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            data.extend_from_slice(bone.name.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+}
+
+// mstudiobone_t: name offset, parent, 6 bone controllers, position, quaternion, euler rotation, ...
+fn bone_at(data: &[u8], start: usize, count: usize) -> Result<Bone> {
+    let name_offset = i32_at(data, start)?;
+    let parent = i32_at(data, start + 4)?;
+    let vector = |offset: usize| -> Result<[f32; 3]> {
+        Ok([
+            f32_at(data, start + offset)?,
+            f32_at(data, start + offset + 4)?,
+            f32_at(data, start + offset + 8)?,
+        ])
+    };
+    let parent = match parent {
+        -1 => None,
+        parent if parent >= 0 && (parent as usize) < count => Some(parent as usize),
+        parent => bail!("A bone has parent {}", parent),
+    };
+    let name_start = (start as i64 + name_offset as i64)
+        .try_into()
+        .map_err(|_| anyhow!("A bone name is outside of the file"))?;
+    Ok(Bone {
+        name: string_at(data, name_start, usize::MAX)?,
+        parent,
+        position: vector(32)?,
+        rotation: vector(60)?,
+    })
+}
+
+fn bytes_at(data: &[u8], offset: usize) -> Result<[u8; 4]> {
+    data.get(offset..offset + 4)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or_else(|| anyhow!("The file is cut off"))
+}
+
+fn i32_at(data: &[u8], offset: usize) -> Result<i32> {
+    Ok(i32::from_le_bytes(bytes_at(data, offset)?))
+}
+
+fn f32_at(data: &[u8], offset: usize) -> Result<f32> {
+    Ok(f32::from_le_bytes(bytes_at(data, offset)?))
+}
+
+// a 0 terminated string of at most max_length bytes
+// what's left of a $modelname in the header, studiomdl cuts it off to fit the name field
+pub fn stored_name(model: &str) -> &str {
+    let mut end = model.len().min(NAME_LENGTH - 1);
+    while !model.is_char_boundary(end) {
+        end -= 1;
+    }
+    &model[..end]
+}
+
+fn string_at(data: &[u8], offset: usize, max_length: usize) -> Result<String> {
+    let rest = data
+        .get(offset..)
+        .ok_or_else(|| anyhow!("A string is outside of the file"))?;
+    let rest = &rest[..rest.len().min(max_length)];
+    let length = rest
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(rest.len());
+    Ok(String::from_utf8_lossy(&rest[..length]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Mdl {
+        Mdl {
+            version: 48,
+            checksum: 1234,
+            name: "weapons/c_models/c_scout_arms.mdl".to_owned(),
+            bones: vec![
+                Bone {
+                    name: "root".to_owned(),
+                    parent: None,
+                    position: [1.0, -2.0, 3.5],
+                    rotation: [0.1, 0.2, -0.3],
+                },
+                Bone {
+                    name: "bip_hand_L".to_owned(),
+                    parent: Some(0),
+                    position: [0.0, 10.0, 0.0],
+                    rotation: [0.0, 0.0, 1.5],
+                },
+            ],
+        }
+    }
+
+    fn set_i32(data: &mut [u8], offset: usize, value: i32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn parses_the_header_and_bones() {
+        let mdl = Mdl::parse(&model().to_bytes()).unwrap();
+        assert_eq!(mdl.version, 48);
+        assert_eq!(mdl.checksum, 1234);
+        assert_eq!(mdl.name, "weapons/c_models/c_scout_arms.mdl");
+        assert_eq!(mdl.bones.len(), 2);
+        assert_eq!(mdl.bones[0].parent, None);
+        assert_eq!(mdl.bones[0].position, [1.0, -2.0, 3.5]);
+        assert_eq!(mdl.bones[0].rotation, [0.1, 0.2, -0.3]);
+        assert_eq!(mdl.bone("BIP_HAND_L").unwrap().parent, Some(0));
+    }
+
+    #[test]
+    fn long_names() {
+        let mut long = model();
+        long.name = format!("__TEMP/0_ViewmodelCustomized/models/{}", long.name);
+        let mdl = Mdl::parse(&long.to_bytes()).unwrap();
+        assert_eq!(mdl.name.len(), NAME_LENGTH - 1);
+        assert_eq!(mdl.name, stored_name(&long.name));
+        assert!(long.name.starts_with(&mdl.name));
+        assert_eq!(stored_name("short.mdl"), "short.mdl");
+    }
+
+    #[test]
+    fn no_bones() {
+        let mut empty = model();
+        empty.bones.clear();
+        assert!(Mdl::parse(&empty.to_bytes()).unwrap().bones.is_empty());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Mdl::parse(b"").is_err());
+        assert!(Mdl::parse(b"IDSV and more").is_err());
+        let mut data = model().to_bytes();
+        set_i32(&mut data, 4, 10);
+        assert!(Mdl::parse(&data).is_err());
+    }
+
+    #[test]
+    fn cut_off_files() {
+        let data = model().to_bytes();
+        // in the header, in the bone table and in the last name
+        for length in [8, 100, BONE_INDEX_OFFSET + 2, 200, 164 + BONE_SIZE + 40] {
+            assert!(Mdl::parse(&data[..length]).is_err(), "{} bytes", length);
+        }
+        // a name without its 0 ends with the file
+        let mdl = Mdl::parse(&data[..data.len() - 1]).unwrap();
+        assert_eq!(mdl.bones[1].name, "bip_hand_L");
+    }
+
+    #[test]
+    fn offsets_past_the_end() {
+        let data = model().to_bytes();
+        let start = BONE_INDEX_OFFSET + 4;
+        let broken = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = data.clone();
+            edit(&mut data);
+            Mdl::parse(&data)
+        };
+        // more bones than there are
+        assert!(broken(&|data| set_i32(data, BONE_COUNT_OFFSET, 3)).is_err());
+        assert!(broken(&|data| set_i32(data, BONE_COUNT_OFFSET, i32::MAX)).is_err());
+        assert!(broken(&|data| set_i32(data, BONE_COUNT_OFFSET, -1)).is_err());
+        // a bone table after the end of the file
+        assert!(broken(&|data| set_i32(data, BONE_INDEX_OFFSET, 1 << 30)).is_err());
+        assert!(broken(&|data| set_i32(data, BONE_INDEX_OFFSET, -4)).is_err());
+        // names before the start or after the end of the file
+        assert!(broken(&|data| set_i32(data, start, 1 << 30)).is_err());
+        assert!(broken(&|data| set_i32(data, start, -(start as i32) - 1)).is_err());
+        // a parent that isn't one of the bones
+        assert!(broken(&|data| set_i32(data, start + BONE_SIZE + 4, 2)).is_err());
+        assert!(broken(&|data| set_i32(data, start + BONE_SIZE + 4, -2)).is_err());
+    }
+}
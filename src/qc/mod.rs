@@ -0,0 +1,473 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::path::Path;
+
+pub mod token;
+
+pub use token::{Token, TokenKind};
+
+// a parsed .qc/.qci file
+//
+// it keeps every token with the whitespace and comments in front of it, so writing it back
+// gives the file it was read from byte for byte, edits only touch what they change
+#[derive(Clone, Debug, PartialEq)]
+pub struct Qc {
+    pub commands: Vec<Command>,
+    // whitespace and comments after the last token
+    pub trailing: String,
+}
+
+// a $command with its arguments, or one line inside a block
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    pub args: Vec<Arg>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Token(Token),
+    Block(Block),
+}
+
+// { ... } after a command, like the options of a $sequence
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub open: Token,
+    pub commands: Vec<Command>,
+    pub close: Token,
+}
+
+// typed views of the commands this program cares about, everything else is Other
+#[derive(Clone, Debug, PartialEq)]
+pub enum Directive<'a> {
+    ModelName(&'a str),
+    Include(&'a str),
+    IncludeModel(&'a str),
+    Cd(&'a str),
+    // $origin x y z [rotation]
+    Origin {
+        position: [f32; 3],
+        rotation: f32,
+    },
+    Sequence {
+        name: &'a str,
+        file: Option<&'a str>,
+    },
+    // $definebone name parent px py pz rx ry rz ...
+    DefineBone {
+        name: &'a str,
+        parent: &'a str,
+        position: [f32; 3],
+        rotation: [f32; 3],
+    },
+    Other(&'a str),
+}
+
+impl Qc {
+    pub fn parse<T: AsRef<str>>(text: T) -> Result<Self> {
+        let (tokens, trailing) = token::tokenize(text.as_ref())?;
+        let (commands, _) = parse_commands(&mut tokens.into_iter(), None)?;
+        Ok(Qc { commands, trailing })
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read {}", path.display()))?;
+        Self::parse(text).with_context(|| format!("Can't parse {}", path.display()))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    // top level commands with this name, $ included, ignoring case
+    pub fn find<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (usize, &'a Command)> {
+        self.commands
+            .iter()
+            .enumerate()
+            .filter(move |(_, command)| command.is(name))
+    }
+
+    // the top level $modelname, relative to models/
+    pub fn model_name(&self) -> Option<&str> {
+        self.commands
+            .iter()
+            .find_map(|command| match command.directive() {
+                Ok(Directive::ModelName(model)) => Some(model),
+                _ => None,
+            })
+    }
+
+    // puts command on a line of its own in front of the command at index
+    pub fn insert(&mut self, index: usize, mut command: Command) {
+        if index == 0 {
+            if let Some(first) = self.commands.first_mut().and_then(|c| c.first_token_mut()) {
+                first.leading.insert(0, '\n');
+            } else {
+                self.trailing.insert(0, '\n');
+            }
+        } else if let Some(token) = command.first_token_mut() {
+            token.leading = "\n".to_owned();
+        }
+        self.commands.insert(index, command);
+    }
+
+    // comments in front of the removed command stay where they were,
+    // and the command after it keeps starting on a line of its own
+    pub fn remove(&mut self, index: usize) -> Command {
+        let mut command = self.commands.remove(index);
+        let leading = command
+            .first_token_mut()
+            .map(|token| std::mem::take(&mut token.leading))
+            .unwrap_or_default();
+        let has_comment = leading.contains("//") || leading.contains("/*");
+        match self
+            .commands
+            .get_mut(index)
+            .and_then(|c| c.first_token_mut())
+        {
+            Some(next) if has_comment || !next.starts_line() => {
+                next.leading.insert_str(0, &leading)
+            }
+            Some(_) => {}
+            None if has_comment => self.trailing.insert_str(0, &leading),
+            None => {}
+        }
+        command
+    }
+}
+
+impl std::fmt::Display for Qc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for command in &self.commands {
+            write!(f, "{}", command)?;
+        }
+        f.write_str(&self.trailing)
+    }
+}
+
+impl Command {
+    // name and arguments on one line, separated by spaces
+    pub fn new<T: Into<String>>(name: T, args: Vec<Token>) -> Self {
+        let mut tokens = vec![Arg::Token(Token::word(name))];
+        tokens.extend(args.into_iter().map(|mut token| {
+            token.leading = " ".to_owned();
+            Arg::Token(token)
+        }));
+        Command { args: tokens }
+    }
+
+    // the first word, which is the $command at the top level
+    pub fn name(&self) -> Option<&str> {
+        match self.args.first() {
+            Some(Arg::Token(token)) => Some(token.value()),
+            _ => None,
+        }
+    }
+
+    pub fn is(&self, name: &str) -> bool {
+        self.name()
+            .is_some_and(|own| own.eq_ignore_ascii_case(name))
+    }
+
+    // the tokens after the name, blocks left out
+    pub fn values(&self) -> Vec<&str> {
+        self.args
+            .iter()
+            .skip(1)
+            .filter_map(|arg| match arg {
+                Arg::Token(token) => Some(token.value()),
+                Arg::Block(_) => None,
+            })
+            .collect()
+    }
+
+    // changes the value at index of values(), keeping its quotes and spacing
+    pub fn set_value<T: AsRef<str>>(&mut self, index: usize, value: T) -> Result<()> {
+        let line_name = self.line_name();
+        let token = self
+            .args
+            .iter_mut()
+            .skip(1)
+            .filter_map(|arg| match arg {
+                Arg::Token(token) => Some(token),
+                Arg::Block(_) => None,
+            })
+            .nth(index)
+            .ok_or_else(|| anyhow!("{} has no value {}", line_name, index))?;
+        token.set_value(value);
+        Ok(())
+    }
+
+    // appends a value, separated by a space
+    pub fn push(&mut self, mut token: Token) {
+        token.leading = " ".to_owned();
+        self.args.push(Arg::Token(token));
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.args.iter().filter_map(|arg| match arg {
+            Arg::Block(block) => Some(block),
+            Arg::Token(_) => None,
+        })
+    }
+
+    pub fn line(&self) -> usize {
+        match self.args.first() {
+            Some(Arg::Token(token)) => token.line,
+            Some(Arg::Block(block)) => block.open.line,
+            None => 0,
+        }
+    }
+
+    pub fn directive(&self) -> Result<Directive<'_>> {
+        let name = match self.name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Ok(Directive::Other("")),
+        };
+        let values = self.values();
+        let string = |index: usize| {
+            values
+                .get(index)
+                .copied()
+                .ok_or_else(|| anyhow!("{} is missing a value", self.line_name()))
+        };
+        let number = |index: usize, default: Option<f32>| -> Result<f32> {
+            match (values.get(index), default) {
+                (Some(value), _) => value
+                    .parse()
+                    .with_context(|| format!("{}: {} isn't a number", self.line_name(), value)),
+                (None, Some(default)) => Ok(default),
+                (None, None) => bail!("{} is missing a value", self.line_name()),
+            }
+        };
+        Ok(match name.as_str() {
+            "$modelname" => Directive::ModelName(string(0)?),
+            "$include" => Directive::Include(string(0)?),
+            "$includemodel" => Directive::IncludeModel(string(0)?),
+            "$cd" => Directive::Cd(string(0)?),
+            "$origin" => Directive::Origin {
+                position: [number(0, None)?, number(1, None)?, number(2, None)?],
+                rotation: number(3, Some(0.0))?,
+            },
+            "$sequence" => Directive::Sequence {
+                name: string(0)?,
+                file: values.get(1).copied(),
+            },
+            "$definebone" => Directive::DefineBone {
+                name: string(0)?,
+                parent: string(1)?,
+                position: [number(2, None)?, number(3, None)?, number(4, None)?],
+                rotation: [number(5, None)?, number(6, None)?, number(7, None)?],
+            },
+            _ => Directive::Other(self.name().unwrap_or_default()),
+        })
+    }
+
+    fn first_token_mut(&mut self) -> Option<&mut Token> {
+        match self.args.first_mut() {
+            Some(Arg::Token(token)) => Some(token),
+            Some(Arg::Block(block)) => Some(&mut block.open),
+            None => None,
+        }
+    }
+
+    // for error messages
+    fn line_name(&self) -> String {
+        format!("{} on line {}", self.name().unwrap_or("{"), self.line())
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for arg in &self.args {
+            match arg {
+                Arg::Token(token) => write!(f, "{}{}", token.leading, token.text)?,
+                Arg::Block(block) => {
+                    write!(f, "{}{}", block.open.leading, block.open.text)?;
+                    for command in &block.commands {
+                        write!(f, "{}", command)?;
+                    }
+                    write!(f, "{}{}", block.close.leading, block.close.text)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// at the top level every $word starts a new command,
+// inside a block every line does, a block belongs to the command in front of it
+// returns the commands and the } that closed the block
+fn parse_commands<I: Iterator<Item = Token>>(
+    tokens: &mut I,
+    open: Option<&Token>,
+) -> Result<(Vec<Command>, Option<Token>)> {
+    let mut commands: Vec<Command> = Vec::new();
+    loop {
+        let token = match (tokens.next(), open) {
+            (Some(token), _) => token,
+            (None, Some(open)) => bail!("Line {}: the {{ is never closed", open.line),
+            (None, None) => return Ok((commands, None)),
+        };
+        let starts_command = match token.kind {
+            TokenKind::Close if open.is_some() => return Ok((commands, Some(token))),
+            TokenKind::Close => bail!("Line {}: there's no {{ for this }}", token.line),
+            TokenKind::Open => false,
+            TokenKind::Word if open.is_none() => token.text.starts_with('$'),
+            TokenKind::Word | TokenKind::Quoted => open.is_some() && token.starts_line(),
+        };
+        let arg = match token.kind {
+            TokenKind::Open => {
+                let (inner, close) = parse_commands(tokens, Some(&token))?;
+                Arg::Block(Block {
+                    open: token,
+                    commands: inner,
+                    close: close.unwrap(),
+                })
+            }
+            _ => Arg::Token(token),
+        };
+        match commands.last_mut() {
+            Some(command) if !starts_command => command.args.push(arg),
+            _ => commands.push(Command { args: vec![arg] }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // like the class qcs of the SCA
+    const CLASS_QC: &str = r#"// scout viewmodel
+$modelname "weapons/c_models/c_scout_arms.mdl"
+$cd "."
+/* the bones
+   of the arms */
+$definebone "root" "" 0.000000 0.000000 0.000000 0.000000 -90.000000 0.000000 0 0 0 0 0 0
+$definebone	"bip_hand_L"	"root"	1.5 -2.25 3	0 0 0
+$include "shared.qci"
+
+$sequence "draw" "draw.smd" {
+	fps 30
+	"ACT_VM_DRAW" 1
+	{ event AE_CL_PLAYSOUND 1 "Weapon_Bat.Draw" }
+	snap
+}
+$sequence idle idle.smd loop fps 30 activity ACT_VM_IDLE 1
+"#;
+
+    fn round_trip(text: &str) -> Qc {
+        let qc = Qc::parse(text).unwrap();
+        assert_eq!(qc.to_string(), text);
+        qc
+    }
+
+    #[test]
+    fn round_trips_sca_qc() {
+        let qc = round_trip(CLASS_QC);
+        assert_eq!(qc.commands.len(), 7);
+        assert_eq!(qc.model_name(), Some("weapons/c_models/c_scout_arms.mdl"));
+    }
+
+    #[test]
+    fn round_trips_crlf() {
+        round_trip(&CLASS_QC.replace('\n', "\r\n"));
+    }
+
+    #[test]
+    fn round_trips_edge_cases() {
+        round_trip("");
+        round_trip("   \n// only a comment");
+        round_trip("$cd \"\"");
+        round_trip("$sequence a a.smd{{}}{ }");
+        round_trip(
+            "$bodygroup \"arms\"\n{\n\tstudio \"arms.smd\" /* inner */ // line\n\tblank\n}\n",
+        );
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let qc = round_trip(CLASS_QC);
+        let (_, draw) = qc.find("$SEQUENCE").next().unwrap();
+        let block = draw.blocks().next().unwrap();
+        let lines = block
+            .commands
+            .iter()
+            .map(|command| command.name().unwrap_or("{"))
+            .collect::<Vec<_>>();
+        // a block on a line of its own belongs to the line before
+        assert_eq!(lines, ["fps", "ACT_VM_DRAW", "snap"]);
+        let event = block.commands[1].blocks().next().unwrap();
+        assert_eq!(
+            event.commands[0].values(),
+            ["AE_CL_PLAYSOUND", "1", "Weapon_Bat.Draw"]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let qc = round_trip(CLASS_QC);
+        let directives = qc
+            .commands
+            .iter()
+            .map(|command| command.directive().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(directives[1], Directive::Cd("."));
+        assert_eq!(
+            directives[3],
+            Directive::DefineBone {
+                name: "bip_hand_L",
+                parent: "root",
+                position: [1.5, -2.25, 3.0],
+                rotation: [0.0, 0.0, 0.0],
+            }
+        );
+        assert_eq!(directives[4], Directive::Include("shared.qci"));
+        assert_eq!(
+            directives[5],
+            Directive::Sequence {
+                name: "draw",
+                file: Some("draw.smd"),
+            }
+        );
+        assert_eq!(
+            directives[6],
+            Directive::Sequence {
+                name: "idle",
+                file: Some("idle.smd"),
+            }
+        );
+    }
+
+    #[test]
+    fn edits_keep_the_rest() {
+        let mut qc = round_trip(CLASS_QC);
+        let mut origin = Command::new("$origin", vec![Token::word("1"), Token::word("2")]);
+        origin.push(Token::word("3"));
+        qc.insert(1, origin);
+        qc.commands[0].set_value(0, "weapons/v_bat.mdl").unwrap();
+        let text = qc.to_string();
+        assert!(text.starts_with(
+            "// scout viewmodel\n$modelname \"weapons/v_bat.mdl\"\n$origin 1 2 3\n$cd"
+        ));
+        let removed = qc.remove(1);
+        assert_eq!(removed.to_string(), "$origin 1 2 3");
+        assert_eq!(
+            qc.to_string(),
+            CLASS_QC.replace("c_models/c_scout_arms", "v_bat")
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(Qc::parse("$modelname \"a.mdl").is_err());
+        assert!(Qc::parse("$cd . /* comment").is_err());
+        let error = Qc::parse("$sequence a a.smd {\n fps 30\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: the { is never closed");
+        assert!(Qc::parse("$cd . }").is_err());
+    }
+}
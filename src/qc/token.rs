@@ -0,0 +1,197 @@
+use anyhow::{bail, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    // anything unquoted, $commands, numbers, options like loop or fps
+    Word,
+    // "...", the quotes are part of the text
+    Quoted,
+    Open,
+    Close,
+}
+
+// one token with everything in front of it, so writing every token back gives the same file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    // whitespace and comments since the previous token
+    pub leading: String,
+    pub kind: TokenKind,
+    // as written in the file
+    pub text: String,
+    // where the token starts, 0 for tokens that weren't read from a file
+    pub line: usize,
+}
+
+impl Token {
+    pub fn word<T: Into<String>>(text: T) -> Self {
+        Token {
+            leading: String::new(),
+            kind: TokenKind::Word,
+            text: text.into(),
+            line: 0,
+        }
+    }
+
+    pub fn quoted<T: AsRef<str>>(value: T) -> Self {
+        Token {
+            leading: String::new(),
+            kind: TokenKind::Quoted,
+            text: format!("\"{}\"", value.as_ref()),
+            line: 0,
+        }
+    }
+
+    // the text without quotes
+    pub fn value(&self) -> &str {
+        match self.kind {
+            TokenKind::Quoted => &self.text[1..self.text.len() - 1],
+            _ => &self.text,
+        }
+    }
+
+    // keeps the quoting of the original token
+    pub fn set_value<T: AsRef<str>>(&mut self, value: T) {
+        self.text = match self.kind {
+            TokenKind::Quoted => format!("\"{}\"", value.as_ref()),
+            _ => value.as_ref().to_owned(),
+        };
+    }
+
+    // whether the token starts on a line of its own
+    pub fn starts_line(&self) -> bool {
+        self.leading.contains('\n')
+    }
+}
+
+// splits text into tokens, what comes after the last token is returned separately
+pub fn tokenize(text: &str) -> Result<(Vec<Token>, String)> {
+    // every character that ends a token is ascii, so slicing at them stays on char boundaries
+    let bytes = text.as_bytes();
+    let ends_word = |i: usize| {
+        bytes[i].is_ascii_whitespace()
+            || matches!(bytes[i], b'"' | b'{' | b'}')
+            || (bytes[i] == b'/' && matches!(bytes.get(i + 1), Some(b'/' | b'*')))
+    };
+    let mut tokens = Vec::new();
+    let mut leading = String::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let start_line = line;
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+            }
+            c if c.is_ascii_whitespace() => i += 1,
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = match text[i + 2..].find("*/") {
+                    Some(end) => i + 2 + end + 2,
+                    None => bail!("Line {}: the comment never ends", start_line),
+                };
+                line += text[i..end].matches('\n').count();
+                i = end;
+            }
+            b'"' => {
+                let end = match text[i + 1..].find(['"', '\n']) {
+                    Some(end) if bytes[i + 1 + end] == b'"' => i + 1 + end + 1,
+                    _ => bail!("Line {}: the string never ends", start_line),
+                };
+                tokens.push(token(&mut leading, TokenKind::Quoted, &text[i..end], line));
+                i = end;
+                continue;
+            }
+            b'{' | b'}' => {
+                let kind = if bytes[i] == b'{' {
+                    TokenKind::Open
+                } else {
+                    TokenKind::Close
+                };
+                tokens.push(token(&mut leading, kind, &text[i..i + 1], line));
+                i += 1;
+                continue;
+            }
+            _ => {
+                i += 1;
+                while i < bytes.len() && !ends_word(i) {
+                    i += 1;
+                }
+                tokens.push(token(&mut leading, TokenKind::Word, &text[start..i], line));
+                continue;
+            }
+        }
+        leading.push_str(&text[start..i]);
+    }
+    Ok((tokens, leading))
+}
+
+fn token<T: Into<String>>(leading: &mut String, kind: TokenKind, text: T, line: usize) -> Token {
+    Token {
+        leading: std::mem::take(leading),
+        kind,
+        text: text.into(),
+        line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_and_lines() {
+        let (tokens, trailing) = tokenize("$cd \".\"\n{ fps 30 }// end\n").unwrap();
+        let kinds = tokens.iter().map(|token| token.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Word,
+                TokenKind::Quoted,
+                TokenKind::Open,
+                TokenKind::Word,
+                TokenKind::Word,
+                TokenKind::Close,
+            ]
+        );
+        assert_eq!(tokens[1].value(), ".");
+        assert_eq!(tokens[2].line, 2);
+        assert_eq!(trailing, "// end\n");
+    }
+
+    #[test]
+    fn comments_go_into_leading() {
+        let (tokens, _) = tokenize("a /* one\ntwo */ // three\r\nb").unwrap();
+        assert_eq!(tokens[1].leading, " /* one\ntwo */ // three\r\n");
+        assert_eq!(tokens[1].line, 3);
+    }
+
+    #[test]
+    fn words_end_at_comments_and_quotes() {
+        let (tokens, _) = tokenize("a//b\nc\"d\"e/*f*/").unwrap();
+        let texts = tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["a", "c", "\"d\"", "e"]);
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let error = tokenize("$modelname \"weapons/c_models").unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: the string never ends");
+        // strings don't go over lines
+        assert!(tokenize("$cd \"a\n\"").is_err());
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let error = tokenize("$cd .\n/* never closed\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 2: the comment never ends");
+    }
+}
@@ -0,0 +1,375 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::graph::Graph;
+use super::{process, Sca};
+use crate::fs;
+
+// bump when what goes into a key changes, so older entries stop matching
+const KEY_VERSION: u32 = 1;
+// the least recently used entries past this get removed after a run
+const MAX_ENTRIES: usize = 300;
+// rewritten on every hit, its modification time is when the entry was last used
+const USED_FILE_NAME: &str = "used";
+const MODELS_FOLDER_NAME: &str = "models";
+// partial entries this old are left over even if their pid is alive, it got reused by then
+const PARTIAL_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// compiled models of earlier runs, by a hash of everything that went into compiling them
+//
+// every entry is a folder named after its key with the models/ tree one qc compiled, so a weapon
+// whose qc, smds and $origin didn't change since a run with the same studiomdl skips studiomdl
+pub struct Cache {
+    folder: PathBuf,
+    // by graph index
+    keys: Vec<String>,
+}
+
+impl Cache {
+    // works out the keys of every qc of graph, which has to be copied and transformed already,
+    // source is the folder the qcs were copied to
+    pub fn open<P: AsRef<Path>>(folder: P, graph: &Graph, source: &Path) -> Result<Self> {
+        let compiler = file_hash(&Sca::studiomdl_exe()?)?;
+        Self::with_compiler(folder.as_ref(), graph, source, &compiler)
+    }
+
+    // compiler is the hash of studiomdl
+    fn with_compiler(folder: &Path, graph: &Graph, source: &Path, compiler: &str) -> Result<Self> {
+        let folder = folder.to_path_buf();
+        std::fs::create_dir_all(&folder)
+            .with_context(|| format!("Can't create the cache folder {}", folder.display()))?;
+        let mut keys = vec![String::new(); graph.len()];
+        // dependencies come first, their keys go into the keys of what includes them
+        for &index in graph.order() {
+            keys[index] = key(graph, index, source, compiler, &keys)?;
+        }
+        Ok(Cache { folder, keys })
+    }
+
+    pub fn key(&self, index: usize) -> &str {
+        &self.keys[index]
+    }
+
+    fn entry(&self, index: usize) -> PathBuf {
+        self.folder.join(&self.keys[index])
+    }
+
+    // copies the cached models of the qc at index into models, false if there aren't any
+    pub fn restore<P: AsRef<Path>>(&self, index: usize, models: P) -> Result<bool> {
+        let entry = self.entry(index);
+        let cached = entry.join(MODELS_FOLDER_NAME);
+        if !cached.is_dir() {
+            return Ok(false);
+        }
+        fs::copy_folder(&cached, models.as_ref())?;
+        std::fs::write(entry.join(USED_FILE_NAME), [])?;
+        Ok(true)
+    }
+
+    // keeps what the qc at index compiled into models,
+    // the entry only shows up once it's complete so a crash never leaves half of one behind
+    pub fn store<P: AsRef<Path>>(&self, index: usize, models: P) -> Result<()> {
+        let entry = self.entry(index);
+        if entry.is_dir() {
+            return Ok(());
+        }
+        let partial = self.folder.join(format!(
+            "{}.partial-{}",
+            self.keys[index],
+            std::process::id()
+        ));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        let result = fs::copy_folder(models.as_ref(), &partial.join(MODELS_FOLDER_NAME))
+            .and_then(|()| Ok(std::fs::write(partial.join(USED_FILE_NAME), [])?))
+            .and_then(|()| Ok(std::fs::rename(&partial, &entry)?));
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&partial);
+        }
+        result
+    }
+
+    // removes the least recently used entries past MAX_ENTRIES, and partial ones of dead runs
+    pub fn prune(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.folder)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Some((_, pid)) = name.rsplit_once(".partial-") {
+                // other runs can be storing into the cache at the same time
+                let running = pid.parse().is_ok_and(|pid: u32| {
+                    pid == std::process::id() || process::start(pid).is_some()
+                });
+                let old = std::fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .filter(|age| *age <= PARTIAL_MAX_AGE)
+                    .is_none();
+                if !running || old {
+                    std::fs::remove_dir_all(&path)?;
+                }
+                continue;
+            }
+            let used = std::fs::metadata(path.join(USED_FILE_NAME))
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((used, path));
+        }
+        entries.sort_by_key(|(used, _)| std::cmp::Reverse(*used));
+        for (_, path) in entries.into_iter().skip(MAX_ENTRIES) {
+            std::fs::remove_dir_all(&path)?;
+        }
+        Ok(())
+    }
+
+    // removes every entry, returns how many there were
+    pub fn clear<P: AsRef<Path>>(folder: P) -> Result<usize> {
+        let folder = folder.as_ref();
+        if !folder.is_dir() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for entry in std::fs::read_dir(folder)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                std::fs::remove_dir_all(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// sha256 over the compiler, the qc with everything next to it and the keys of the models it
+// includes, the $origin and smd transform are in there through the rewritten qc and smds
+// paths go in relative to source, the workspace is somewhere else every run
+fn key(
+    graph: &Graph,
+    index: usize,
+    source: &Path,
+    compiler: &str,
+    keys: &[String],
+) -> Result<String> {
+    let node = &graph.nodes()[index];
+    let folder = node.qc.parent().unwrap_or(Path::new(""));
+    // other qcs in the build, the animation folders inside a class folder, are models of their own
+    let others = graph
+        .nodes()
+        .iter()
+        .filter(|other| other.qc != node.qc)
+        .filter_map(|other| other.qc.parent())
+        .filter(|other| *other != folder)
+        .collect::<Vec<&Path>>();
+    let mut files = fs::walk(folder, |path| !others.contains(&path))?;
+    for included in &node.included_files {
+        if !files.contains(included) {
+            files.push(included.clone());
+        }
+    }
+    let mut files = files
+        .iter()
+        .map(|path| (fs::relative_path(path, source), path))
+        .collect::<Vec<_>>();
+    files.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_VERSION.to_le_bytes());
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update([0]);
+    hasher.update(compiler.as_bytes());
+    hasher.update(fs::relative_path(&node.qc, source).as_bytes());
+    hasher.update([0]);
+    for (name, path) in files {
+        let content =
+            std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    let mut dependencies = node
+        .dependencies
+        .iter()
+        .map(|&dependency| keys[dependency].as_str())
+        .collect::<Vec<&str>>();
+    dependencies.sort_unstable();
+    for dependency in dependencies {
+        hasher.update(dependency.as_bytes());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn file_hash(path: &Path) -> Result<String> {
+    let content = std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(&content)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::test_folder;
+
+    const OWN: &str = "__TEMP/0_ViewmodelCustomized/models/weapons";
+
+    fn write(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    // the pistol includes the model of the scout's arms, the bat has a qc of its own
+    fn source(folder: &Path) -> Vec<PathBuf> {
+        let arms = folder.join("scout/scout.qc");
+        write(&arms, &format!("$modelname \"{}/c_scout_arms.mdl\"\n", OWN));
+        write(&folder.join("scout/arms.smd"), "arms");
+        let pistol = folder.join("scout/pistol/pistol.qc");
+        write(
+            &pistol,
+            &format!(
+                "$modelname \"{0}/v_pistol.mdl\"\n$includemodel \"{0}/c_scout_arms.mdl\"\n\
+                 $origin 0 0 0\n",
+                OWN
+            ),
+        );
+        write(&folder.join("scout/pistol/idle.smd"), "pistol");
+        let bat = folder.join("scout/bat/bat.qc");
+        write(&bat, &format!("$modelname \"{}/v_bat.mdl\"\n", OWN));
+        vec![arms, pistol, bat]
+    }
+
+    fn keys(cache: &Path, source: &Path, compiler: &str) -> Vec<String> {
+        let qcs = source_qcs(source);
+        let graph = Graph::new(&qcs).unwrap();
+        let cache = Cache::with_compiler(cache, &graph, source, compiler).unwrap();
+        (0..qcs.len())
+            .map(|index| cache.key(index).to_owned())
+            .collect()
+    }
+
+    fn source_qcs(source: &Path) -> Vec<PathBuf> {
+        [
+            "scout/scout.qc",
+            "scout/pistol/pistol.qc",
+            "scout/bat/bat.qc",
+        ]
+        .iter()
+        .map(|qc| source.join(qc))
+        .collect()
+    }
+
+    #[test]
+    fn keys_stay_the_same() {
+        let folder = test_folder("cache_keys");
+        let cache = folder.join("cache");
+        source(&folder.join("a"));
+        let keys_a = keys(&cache, &folder.join("a"), "studiomdl");
+        assert_eq!(keys(&cache, &folder.join("a"), "studiomdl"), keys_a);
+        assert!(keys_a[0] != keys_a[1] && keys_a[1] != keys_a[2]);
+        assert!(keys_a.iter().all(|key| key.len() == 64));
+        // the workspace is somewhere else every run
+        source(&folder.join("b"));
+        assert_eq!(keys(&cache, &folder.join("b"), "studiomdl"), keys_a);
+        // another studiomdl compiles everything again
+        let other = keys(&cache, &folder.join("a"), "other studiomdl");
+        assert!(other.iter().zip(&keys_a).all(|(other, key)| other != key));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn changes_invalidate_what_they_touch() {
+        let folder = test_folder("cache_invalidation");
+        let (cache, source) = (folder.join("cache"), folder.join("source"));
+        let qcs = self::source(&source);
+        let before = keys(&cache, &source, "studiomdl");
+        let changed = |before: &[String], after: &[String]| {
+            before
+                .iter()
+                .zip(after)
+                .map(|(before, after)| before != after)
+                .collect::<Vec<bool>>()
+        };
+
+        // a new $origin is a change of the qc, only the pistol has to compile again
+        let pistol = std::fs::read_to_string(&qcs[1]).unwrap();
+        write(&qcs[1], &pistol.replace("$origin 0 0 0", "$origin 1 0 0"));
+        let after = keys(&cache, &source, "studiomdl");
+        assert_eq!(changed(&before, &after), [false, true, false]);
+
+        // the pistol's animation
+        write(&source.join("scout/pistol/idle.smd"), "moved pistol");
+        let again = keys(&cache, &source, "studiomdl");
+        assert_eq!(changed(&after, &again), [false, true, false]);
+
+        // the arms go into the pistol too
+        write(&source.join("scout/arms.smd"), "moved arms");
+        let arms = keys(&cache, &source, "studiomdl");
+        assert_eq!(changed(&again, &arms), [true, true, false]);
+
+        // a file that appears next to the bat's qc
+        write(&source.join("scout/bat/idle.smd"), "bat");
+        assert_eq!(
+            changed(&arms, &keys(&cache, &source, "studiomdl")),
+            [false, false, true]
+        );
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn store_and_restore() {
+        let folder = test_folder("cache_store");
+        let (cache_folder, source) = (folder.join("cache"), folder.join("source"));
+        let qcs = self::source(&source);
+        let cache =
+            Cache::with_compiler(&cache_folder, &Graph::new(&qcs).unwrap(), &source, "").unwrap();
+        let compiled = folder.join("compiled");
+        write(&compiled.join("weapons/v_pistol.mdl"), "pistol model");
+        let restored = folder.join("restored");
+        assert!(!cache.restore(1, &restored).unwrap());
+        cache.store(1, &compiled).unwrap();
+        assert!(cache.restore(1, &restored).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(restored.join("weapons/v_pistol.mdl")).unwrap(),
+            "pistol model"
+        );
+        assert!(!cache.restore(2, &restored).unwrap());
+        assert_eq!(Cache::clear(&cache_folder).unwrap(), 1);
+        assert!(!cache.restore(1, folder.join("again")).unwrap());
+        assert_eq!(Cache::clear(folder.join("nothing")).unwrap(), 0);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn prune() {
+        let folder = test_folder("cache_prune");
+        let cache = Cache {
+            folder: folder.clone(),
+            keys: Vec::new(),
+        };
+        for index in 0..MAX_ENTRIES {
+            write(
+                &folder.join(format!("{:064}", index)).join(USED_FILE_NAME),
+                "",
+            );
+        }
+        // never used, so the oldest
+        let unused = folder.join("f".repeat(64));
+        std::fs::create_dir_all(unused.join(MODELS_FOLDER_NAME)).unwrap();
+        let key = "e".repeat(64);
+        let dead = folder.join(format!("{}.partial-{}", key, u32::MAX - 1));
+        let alive = folder.join(format!("{}.partial-{}", key, std::process::id()));
+        std::fs::create_dir_all(&dead).unwrap();
+        std::fs::create_dir_all(&alive).unwrap();
+        cache.prune().unwrap();
+        assert!(!unused.exists() && !dead.exists());
+        // another run could still be storing it
+        assert!(alive.exists());
+        assert_eq!(std::fs::read_dir(&folder).unwrap().count(), MAX_ENTRIES + 1);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use std::path::PathBuf;
+use std::process::Output;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// one ERROR: or WARNING: line of studiomdl
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // the qc or smd it's about, as studiomdl printed it
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{} line {}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            _ => {}
+        }
+        f.write_str(&self.message)
+    }
+}
+
+// everything one studiomdl run printed
+#[derive(Clone, Debug)]
+pub struct CompileLog {
+    pub qc: PathBuf,
+    // which weapon or class the qc belongs to
+    pub weapon: String,
+    pub stdout: String,
+    pub stderr: String,
+    // None if it got killed
+    pub exit_code: Option<i32>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CompileLog {
+    pub fn new(qc: PathBuf, weapon: String, output: &Output) -> Self {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let mut diagnostics = parse(&stdout);
+        diagnostics.extend(parse(&stderr));
+        CompileLog {
+            qc,
+            weapon,
+            stdout,
+            stderr,
+            exit_code: output.status.code(),
+            diagnostics,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == severity)
+            .count()
+    }
+
+    // why the compile failed, the first error if studiomdl printed one
+    pub fn failure(&self) -> String {
+        if let Some(error) = self
+            .diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.severity == Severity::Error)
+        {
+            return error.to_string();
+        }
+        let last_line = |text: &str| {
+            text.lines()
+                .map(str::trim)
+                .rfind(|line| !line.is_empty())
+                .map(str::to_owned)
+        };
+        let last_line = last_line(&self.stderr).or_else(|| last_line(&self.stdout));
+        let exit = match self.exit_code {
+            Some(code) => format!("studiomdl exited with code {}", code),
+            None => "studiomdl got killed".to_owned(),
+        };
+        match last_line {
+            Some(line) => format!("{}, it last said: {}", exit, line),
+            None => exit,
+        }
+    }
+}
+
+// picks the errors and warnings out of studiomdl's output, they look like
+//   ERROR: c:\...\weapon.qc(12): - unknown command "$foo"
+//   WARNING: idle.smd(40): - bone "x" has no parent
+//   ERROR: Aborted Processing on 'weapon.mdl'
+pub fn parse(output: &str) -> Vec<Diagnostic> {
+    output.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<Diagnostic> {
+    let line = line.trim().trim_start_matches('*').trim_start();
+    let upper = line.to_ascii_uppercase();
+    let (severity, rest) = if upper.starts_with("ERROR:") {
+        (Severity::Error, &line["ERROR:".len()..])
+    } else if upper.starts_with("WARNING:") {
+        (Severity::Warning, &line["WARNING:".len()..])
+    } else {
+        return None;
+    };
+    let rest = rest.trim();
+    let located = rest.find("):").and_then(|close| {
+        let open = rest[..close].rfind('(')?;
+        let line = rest[open + 1..close].trim().parse::<usize>().ok()?;
+        Some((rest[..open].trim(), line, &rest[close + 2..]))
+    });
+    Some(match located {
+        Some((file, line, message)) => Diagnostic {
+            severity,
+            file: Some(file.to_owned()),
+            line: Some(line),
+            message: clean(message),
+        },
+        None => Diagnostic {
+            severity,
+            file: None,
+            line: None,
+            message: clean(rest),
+        },
+    })
+}
+
+// studiomdl puts a " - " between the location and the message
+fn clean(message: &str) -> String {
+    message.trim().trim_start_matches('-').trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(
+        severity: Severity,
+        file: Option<&str>,
+        line: Option<usize>,
+        message: &str,
+    ) -> Diagnostic {
+        Diagnostic {
+            severity,
+            file: file.map(str::to_owned),
+            line,
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn the_formats_studiomdl_prints() {
+        let output = "\
+Processing weapon.qc\r
+ERROR: c:\\tf\\models\\weapon.qc(12): - unknown command \"$foo\"\r
+WARNING: idle.smd(40): - bone \"x\" has no parent
+ERROR: Aborted Processing on 'weapon.mdl'
+Completed \"weapon.qc\"
+";
+        assert_eq!(
+            parse(output),
+            [
+                diagnostic(
+                    Severity::Error,
+                    Some("c:\\tf\\models\\weapon.qc"),
+                    Some(12),
+                    "unknown command \"$foo\""
+                ),
+                diagnostic(
+                    Severity::Warning,
+                    Some("idle.smd"),
+                    Some(40),
+                    "bone \"x\" has no parent"
+                ),
+                diagnostic(
+                    Severity::Error,
+                    None,
+                    None,
+                    "Aborted Processing on 'weapon.mdl'"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_without_a_location() {
+        assert_eq!(
+            parse("warning: too many bones (140)\nERROR: weapon.qc(x): not a line number"),
+            [
+                diagnostic(Severity::Warning, None, None, "too many bones (140)"),
+                diagnostic(
+                    Severity::Error,
+                    None,
+                    None,
+                    "weapon.qc(x): not a line number"
+                ),
+            ]
+        );
+        assert!(parse("").is_empty());
+        assert!(parse("no errors here\nERRORS: 0").is_empty());
+    }
+
+    #[test]
+    fn starred_lines() {
+        assert_eq!(
+            parse("*** ERROR: a.smd(3): - broken\n  ** WARNING: careful"),
+            [
+                diagnostic(Severity::Error, Some("a.smd"), Some(3), "broken"),
+                diagnostic(Severity::Warning, None, None, "careful"),
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_name_the_first_error() {
+        let mut log = CompileLog {
+            qc: PathBuf::from("weapon.qc"),
+            weapon: "Scout Pistol".to_owned(),
+            stdout: "WARNING: careful\nERROR: a.qc(2): - first\nERROR: second\n".to_owned(),
+            stderr: String::new(),
+            exit_code: Some(1),
+            diagnostics: Vec::new(),
+        };
+        log.diagnostics = parse(&log.stdout);
+        assert!(!log.success());
+        assert_eq!(
+            (log.count(Severity::Error), log.count(Severity::Warning)),
+            (2, 1)
+        );
+        assert_eq!(log.failure(), "a.qc line 2: first");
+        log.diagnostics.clear();
+        assert_eq!(
+            log.failure(),
+            "studiomdl exited with code 1, it last said: ERROR: second"
+        );
+        log.exit_code = None;
+        log.stdout.clear();
+        assert_eq!(log.failure(), "studiomdl got killed");
+    }
+}
@@ -0,0 +1,641 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::platform::{Runner, Tool, RUNNER_ENV};
+use super::vdf;
+
+pub const TF2_APP_ID: u32 = 440;
+pub const CONFIG_ENV: &str = "V_CUSTOMIZER_CONFIG";
+pub const TF_ENV: &str = "V_CUSTOMIZER_TF";
+pub const STUDIOMDL_ENV: &str = "V_CUSTOMIZER_STUDIOMDL";
+pub const SCA_ENV: &str = "V_CUSTOMIZER_SCA";
+pub const JOBS_ENV: &str = "V_CUSTOMIZER_JOBS";
+pub const CACHE_ENV: &str = "V_CUSTOMIZER_CACHE";
+// as the cache folder, turns the compile cache off
+pub const CACHE_OFF: &str = "off";
+const APP_FOLDER_NAME: &str = "v_customizer";
+
+// set once from the command line, wins over everything else
+static CLI_OVERRIDES: Mutex<Option<Overrides>> = Mutex::new(None);
+
+// explicitly configured paths, anything left as None gets discovered
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Overrides {
+    pub tf: Option<PathBuf>,
+    pub studiomdl: Option<PathBuf>,
+    pub sca: Option<PathBuf>,
+    // same format as V_CUSTOMIZER_RUNNER
+    pub runner: Option<String>,
+    // how many studiomdl processes run at once
+    pub jobs: Option<usize>,
+    // where compiled models are kept between runs, or "off"
+    pub cache: Option<PathBuf>,
+}
+
+impl Overrides {
+    // takes out the --tf/--studiomdl/--sca/--runner/--jobs/--cache options and returns the other arguments
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<(Self, Vec<String>)> {
+        let mut overrides = Overrides::default();
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_owned(), Some(arg[i + 1..].to_owned()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let slot = match flag.as_str() {
+                "--tf" => &mut overrides.tf,
+                "--studiomdl" => &mut overrides.studiomdl,
+                "--sca" => &mut overrides.sca,
+                "--cache" => &mut overrides.cache,
+                "--runner" => {
+                    let value = inline_value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| anyhow!("{} needs a value", flag))?;
+                    overrides.runner = Some(value);
+                    continue;
+                }
+                "--jobs" | "-j" => {
+                    let value = inline_value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| anyhow!("{} needs a number", flag))?;
+                    overrides.jobs = Some(parse_jobs(&value)?);
+                    continue;
+                }
+                _ => {
+                    rest.push(arg);
+                    continue;
+                }
+            };
+            let value = inline_value
+                .or_else(|| args.next())
+                .ok_or_else(|| anyhow!("{} needs a path", flag))?;
+            *slot = Some(PathBuf::from(value));
+        }
+        Ok((overrides, rest))
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let path = |var| {
+            std::env::var_os(var)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        };
+        let jobs = match std::env::var(JOBS_ENV) {
+            Ok(value) if !value.trim().is_empty() => {
+                Some(parse_jobs(&value).with_context(|| format!("Invalid {}", JOBS_ENV))?)
+            }
+            _ => None,
+        };
+        Ok(Overrides {
+            tf: path(TF_ENV),
+            studiomdl: path(STUDIOMDL_ENV),
+            sca: path(SCA_ENV),
+            runner: std::env::var(RUNNER_ENV)
+                .ok()
+                .filter(|v| !v.trim().is_empty()),
+            jobs,
+            cache: path(CACHE_ENV),
+        })
+    }
+
+    // reads the config file, a missing file is the same as an empty one
+    pub fn from_config() -> Result<Self> {
+        Self::read(&config_file()?)
+    }
+
+    fn read(config_file: &Path) -> Result<Self> {
+        if !config_file.is_file() {
+            return Ok(Overrides::default());
+        }
+        let content = std::fs::read_to_string(config_file)?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid config file {}", config_file.display()))
+    }
+
+    // command line > environment variables > config file
+    fn layered(cli: Overrides, env: Overrides, config: Overrides) -> Self {
+        cli.or(env).or(config)
+    }
+
+    // fills every unset field from other
+    fn or(self, other: Overrides) -> Self {
+        Overrides {
+            tf: self.tf.or(other.tf),
+            studiomdl: self.studiomdl.or(other.studiomdl),
+            sca: self.sca.or(other.sca),
+            runner: self.runner.or(other.runner),
+            jobs: self.jobs.or(other.jobs),
+            cache: self.cache.or(other.cache),
+        }
+    }
+}
+
+pub fn set_cli_overrides(overrides: Overrides) {
+    *CLI_OVERRIDES.lock().unwrap() = Some(overrides);
+}
+
+// for tests that need configured folders, they take turns since the overrides are global
+#[cfg(test)]
+pub fn test_overrides(overrides: Overrides) -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    set_cli_overrides(overrides);
+    guard
+}
+
+// command line > environment variables > config file
+pub fn overrides() -> Result<Overrides> {
+    let cli = CLI_OVERRIDES.lock().unwrap().clone().unwrap_or_default();
+    Ok(Overrides::layered(
+        cli,
+        Overrides::from_env()?,
+        Overrides::from_config()?,
+    ))
+}
+
+fn parse_jobs(value: &str) -> Result<usize> {
+    match value.trim().parse::<usize>() {
+        Ok(jobs) if jobs > 0 => Ok(jobs),
+        _ => bail!("The job count has to be a number above 0, got {}", value),
+    }
+}
+
+// the configured job count, or one studiomdl per cpu
+pub fn jobs() -> Result<usize> {
+    match overrides()?.jobs {
+        Some(0) => bail!("The job count has to be above 0"),
+        Some(jobs) => return Ok(jobs),
+        None => {}
+    }
+    Ok(std::thread::available_parallelism()
+        .map(|jobs| jobs.get())
+        .unwrap_or(1))
+}
+
+pub fn tf_folder() -> Result<PathBuf> {
+    if let Some(tf) = overrides()?.tf {
+        if !tf.is_dir() {
+            bail!("Configured tf folder {} doesn't exist", tf.display());
+        }
+        return Ok(tf);
+    }
+    if let Some(tf) = legacy_tf_folder() {
+        return Ok(tf);
+    }
+    match find_game_folder() {
+        Some(game) if game.join("tf").is_dir() => Ok(game.join("tf")),
+        _ => bail!(
+            "Can't find the tf folder, set it with --tf, {} or tf in {}",
+            TF_ENV,
+            config_file()?.display()
+        ),
+    }
+}
+
+pub fn studiomdl_exe() -> Result<PathBuf> {
+    if let Some(studiomdl) = overrides()?.studiomdl {
+        if !studiomdl.is_file() {
+            bail!("Configured studiomdl {} doesn't exist", studiomdl.display());
+        }
+        return Ok(studiomdl);
+    }
+    let tf = tf_folder()?;
+    game_tool(&tf, "studiomdl.exe").ok_or_else(|| {
+        anyhow!(
+            "Can't find studiomdl.exe next to {}, set it with --studiomdl or {}",
+            tf.display(),
+            STUDIOMDL_ENV
+        )
+    })
+}
+
+pub fn sca_folder() -> Result<PathBuf> {
+    if let Some(sca) = overrides()?.sca {
+        if !sca.is_dir() {
+            bail!("Configured SCA folder {} doesn't exist", sca.display());
+        }
+        return Ok(sca);
+    }
+    let mut candidates = vec![super::Sca::exe_folder()?.join("SCA")];
+    if let Ok(current_dir) = std::env::current_dir() {
+        candidates.push(current_dir.join("SCA"));
+    }
+    // the release zip gets extracted into custom, so look through every addon folder
+    if let Ok(tf) = tf_folder() {
+        if let Ok(entries) = std::fs::read_dir(tf.join("custom")) {
+            let mut addons = entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path().join("SCA"))
+                .collect::<Vec<PathBuf>>();
+            addons.sort();
+            candidates.extend(addons);
+        }
+    }
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.is_dir())
+        .ok_or_else(|| anyhow!("Can't find SCA folder, set it with --sca or {}", SCA_ENV))
+}
+
+pub fn runner() -> Result<Runner> {
+    match overrides()?.runner {
+        Some(runner) => runner.parse(),
+        None => Ok(Runner::default()),
+    }
+}
+
+// %APPDATA%\v_customizer on windows, $XDG_CONFIG_HOME/v_customizer elsewhere
+pub fn config_dir() -> Result<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|home| home.join(".config")))
+    };
+    base.map(|base| base.join(APP_FOLDER_NAME))
+        .ok_or_else(|| anyhow!("Can't find a config folder"))
+}
+
+// the configured compile cache folder or the default one, None if it's turned off
+pub fn cache_folder() -> Result<Option<PathBuf>> {
+    match overrides()?.cache {
+        Some(cache) if cache.as_os_str() == CACHE_OFF => Ok(None),
+        Some(cache) => Ok(Some(cache)),
+        None => cache_dir().map(Some),
+    }
+}
+
+// %LOCALAPPDATA%\v_customizer\cache on windows, $XDG_CACHE_HOME/v_customizer elsewhere
+pub fn cache_dir() -> Result<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA")
+            .map(PathBuf::from)
+            .map(|base| base.join(APP_FOLDER_NAME).join("cache"))
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|home| home.join(".cache")))
+            .map(|base| base.join(APP_FOLDER_NAME))
+    };
+    base.ok_or_else(|| anyhow!("Can't find a cache folder"))
+}
+
+// the wine prefix proton runs studiomdl.exe in, $XDG_DATA_HOME/v_customizer/proton,
+// not in the cache folder since clearing that would throw it away with the models
+pub fn proton_prefix() -> Result<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".local").join("share")))
+        .map(|base| base.join(APP_FOLDER_NAME).join("proton"))
+        .ok_or_else(|| anyhow!("Can't find a folder for the proton prefix"))
+}
+
+pub fn config_file() -> Result<PathBuf> {
+    match std::env::var_os(CONFIG_ENV).filter(|v| !v.is_empty()) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(config_dir()?.join("config.toml")),
+    }
+}
+
+// every steam install that exists on this machine
+pub fn steam_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if cfg!(windows) {
+        roots.extend(registry_steam_path());
+        for var in ["ProgramFiles(x86)", "ProgramFiles"] {
+            if let Some(dir) = std::env::var_os(var) {
+                roots.push(PathBuf::from(dir).join("Steam"));
+            }
+        }
+    } else {
+        if let Some(data) = std::env::var_os("XDG_DATA_HOME").filter(|v| !v.is_empty()) {
+            roots.push(PathBuf::from(data).join("Steam"));
+        }
+        if let Some(home) = home_dir() {
+            for relative in [
+                ".steam/steam",
+                ".steam/root",
+                ".local/share/Steam",
+                // flatpak
+                ".var/app/com.valvesoftware.Steam/.local/share/Steam",
+                ".var/app/com.valvesoftware.Steam/data/Steam",
+                // snap
+                "snap/steam/common/.local/share/Steam",
+                // macos
+                "Library/Application Support/Steam",
+            ] {
+                roots.push(home.join(relative));
+            }
+        }
+    }
+    let mut seen = Vec::new();
+    roots
+        .into_iter()
+        .filter(|root| root.join("steamapps").is_dir())
+        .filter(|root| {
+            // ~/.steam/steam is usually a symlink to one of the others
+            let canonical = root.canonicalize().unwrap_or_else(|_| root.clone());
+            if seen.contains(&canonical) {
+                false
+            } else {
+                seen.push(canonical);
+                true
+            }
+        })
+        .collect()
+}
+
+// the library folders of a steam install, the install itself included
+pub fn library_folders<P: AsRef<Path>>(steam_root: P) -> Result<Vec<PathBuf>> {
+    let steam_root = steam_root.as_ref();
+    let mut libraries = vec![steam_root.to_path_buf()];
+    let vdf_file = vec![
+        steam_root.join("steamapps").join("libraryfolders.vdf"),
+        steam_root.join("config").join("libraryfolders.vdf"),
+    ]
+    .into_iter()
+    .find(|file| file.is_file());
+    let vdf_file = match vdf_file {
+        Some(file) => file,
+        None => return Ok(libraries),
+    };
+    let doc = vdf::parse(std::fs::read_to_string(&vdf_file)?)
+        .with_context(|| format!("Can't parse {}", vdf_file.display()))?;
+    let folders = doc
+        .get("libraryfolders")
+        .ok_or_else(|| anyhow!("{} has no libraryfolders", vdf_file.display()))?;
+    for (key, value) in folders.entries() {
+        // other keys are things like contentstatsid
+        if key.parse::<u32>().is_err() {
+            continue;
+        }
+        // the old format maps the index straight to the path
+        let path = match value.as_str() {
+            Some(path) => path,
+            None => match value.get("path").and_then(|path| path.as_str()) {
+                Some(path) => path,
+                None => continue,
+            },
+        };
+        let path = PathBuf::from(path);
+        if !libraries.contains(&path) {
+            libraries.push(path);
+        }
+    }
+    Ok(libraries)
+}
+
+// reads appmanifest_<id>.acf of a library and returns the game folder if it's installed there
+pub fn app_install_folder<P: AsRef<Path>>(library: P, app_id: u32) -> Result<Option<PathBuf>> {
+    let steamapps = library.as_ref().join("steamapps");
+    let manifest = steamapps.join(format!("appmanifest_{}.acf", app_id));
+    if !manifest.is_file() {
+        return Ok(None);
+    }
+    let doc = vdf::parse(std::fs::read_to_string(&manifest)?)
+        .with_context(|| format!("Can't parse {}", manifest.display()))?;
+    let install_dir = doc
+        .get("AppState")
+        .and_then(|state| state.get("installdir"))
+        .and_then(|dir| dir.as_str())
+        .ok_or_else(|| anyhow!("{} has no installdir", manifest.display()))?;
+    let folder = steamapps.join("common").join(install_dir);
+    Ok(if folder.is_dir() { Some(folder) } else { None })
+}
+
+// the Team Fortress 2 folder from the first steam library that has it installed
+pub fn find_game_folder() -> Option<PathBuf> {
+    steam_roots()
+        .iter()
+        .filter_map(|root| library_folders(root).ok())
+        .flatten()
+        .find_map(|library| app_install_folder(library, TF2_APP_ID).ok().flatten())
+}
+
+// older releases had to be extracted to tf/custom/<folder>, keep supporting that
+fn legacy_tf_folder() -> Option<PathBuf> {
+    let mut tf_folder = super::Sca::exe_folder().ok()?;
+    tf_folder.pop();
+    tf_folder.pop();
+    if tf_folder.join("gameinfo.txt").is_file() {
+        Some(tf_folder)
+    } else {
+        None
+    }
+}
+
+// looks for a tool in the game's bin folder, newer builds also ship a bin/x64 one
+fn game_tool(tf: &Path, name: &str) -> Option<PathBuf> {
+    let bin = tf.parent()?.join("bin");
+    vec![bin.join(name), bin.join("x64").join(name)]
+        .into_iter()
+        .find(|tool| tool.is_file())
+}
+
+fn registry_steam_path() -> Option<PathBuf> {
+    let output = Tool::new("reg", Runner::Native)
+        .command()
+        .ok()?
+        .args(["query", r"HKCU\Software\Valve\Steam", "/v", "SteamPath"])
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout.lines().find(|line| line.contains("SteamPath"))?;
+    let path = line.split("REG_SZ").nth(1)?.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::test_folder;
+
+    fn args(args: &[&str]) -> Result<(Overrides, Vec<String>)> {
+        Overrides::from_args(args.iter().map(|arg| (*arg).to_owned()))
+    }
+
+    #[test]
+    fn takes_options_out_of_args() {
+        let (overrides, rest) = args(&[
+            "--tf",
+            "/games/tf",
+            "set",
+            "--sca=/sca",
+            "scout",
+            "-j",
+            "3",
+            "--runner=wine",
+            "x=1",
+            "--cache",
+            "off",
+        ])
+        .unwrap();
+        assert_eq!(rest, ["set", "scout", "x=1"]);
+        assert_eq!(overrides.tf, Some(PathBuf::from("/games/tf")));
+        assert_eq!(overrides.sca, Some(PathBuf::from("/sca")));
+        assert_eq!(overrides.jobs, Some(3));
+        assert_eq!(overrides.runner.as_deref(), Some("wine"));
+        assert_eq!(overrides.cache, Some(PathBuf::from(CACHE_OFF)));
+        assert!(overrides.studiomdl.is_none());
+        assert!(args(&["generate", "--studiomdl"]).is_err());
+        assert!(args(&["--jobs", "0"]).is_err());
+        assert!(args(&["--jobs=many"]).is_err());
+    }
+
+    #[test]
+    fn command_line_then_environment_then_config() {
+        let folder = test_folder("discovery_config");
+        let config_file = folder.join("config.toml");
+        assert!(Overrides::read(&config_file).unwrap().tf.is_none());
+        std::fs::write(
+            &config_file,
+            "tf = \"/config/tf\"\nsca = \"/config/sca\"\nstudiomdl = \"/config/studiomdl.exe\"\njobs = 2\n",
+        )
+        .unwrap();
+        let config = Overrides::read(&config_file).unwrap();
+        let env = Overrides {
+            sca: Some(PathBuf::from("/env/sca")),
+            studiomdl: Some(PathBuf::from("/env/studiomdl.exe")),
+            ..Overrides::default()
+        };
+        let cli = Overrides {
+            studiomdl: Some(PathBuf::from("/cli/studiomdl.exe")),
+            ..Overrides::default()
+        };
+        let overrides = Overrides::layered(cli, env, config);
+        assert_eq!(overrides.tf, Some(PathBuf::from("/config/tf")));
+        assert_eq!(overrides.sca, Some(PathBuf::from("/env/sca")));
+        assert_eq!(
+            overrides.studiomdl,
+            Some(PathBuf::from("/cli/studiomdl.exe"))
+        );
+        assert_eq!(overrides.jobs, Some(2));
+        assert!(overrides.runner.is_none() && overrides.cache.is_none());
+
+        std::fs::write(&config_file, "tf = 5").unwrap();
+        assert!(Overrides::read(&config_file).is_err());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    // a steam install with libraryfolders.vdf in steamapps
+    fn steam(name: &str, libraryfolders: &str) -> PathBuf {
+        let steam = test_folder(name);
+        std::fs::create_dir_all(steam.join("steamapps")).unwrap();
+        std::fs::write(
+            steam.join("steamapps").join("libraryfolders.vdf"),
+            libraryfolders,
+        )
+        .unwrap();
+        steam
+    }
+
+    #[test]
+    fn old_library_folders() {
+        let steam = steam(
+            "discovery_old_libraries",
+            r#""LibraryFolders"
+            {
+                "TimeNextStatsReport"   "1600000000"
+                "ContentStatsID"        "-1234"
+                "1"     "D:\\SteamLibrary"
+                "2"     "/mnt/games/steam"
+            }"#,
+        );
+        assert_eq!(
+            library_folders(&steam).unwrap(),
+            [
+                steam.clone(),
+                PathBuf::from(r"D:\SteamLibrary"),
+                PathBuf::from("/mnt/games/steam")
+            ]
+        );
+        std::fs::remove_dir_all(steam).unwrap();
+    }
+
+    #[test]
+    fn new_library_folders() {
+        let steam = steam("discovery_new_libraries", "");
+        let library = steam.join("library");
+        let libraryfolders = format!(
+            r#""libraryfolders"
+            {{
+                "contentstatsid"    "-1234"
+                "0"
+                {{
+                    "path"      "{}"
+                    "label"     ""
+                    "apps" {{ "228980" "1" }}
+                }}
+                "1"
+                {{
+                    "path"      "{}"
+                    "apps" {{ "440" "1" }}
+                }}
+                "2" {{ "label" "no path" }}
+            }}"#,
+            steam.display().to_string().replace('\\', "\\\\"),
+            library.display().to_string().replace('\\', "\\\\"),
+        );
+        std::fs::write(
+            steam.join("steamapps").join("libraryfolders.vdf"),
+            libraryfolders,
+        )
+        .unwrap();
+        // the install itself is only in there once
+        assert_eq!(
+            library_folders(&steam).unwrap(),
+            [steam.clone(), library.clone()]
+        );
+
+        assert_eq!(app_install_folder(&library, TF2_APP_ID).unwrap(), None);
+        let steamapps = library.join("steamapps");
+        std::fs::create_dir_all(steamapps.join("common").join("Team Fortress 2")).unwrap();
+        std::fs::write(
+            steamapps.join("appmanifest_440.acf"),
+            r#""AppState" { "appid" "440" "installdir" "Team Fortress 2" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            app_install_folder(&library, TF2_APP_ID).unwrap(),
+            Some(steamapps.join("common").join("Team Fortress 2"))
+        );
+        std::fs::remove_dir_all(steam).unwrap();
+    }
+
+    #[test]
+    fn no_or_broken_library_folders() {
+        let steam = steam("discovery_broken_libraries", r#""libraryfolders" {"#);
+        assert!(library_folders(&steam).is_err());
+        std::fs::write(
+            steam.join("steamapps").join("libraryfolders.vdf"),
+            r#""other" {}"#,
+        )
+        .unwrap();
+        assert!(library_folders(&steam).is_err());
+        std::fs::remove_file(steam.join("steamapps").join("libraryfolders.vdf")).unwrap();
+        assert_eq!(library_folders(&steam).unwrap(), vec![steam.clone()]);
+        std::fs::remove_dir_all(steam).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+use anyhow::{bail, Result};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::workspace::OUTPUT_FOLDER_NAME;
+use crate::qc::{Directive, Qc};
+
+// one qc of the build, with what it compiles and what it needs
+pub struct Node {
+    pub qc: PathBuf,
+    // the $modelname, relative to models/
+    pub model: Option<String>,
+    // every $includemodel, from the qc and the files it $includes
+    pub included_models: Vec<String>,
+    // every $include file, resolved
+    pub included_files: Vec<PathBuf>,
+    // qcs of this build that have to be compiled first, as indices into the graph
+    pub dependencies: Vec<usize>,
+}
+
+// the qcs of one build, ordered so every qc compiles after the models it $includemodels
+pub struct Graph {
+    nodes: Vec<Node>,
+    order: Vec<usize>,
+}
+
+impl Graph {
+    // reads every qc and resolves the references between them,
+    // missing files and cycles are errors, all of them get listed at once
+    pub fn new(qcs: &[PathBuf]) -> Result<Self> {
+        let mut problems = Vec::new();
+        let mut nodes = qcs
+            .iter()
+            .map(|qc| {
+                let mut node = Node {
+                    qc: qc.clone(),
+                    model: None,
+                    included_models: Vec::new(),
+                    included_files: Vec::new(),
+                    dependencies: Vec::new(),
+                };
+                scan(qc, &mut vec![qc.clone()], &mut node, &mut problems);
+                node
+            })
+            .collect::<Vec<Node>>();
+
+        let mut producers = HashMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            if let Some(model) = &node.model {
+                if let Some(other) = producers.insert(model.clone(), index) {
+                    problems.push(format!(
+                        "{} and {} both compile {}",
+                        nodes[other].qc.display(),
+                        node.qc.display(),
+                        model
+                    ));
+                }
+            }
+        }
+        // models outside of the output folder come from the game, only ours have to be built here
+        let own_models = format!("{}/", OUTPUT_FOLDER_NAME.to_lowercase());
+        for (index, node) in nodes.iter_mut().enumerate() {
+            for model in &node.included_models {
+                match producers.get(model) {
+                    Some(&producer) if producer == index => problems.push(format!(
+                        "{} includes its own model {}",
+                        node.qc.display(),
+                        model
+                    )),
+                    Some(&producer) if !node.dependencies.contains(&producer) => {
+                        node.dependencies.push(producer)
+                    }
+                    Some(_) => {}
+                    None if model.starts_with(&own_models) => problems.push(format!(
+                        "{} includes {}, but no qc compiles it",
+                        node.qc.display(),
+                        model
+                    )),
+                    None => {}
+                }
+            }
+        }
+
+        let mut graph = Graph {
+            nodes,
+            order: Vec::new(),
+        };
+        match graph.sort() {
+            Ok(order) => graph.order = order,
+            Err(cycle) => problems.push(format!(
+                "The qcs include each other's models: {}",
+                cycle
+                    .iter()
+                    .map(|&index| graph.nodes[index].qc.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            )),
+        }
+        if !problems.is_empty() {
+            bail!("Can't build the qcs:\n{}", problems.join("\n"));
+        }
+        Ok(graph)
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // node indices, dependencies always come first,
+    // otherwise the qcs keep the order they were given in
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    // the qcs that have to wait for index
+    pub fn dependents(&self, index: usize) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&other| self.nodes[other].dependencies.contains(&index))
+            .collect()
+    }
+
+    // kahn's algorithm, always taking the first ready qc so the order is stable,
+    // on a cycle the qcs that form it are returned instead
+    fn sort(&self) -> std::result::Result<Vec<usize>, Vec<usize>> {
+        let mut waiting_on = self
+            .nodes
+            .iter()
+            .map(|node| node.dependencies.len())
+            .collect::<Vec<usize>>();
+        let mut done = vec![false; self.nodes.len()];
+        let mut order = Vec::new();
+        while let Some(index) = (0..self.nodes.len()).find(|&i| !done[i] && waiting_on[i] == 0) {
+            done[index] = true;
+            order.push(index);
+            for dependent in self.dependents(index) {
+                waiting_on[dependent] -= 1;
+            }
+        }
+        match (0..self.nodes.len()).find(|&i| !done[i]) {
+            None => Ok(order),
+            Some(start) => Err(self.find_cycle(start, &done)),
+        }
+    }
+
+    // every qc that isn't done waits on another one that isn't done, so following those
+    // has to run into a qc that was already visited
+    fn find_cycle(&self, start: usize, done: &[bool]) -> Vec<usize> {
+        let mut path = vec![start];
+        loop {
+            let current = *path.last().unwrap();
+            let next = self.nodes[current]
+                .dependencies
+                .iter()
+                .copied()
+                .find(|&dependency| !done[dependency])
+                .unwrap();
+            if let Some(position) = path.iter().position(|&index| index == next) {
+                let mut cycle = path[position..].to_vec();
+                cycle.push(next);
+                return cycle;
+            }
+            path.push(next);
+        }
+    }
+}
+
+// collects the directives of file and everything it $includes into node
+fn scan(file: &Path, stack: &mut Vec<PathBuf>, node: &mut Node, problems: &mut Vec<String>) {
+    let qc = match Qc::read(file) {
+        Ok(qc) => qc,
+        Err(error) => {
+            problems.push(format!("{:#}", error));
+            return;
+        }
+    };
+    for command in &qc.commands {
+        match command.directive() {
+            Ok(Directive::ModelName(model)) => node.model = Some(model_path(model)),
+            Ok(Directive::IncludeModel(model)) => node.included_models.push(model_path(model)),
+            Ok(Directive::Include(included)) => {
+                let included = file.parent().unwrap_or(Path::new("")).join(included);
+                if !included.is_file() {
+                    problems.push(format!(
+                        "{} includes {}, which doesn't exist",
+                        file.display(),
+                        included.display()
+                    ));
+                } else if stack.contains(&included) {
+                    problems.push(format!(
+                        "{} includes itself through {}",
+                        included.display(),
+                        file.display()
+                    ));
+                } else {
+                    node.included_files.push(included.clone());
+                    stack.push(included.clone());
+                    scan(&included, stack, node, problems);
+                    stack.pop();
+                }
+            }
+            Ok(_) => {}
+            Err(error) => problems.push(format!("{}: {:#}", file.display(), error)),
+        }
+    }
+}
+
+// model paths are relative to models/ and the engine doesn't care about casing or slashes
+fn model_path(path: &str) -> String {
+    path.replace('\\', "/")
+        .trim_start_matches('/')
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::test_folder;
+
+    const OWN: &str = "__TEMP/0_ViewmodelCustomized/models/weapons";
+
+    fn qc(folder: &Path, name: &str, text: &str) -> PathBuf {
+        let path = folder.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    // a qc that compiles model and includes the models in includes
+    fn model(folder: &Path, name: &str, model: &str, includes: &[&str]) -> PathBuf {
+        let mut text = format!("$modelname \"{}/{}.mdl\"\n", OWN, model);
+        for included in includes {
+            text += &format!("$includemodel \"{}/{}.mdl\"\n", OWN, included);
+        }
+        qc(folder, name, &text)
+    }
+
+    fn error(qcs: &[PathBuf]) -> String {
+        match Graph::new(qcs) {
+            Ok(_) => panic!("the graph should be broken"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    fn error_contains(qcs: &[PathBuf], text: &str) -> bool {
+        error(qcs).contains(text)
+    }
+
+    #[test]
+    fn dependencies_first() {
+        let folder = test_folder("graph_order");
+        let qcs = vec![
+            model(&folder, "arms.qc", "arms", &["hands", "pistol"]),
+            model(&folder, "pistol.qc", "pistol", &["hands"]),
+            model(&folder, "bat.qc", "bat", &[]),
+            model(&folder, "hands.qc", "hands", &[]),
+        ];
+        let graph = Graph::new(&qcs).unwrap();
+        assert_eq!(graph.len(), 4);
+        assert_eq!(graph.order(), &[2, 3, 1, 0]);
+        assert_eq!(graph.nodes()[0].dependencies, vec![3, 1]);
+        assert_eq!(graph.dependents(3), vec![0, 1]);
+        assert_eq!(
+            graph.nodes()[1].model.as_deref(),
+            Some("__temp/0_viewmodelcustomized/models/weapons/pistol.mdl")
+        );
+        // without dependencies the given order stays
+        let graph = Graph::new(&qcs[2..]).unwrap();
+        assert_eq!(graph.order(), &[0, 1]);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn follows_includes() {
+        let folder = test_folder("graph_includes");
+        qc(
+            &folder,
+            "shared/hands.qci",
+            &format!("$includemodel \"{}\\HANDS.mdl\"\n", OWN),
+        );
+        let qcs = vec![
+            qc(
+                &folder,
+                "pistol/pistol.qc",
+                &format!(
+                    "$modelname \"{}/pistol.mdl\"\n$include \"../shared/hands.qci\"\n\
+                     $includemodel \"weapons/c_models/c_scout_animations.mdl\"\n",
+                    OWN
+                ),
+            ),
+            model(&folder, "hands.qc", "hands", &[]),
+        ];
+        let graph = Graph::new(&qcs).unwrap();
+        let pistol = &graph.nodes()[0];
+        assert_eq!(
+            pistol.included_files,
+            vec![folder.join("pistol").join("../shared/hands.qci")]
+        );
+        // the game's own models don't need a qc of the build
+        assert_eq!(pistol.included_models.len(), 2);
+        assert_eq!(pistol.dependencies, vec![1]);
+        assert_eq!(graph.order(), &[1, 0]);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let folder = test_folder("graph_cycle");
+        let qcs = vec![
+            model(&folder, "bat.qc", "bat", &[]),
+            model(&folder, "a.qc", "a", &["b"]),
+            model(&folder, "b.qc", "b", &["c"]),
+            model(&folder, "c.qc", "c", &["a"]),
+        ];
+        let error = error(&qcs);
+        let names = ["a", "b", "c", "a"]
+            .iter()
+            .map(|name| folder.join(format!("{}.qc", name)).display().to_string())
+            .collect::<Vec<String>>();
+        assert!(
+            error.contains(&format!(
+                "The qcs include each other's models: {}",
+                names.join(" -> ")
+            )),
+            "{}",
+            error
+        );
+        let own = vec![model(&folder, "own.qc", "own", &["own"])];
+        assert!(error_contains(&own, "includes its own model"));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn reports_missing_files_and_models() {
+        let folder = test_folder("graph_missing");
+        let qcs = vec![
+            qc(&folder, "a.qc", "$include \"gone.qci\"\n"),
+            model(&folder, "b.qc", "b", &["nobody"]),
+            qc(&folder, "loop.qci", "$include \"loop.qci\"\n"),
+            folder.join("c.qc"),
+        ];
+        let error = error(&qcs);
+        let lines = error.lines().collect::<Vec<&str>>();
+        // every problem at once
+        assert_eq!(lines.len(), 5, "{}", error);
+        assert_eq!(lines[0], "Can't build the qcs:");
+        // files the qcs read come first, then the models they need
+        assert!(lines[1].ends_with("gone.qci, which doesn't exist"));
+        assert!(lines[2].contains("loop.qci includes itself through"));
+        assert!(lines[3].contains("c.qc"));
+        assert!(lines[4].ends_with(&format!(
+            "includes {}/nobody.mdl, but no qc compiles it",
+            OWN.to_lowercase()
+        )));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn reports_models_compiled_twice() {
+        let folder = test_folder("graph_twice");
+        let qcs = vec![
+            model(&folder, "a.qc", "pistol", &[]),
+            qc(
+                &folder,
+                "b.qc",
+                &format!("$modelname \"{}\\Pistol.mdl\"\n", OWN),
+            ),
+        ];
+        assert!(error_contains(
+            &qcs,
+            &format!(
+                "{} and {} both compile {}/pistol.mdl",
+                qcs[0].display(),
+                qcs[1].display(),
+                OWN.to_lowercase()
+            )
+        ));
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
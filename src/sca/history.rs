@@ -0,0 +1,357 @@
+use std::collections::VecDeque;
+
+use super::layers::{Field, Layer, Level};
+use super::Sca;
+
+// how many steps undo can go back, the oldest ones get forgotten
+pub const HISTORY_LIMIT: usize = 100;
+
+// one layer going from before to after
+#[derive(Clone)]
+struct Change {
+    level: Level,
+    before: Layer,
+    after: Layer,
+}
+
+// what a single undo takes back
+#[derive(Clone)]
+struct Step {
+    changes: Vec<Change>,
+    // a slider drag that's still going, the next values of the same field end up in here
+    dragging: Option<Field>,
+}
+
+// the edits made to the layers of a Sca, as the layers before and after every one of them
+#[derive(Clone, Default)]
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+}
+
+impl History {
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    fn push(&mut self, step: Step) {
+        self.end_drag();
+        self.redo.clear();
+        self.undo.push_back(step);
+        if self.undo.len() > HISTORY_LIMIT {
+            self.undo.pop_front();
+        }
+    }
+
+    // the drag that was going on becomes a step like any other, or goes if it ended where it began
+    fn end_drag(&mut self) {
+        if let Some(step) = self.undo.back_mut().filter(|step| step.dragging.is_some()) {
+            step.dragging = None;
+            if step
+                .changes
+                .iter()
+                .all(|change| change.before == change.after)
+            {
+                self.undo.pop_back();
+            }
+        }
+    }
+}
+
+impl Sca {
+    // None takes field out of the layer at level so it inherits again
+    pub fn set_field(&mut self, level: &Level, field: Field, value: Option<f32>) {
+        if let Some(mut layer) = self.layer(level).copied() {
+            layer.set(field, value);
+            self.set_layer(level, layer);
+        }
+    }
+
+    // like set_field, but everything until end_drag undoes as one step
+    pub fn drag_field(&mut self, level: &Level, field: Field, value: f32) {
+        let before = match self.layer(level).copied() {
+            Some(layer) => layer,
+            None => return,
+        };
+        let mut layer = before;
+        layer.set(field, Some(value));
+        let dragging = self.history.undo.back_mut().filter(|step| {
+            step.dragging == Some(field)
+                && step.changes.len() == 1
+                && step.changes[0].level == *level
+        });
+        match dragging {
+            Some(step) => step.changes[0].after = layer,
+            None => self.history.push(Step {
+                changes: vec![Change {
+                    level: level.clone(),
+                    before,
+                    after: layer,
+                }],
+                dragging: Some(field),
+            }),
+        }
+        self.put_layer(level, layer);
+        self.resolve();
+    }
+
+    pub fn end_drag(&mut self) {
+        self.history.end_drag();
+    }
+
+    pub fn set_layer(&mut self, level: &Level, layer: Layer) {
+        self.record(vec![(level.clone(), layer)]);
+    }
+
+    pub fn clear_layer(&mut self, level: &Level) {
+        self.set_layer(level, Layer::default());
+    }
+
+    // empties every layer in a single step
+    pub fn clear_all_layers(&mut self) {
+        let mut levels = vec![Level::Global];
+        for folder in &self.folders {
+            levels.push(Level::Class(folder.class));
+            for animation in &folder.animations {
+                levels.push(Level::Weapon(folder.class, animation.name.clone()));
+            }
+        }
+        self.record(
+            levels
+                .into_iter()
+                .map(|level| (level, Layer::default()))
+                .collect(),
+        );
+    }
+
+    // takes back the last step, false if there's none
+    pub fn undo(&mut self) -> bool {
+        self.history.end_drag();
+        let step = match self.history.undo.pop_back() {
+            Some(step) => step,
+            None => return false,
+        };
+        for change in step.changes.iter().rev() {
+            self.put_layer(&change.level, change.before);
+        }
+        self.history.redo.push(step);
+        self.resolve();
+        true
+    }
+
+    // does the last undone step again, false if there's none
+    pub fn redo(&mut self) -> bool {
+        self.history.end_drag();
+        let step = match self.history.redo.pop() {
+            Some(step) => step,
+            None => return false,
+        };
+        for change in &step.changes {
+            self.put_layer(&change.level, change.after);
+        }
+        self.history.undo.push_back(step);
+        self.resolve();
+        true
+    }
+
+    // sets the layers as one step, leaving out the ones that wouldn't change
+    fn record(&mut self, layers: Vec<(Level, Layer)>) {
+        let changes = layers
+            .into_iter()
+            .filter_map(|(level, after)| {
+                let before = *self.layer(&level)?;
+                (before != after).then_some(Change {
+                    level,
+                    before,
+                    after,
+                })
+            })
+            .collect::<Vec<Change>>();
+        if changes.is_empty() {
+            return;
+        }
+        for change in &changes {
+            self.put_layer(&change.level, change.after);
+        }
+        self.history.push(Step {
+            changes,
+            dragging: None,
+        });
+        self.resolve();
+    }
+
+    fn put_layer(&mut self, level: &Level, layer: Layer) {
+        if let Some(slot) = self.layer_mut(level) {
+            *slot = layer;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{test_sca, Class};
+    use super::*;
+
+    fn pistol() -> Level {
+        Level::Weapon(Class::Scout, "Pistol".to_owned())
+    }
+
+    // the x the pistol gets compiled with
+    fn x(sca: &Sca) -> f32 {
+        sca.folders[0].animations[0].origin.x
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut sca = test_sca();
+        assert!(!sca.history.can_undo() && !sca.undo());
+        sca.set_field(&pistol(), Field::X, Some(1.0));
+        sca.set_field(&Level::Class(Class::Scout), Field::X, Some(5.0));
+        assert_eq!(x(&sca), 1.0);
+        assert!(sca.undo());
+        assert_eq!(
+            sca.layer(&Level::Class(Class::Scout)),
+            Some(&Layer::default())
+        );
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 0.0);
+        assert!(!sca.undo());
+        assert!(sca.history.can_redo());
+        assert!(sca.redo());
+        assert_eq!(x(&sca), 1.0);
+        assert_eq!(sca.layer(&pistol()).unwrap().x, Some(1.0));
+        // a new edit forgets what was undone
+        sca.set_field(&pistol(), Field::Y, Some(2.0));
+        assert!(!sca.history.can_redo() && !sca.redo());
+        assert!(sca.undo() && sca.undo() && !sca.undo());
+        assert_eq!(sca.layer(&pistol()), Some(&Layer::default()));
+    }
+
+    #[test]
+    fn only_changes_are_steps() {
+        let mut sca = test_sca();
+        sca.set_field(&pistol(), Field::X, None);
+        sca.clear_all_layers();
+        sca.set_field(
+            &Level::Weapon(Class::Spy, "Knife".to_owned()),
+            Field::X,
+            Some(1.0),
+        );
+        assert!(!sca.history.can_undo());
+        sca.set_field(&pistol(), Field::X, Some(1.0));
+        sca.set_field(&pistol(), Field::X, Some(1.0));
+        assert!(sca.undo());
+        assert!(!sca.history.can_undo());
+    }
+
+    #[test]
+    fn clearing_everything_is_one_step() {
+        let mut sca = test_sca();
+        sca.set_field(&Level::Global, Field::Yaw, Some(10.0));
+        sca.set_field(&Level::Class(Class::Soldier), Field::Z, Some(-2.0));
+        sca.set_field(&pistol(), Field::X, Some(1.0));
+        sca.clear_all_layers();
+        assert_eq!(x(&sca), 0.0);
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 1.0);
+        assert_eq!(sca.global.yaw, Some(10.0));
+        assert_eq!(sca.folders[1].layer.z, Some(-2.0));
+        assert!(sca.undo() && sca.undo() && sca.undo() && !sca.undo());
+    }
+
+    #[test]
+    fn drags_merge() {
+        let mut sca = test_sca();
+        for value in [1.0, 2.0, 3.0] {
+            sca.drag_field(&pistol(), Field::X, value);
+        }
+        assert_eq!(x(&sca), 3.0);
+        sca.end_drag();
+        // the next drag of the same field is a step of its own
+        sca.drag_field(&pistol(), Field::X, 4.0);
+        // so is a drag of another field, which ends the one before
+        sca.drag_field(&pistol(), Field::Y, 1.0);
+        sca.drag_field(&pistol(), Field::Y, 2.0);
+        // and of the same field on another level
+        sca.drag_field(&Level::Global, Field::Y, 1.0);
+        sca.end_drag();
+        assert!(sca.undo());
+        assert_eq!(sca.global.y, None);
+        assert!(sca.undo());
+        assert_eq!(sca.layer(&pistol()).unwrap().y, None);
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 3.0);
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 0.0);
+        assert!(!sca.undo());
+        // redo brings the whole drag back
+        assert!(sca.redo());
+        assert_eq!(x(&sca), 3.0);
+    }
+
+    #[test]
+    fn drags_back_to_the_start_are_no_step() {
+        let mut sca = test_sca();
+        sca.set_field(&pistol(), Field::X, Some(1.0));
+        sca.drag_field(&pistol(), Field::X, 2.0);
+        sca.drag_field(&pistol(), Field::X, 1.0);
+        sca.end_drag();
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 0.0);
+        assert!(!sca.undo());
+    }
+
+    #[test]
+    fn undo_ends_a_drag() {
+        let mut sca = test_sca();
+        sca.drag_field(&pistol(), Field::X, 1.0);
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 0.0);
+        // a drag that goes on after it starts a new step
+        sca.drag_field(&pistol(), Field::X, 2.0);
+        assert!(!sca.history.can_redo());
+        assert!(sca.undo());
+        assert_eq!(x(&sca), 0.0);
+    }
+
+    #[test]
+    fn forgets_the_oldest_steps() {
+        let mut sca = test_sca();
+        for step in 1..=HISTORY_LIMIT + 20 {
+            sca.set_field(&pistol(), Field::X, Some(step as f32));
+        }
+        let mut undone = 0;
+        while sca.undo() {
+            undone += 1;
+        }
+        assert_eq!(undone, HISTORY_LIMIT);
+        // the 20 oldest are gone
+        assert_eq!(x(&sca), 20.0);
+        let mut redone = 0;
+        while sca.redo() {
+            redone += 1;
+        }
+        assert_eq!(redone, HISTORY_LIMIT);
+        assert_eq!(x(&sca), (HISTORY_LIMIT + 20) as f32);
+    }
+
+    #[test]
+    fn drags_count_against_the_limit_once() {
+        let mut sca = test_sca();
+        for step in 0..HISTORY_LIMIT * 2 {
+            sca.drag_field(&pistol(), Field::X, step as f32 + 1.0);
+        }
+        sca.end_drag();
+        assert!(sca.undo());
+        assert!(!sca.undo());
+    }
+}
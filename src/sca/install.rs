@@ -0,0 +1,454 @@
+use anyhow::{anyhow, bail, Result};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use super::manifest::{sca_hash, Manifest, MANIFEST_PATH};
+use super::recover::Pack;
+use super::workspace::{ADDON_NAME, OUTPUT_FOLDER_NAME};
+use super::{Class, Origin, Sca};
+use crate::fs;
+use crate::qc::Qc;
+use crate::vpk;
+
+// what happens to the vpk that's already installed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Install {
+    // build it again from the selected classes, weapons of other classes are gone
+    Replace,
+    // only compile the classes whose weapons changed since it was built and keep everything else
+    Patch,
+}
+
+// the form the weapons get installed in, installing one removes the other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    // custom/0_ViewmodelCustomized.vpk
+    Vpk,
+    // custom/0_ViewmodelCustomized/models/..., quicker to build and to look into
+    Loose,
+}
+
+// whatever is installed in custom, the loose folder if there are both
+pub fn installed() -> Result<Option<PathBuf>> {
+    let loose = Sca::installed_loose()?;
+    if loose.is_dir() {
+        return Ok(Some(loose));
+    }
+    let vpk = Sca::installed_vpk()?;
+    Ok(Some(vpk).filter(|vpk| vpk.is_file()))
+}
+
+// how a generate changes the installed vpk
+pub struct Patch {
+    // None if nothing is installed yet, the patch is then a whole new addon
+    installed: Option<(Pack, Manifest)>,
+    // sca with only the classes that have to be compiled selected
+    build: Sca,
+    // models of weapons that were reset, as vpk paths without extension
+    dropped: HashSet<String>,
+}
+
+impl Patch {
+    // compares sca to the settings of the installed vpk or loose folder
+    pub fn plan(sca: &Sca) -> Result<Self> {
+        let path = match installed()? {
+            Some(path) => path,
+            None => {
+                return Ok(Patch {
+                    installed: None,
+                    build: sca.clone(),
+                    dropped: HashSet::new(),
+                })
+            }
+        };
+        let pack = Pack::open(&path)?;
+        let manifest = match pack.read(MANIFEST_PATH)? {
+            Some(json) => Manifest::from_json(&json)?,
+            None => bail!(
+                "{} has no settings in it, generate it once without only updating what changed",
+                path.display()
+            ),
+        };
+        let sca_dir = Sca::sca_folder()?;
+        let mut build = sca.clone();
+        let mut dropped = HashSet::new();
+        // a different $origin handling or different SCA files change every weapon
+        let everything = manifest.existing_origin != sca.existing_origin
+            || manifest.sca_hash != sca_hash(&sca_dir)?;
+        for class in build.folders.iter_mut() {
+            let changed = everything
+                || class.animations.iter().any(|animation| {
+                    animation.origin != previous_origin(&manifest, class.class, &animation.name)
+                });
+            let selected = class
+                .animations
+                .iter()
+                .any(|animation| animation.origin.is_modified());
+            if changed && !selected {
+                // every weapon of the class got reset, nothing of it stays in the vpk
+                let class_folder = sca_dir.join(class.class);
+                dropped.insert(model_stem(&class_folder)?);
+                for animation in &class.animations {
+                    dropped.insert(model_stem(&class_folder.join(&animation.name))?);
+                }
+            }
+            if !changed {
+                // what's installed for it is still right
+                class
+                    .animations
+                    .iter_mut()
+                    .for_each(|animation| animation.origin.reset());
+            }
+        }
+        Ok(Patch {
+            installed: Some((pack, manifest)),
+            build,
+            dropped,
+        })
+    }
+
+    // what to copy, transform and compile
+    pub fn build(&self) -> &Sca {
+        &self.build
+    }
+
+    // true if the run only takes weapons out of the vpk
+    pub fn only_removes(&self) -> bool {
+        self.build.get_selected_classes().is_empty() && !self.dropped.is_empty()
+    }
+
+    // the origin the installed vpk has for an animation, default if it has none
+    pub fn installed_origin(&self, class: Class, name: &str) -> Origin {
+        match &self.installed {
+            Some((_, manifest)) => previous_origin(manifest, class, name),
+            None => Origin::default(),
+        }
+    }
+
+    // hands every file of the installed addon that this run doesn't replace or drop to add,
+    // output_folder has the new models, returns how many were kept
+    pub fn carry_over<F: FnMut(&str, Vec<u8>) -> Result<()>>(
+        &self,
+        output_folder: &Path,
+        mut add: F,
+    ) -> Result<usize> {
+        let pack = match &self.installed {
+            Some((pack, _)) => pack,
+            None => return Ok(0),
+        };
+        // a model compiled again replaces all of its old files, even ones it doesn't have anymore
+        let mut replaced = HashSet::new();
+        if output_folder.is_dir() {
+            for path in fs::files(output_folder)? {
+                replaced.insert(stem(&fs::relative_path(&path, output_folder)));
+            }
+        }
+        let mut kept = 0;
+        for path in pack.paths()? {
+            let stem = stem(&path);
+            if vpk::normalize_path(&path) == MANIFEST_PATH
+                || replaced.contains(&stem)
+                || self.dropped.contains(&stem)
+            {
+                continue;
+            }
+            if let Some(data) = pack.read(&path)? {
+                add(&path, data)?;
+                kept += 1;
+            }
+        }
+        Ok(kept)
+    }
+}
+
+fn previous_origin(manifest: &Manifest, class: Class, name: &str) -> Origin {
+    manifest
+        .classes
+        .iter()
+        .filter(|previous| previous.class == class)
+        .flat_map(|previous| previous.animations.iter())
+        .find(|previous| previous.name == name)
+        .map(|previous| previous.origin)
+        .unwrap_or_default()
+}
+
+// where the model of the qc in folder ends up in the vpk, without extension
+fn model_stem(folder: &Path) -> Result<String> {
+    let qc = Qc::read(Sca::find_qc(folder)?)?;
+    let model = qc
+        .model_name()
+        .ok_or_else(|| anyhow!("{} has no $modelname", folder.display()))?;
+    Ok(stem(&pack_path(model)))
+}
+
+// where the model of a $modelname is in the installed addon, the SCA qcs write to
+// __TEMP/0_ViewmodelCustomized/models/... and that folder becomes the root of the addon
+pub fn pack_path(model: &str) -> String {
+    let model = model.replace('\\', "/");
+    let prefix = format!("{}/{}/", OUTPUT_FOLDER_NAME, ADDON_NAME);
+    match model.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(&prefix) => model[prefix.len()..].to_owned(),
+        _ => model,
+    }
+}
+
+// a vpk path up to the first dot of its file name, v_pistol.dx90.vtx and v_pistol.mdl share one
+fn stem(path: &str) -> String {
+    let path = vpk::normalize_path(path);
+    let name_start = path.rfind('/').map_or(0, |slash| slash + 1);
+    match path[name_start..].find('.') {
+        Some(dot) => path[..name_start + dot].to_owned(),
+        None => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::discovery::{self, Overrides};
+    use super::super::journal::Journal;
+    use super::super::manifest::Manifest;
+    use super::super::workspace::Workspace;
+    use super::super::{test_sca, ExistingOrigin};
+    use super::*;
+    use crate::fs::test_folder;
+    use crate::vpk::{Version, VpkWriter};
+
+    use std::sync::MutexGuard;
+
+    // an SCA folder for the classes and weapons of test_sca and an empty tf/custom, configured
+    // until the guard goes
+    fn setup(name: &str) -> (PathBuf, MutexGuard<'static, ()>) {
+        let root = test_folder(name);
+        let tf = root.join("tf");
+        std::fs::create_dir_all(tf.join("custom")).unwrap();
+        let sca = root.join("SCA");
+        for (folder, model) in [
+            ("Scout", "c_models/c_scout_arms"),
+            ("Scout/Pistol", "v_pistol"),
+            ("Scout/Bat", "v_bat"),
+            ("Soldier", "c_models/c_soldier_arms"),
+            ("Soldier/Shotgun", "v_shotgun"),
+        ] {
+            let qc = format!(
+                "$modelname \"{}/{}/models/weapons/{}.mdl\"\n",
+                OUTPUT_FOLDER_NAME, ADDON_NAME, model
+            );
+            write(&sca.join(folder).join("weapon.qc"), qc.as_bytes());
+        }
+        let guard = discovery::test_overrides(Overrides {
+            tf: Some(tf),
+            sca: Some(sca),
+            ..Overrides::default()
+        });
+        (root, guard)
+    }
+
+    fn write(path: &Path, data: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    // the pistol and the shotgun moved
+    fn installed_sca() -> Sca {
+        let mut sca = test_sca();
+        sca.folders[0].animations[0].origin.x = 1.0;
+        sca.folders[1].animations[0].origin.x = 2.0;
+        sca
+    }
+
+    // the vpk generate made out of sca, with a material that isn't from any weapon
+    fn install_vpk(sca: &Sca) {
+        let mut vpk = VpkWriter::new(Version::V2);
+        for path in &[
+            "models/weapons/c_models/c_scout_arms.mdl",
+            "models/weapons/v_pistol.mdl",
+            "models/weapons/v_pistol.dx90.vtx",
+            "models/weapons/c_models/c_soldier_arms.mdl",
+            "models/weapons/v_shotgun.mdl",
+            "materials/custom.vmt",
+        ] {
+            vpk.add_data(path, format!("old {}", path).into_bytes())
+                .unwrap();
+        }
+        vpk.add_data(
+            MANIFEST_PATH,
+            Manifest::new(sca).unwrap().to_json().unwrap(),
+        )
+        .unwrap();
+        vpk.write(Sca::tf_folder().unwrap().join("custom"), ADDON_NAME)
+            .unwrap();
+    }
+
+    fn kept(patch: &Patch, output_folder: &Path) -> Vec<String> {
+        let mut kept = Vec::new();
+        let count = patch
+            .carry_over(output_folder, |path, _| {
+                kept.push(path.to_owned());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, kept.len());
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn nothing_installed_is_a_new_addon() {
+        let (root, _guard) = setup("install_new");
+        let sca = installed_sca();
+        assert_eq!(installed().unwrap(), None);
+        let patch = Patch::plan(&sca).unwrap();
+        assert_eq!(patch.build().get_selected_classes().len(), 2);
+        assert!(!patch.only_removes());
+        assert_eq!(
+            patch.installed_origin(Class::Scout, "Pistol"),
+            Origin::default()
+        );
+        assert!(kept(&patch, &root.join("nothing")).is_empty());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn patches_only_what_changed() {
+        let (root, _guard) = setup("install_patch");
+        install_vpk(&installed_sca());
+        // the bat moves, the shotgun gets reset
+        let mut sca = installed_sca();
+        sca.folders[0].animations[1].origin.y = 3.0;
+        sca.folders[1].animations[0].origin.reset();
+        let patch = Patch::plan(&sca).unwrap();
+        let build = patch.build();
+        assert_eq!(build.get_selected_classes().len(), 1);
+        assert_eq!(build.folders[0].animations[0].origin.x, 1.0);
+        assert_eq!(build.folders[0].animations[1].origin.y, 3.0);
+        assert!(!patch.only_removes());
+        assert_eq!(patch.installed_origin(Class::Scout, "Pistol").x, 1.0);
+        assert_eq!(patch.installed_origin(Class::Soldier, "Shotgun").x, 2.0);
+
+        // the scout is compiled again, every old file of its models goes, even the ones the new
+        // compile doesn't have, and the soldier is gone
+        let output = root.join("output");
+        for model in &["c_models/c_scout_arms", "v_pistol", "v_bat"] {
+            write(
+                &output.join(format!("models/weapons/{}.mdl", model)),
+                b"new",
+            );
+        }
+        assert_eq!(kept(&patch, &output), ["materials/custom.vmt"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn same_settings_keep_everything() {
+        let (root, _guard) = setup("install_same");
+        install_vpk(&installed_sca());
+        let patch = Patch::plan(&installed_sca()).unwrap();
+        assert!(patch.build().get_selected_classes().is_empty());
+        assert!(!patch.only_removes());
+        let everything = kept(&patch, &root.join("output"));
+        assert_eq!(everything.len(), 6);
+        assert!(!everything.iter().any(|path| path == MANIFEST_PATH));
+
+        // resetting everything only removes
+        let mut reset = installed_sca();
+        reset.folders[0].animations[0].origin.reset();
+        reset.folders[1].animations[0].origin.reset();
+        let patch = Patch::plan(&reset).unwrap();
+        assert!(patch.only_removes());
+        assert_eq!(kept(&patch, &root.join("output")), ["materials/custom.vmt"]);
+
+        // another way of handling $origin changes every weapon
+        let mut other = installed_sca();
+        other.existing_origin = ExistingOrigin::Replace;
+        assert_eq!(
+            Patch::plan(&other)
+                .unwrap()
+                .build()
+                .get_selected_classes()
+                .len(),
+            2
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn needs_the_settings_of_the_installed_addon() {
+        let (root, _guard) = setup("install_no_manifest");
+        let mut vpk = VpkWriter::new(Version::V2);
+        vpk.add_data("models/weapons/v_pistol.mdl", b"old".to_vec())
+            .unwrap();
+        vpk.write(Sca::tf_folder().unwrap().join("custom"), ADDON_NAME)
+            .unwrap();
+        let error = Patch::plan(&installed_sca()).err().unwrap().to_string();
+        assert!(error.contains("has no settings"), "{}", error);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn install_file_replaces() {
+        let root = test_folder("install_file");
+        let journal = Journal::at(root.join("run.jsonl"));
+        let (source, destination) = (root.join("new.vpk"), root.join("custom/addon.vpk"));
+        let loose = root.join("custom/addon");
+        write(&source, b"new");
+        std::fs::create_dir_all(root.join("custom")).unwrap();
+        Sca::install_file(&journal, &source, &destination, Some(&loose)).unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), b"new");
+
+        // the one before and the loose folder both make way
+        write(&source, b"newer");
+        write(&loose.join("models/a.mdl"), b"loose");
+        Sca::install_file(&journal, &source, &destination, Some(&loose)).unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), b"newer");
+        assert!(!loose.exists());
+        assert_eq!(std::fs::read_dir(root.join("custom")).unwrap().count(), 1);
+        assert!(std::fs::read_to_string(journal.file())
+            .unwrap()
+            .contains("Installed"));
+
+        // and folders go in whole
+        let folder = root.join("package");
+        write(&folder.join("models/b.mdl"), b"b");
+        Sca::install_file(&journal, &folder, &loose, Some(&destination)).unwrap();
+        assert_eq!(std::fs::read(loose.join("models/b.mdl")).unwrap(), b"b");
+        assert!(!destination.exists());
+        assert_eq!(std::fs::read_dir(root.join("custom")).unwrap().count(), 1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn install_loose_carries_over() {
+        let (root, _guard) = setup("install_loose");
+        install_vpk(&installed_sca());
+        let mut sca = installed_sca();
+        sca.folders[0].animations[1].origin.y = 3.0;
+        let patch = Patch::plan(&sca).unwrap();
+        let workspace = Workspace::at(root.join("workspace"), Journal::at(root.join("run.jsonl")));
+        let output = workspace.output_folder();
+        write(&output.join("models/weapons/v_bat.mdl"), b"new bat");
+        sca.install_loose(&workspace, Some(&patch)).unwrap();
+
+        let vpk = Sca::installed_vpk().unwrap();
+        let loose = Sca::installed_loose().unwrap();
+        assert!(!vpk.exists());
+        assert_eq!(installed().unwrap(), Some(loose.clone()));
+        assert_eq!(
+            std::fs::read(loose.join("models/weapons/v_bat.mdl")).unwrap(),
+            b"new bat"
+        );
+        // what the compile didn't replace stays
+        assert_eq!(
+            std::fs::read(loose.join("models/weapons/v_pistol.mdl")).unwrap(),
+            b"old models/weapons/v_pistol.mdl"
+        );
+        assert_eq!(
+            std::fs::read(loose.join("models/weapons/v_shotgun.mdl")).unwrap(),
+            b"old models/weapons/v_shotgun.mdl"
+        );
+        assert!(loose.join("materials/custom.vmt").is_file());
+        let manifest = Manifest::from_json(&std::fs::read(loose.join(MANIFEST_PATH)).unwrap());
+        assert_eq!(manifest.unwrap().classes[0].animations[1].origin.y, 3.0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
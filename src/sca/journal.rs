@@ -0,0 +1,617 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{discovery, process, Sca};
+
+const JOURNAL_FOLDER_NAME: &str = "journal";
+// what older versions left behind when they didn't get to clean up
+const LEGACY_TEMP_FOLDER_NAME: &str = "__v_customizer_temp__";
+const LEGACY_TEMP_MODELS_NAME: &str = "__TEMP_MODELS";
+const LEGACY_OUTPUT_FOLDER_NAME: &str = "__TEMP";
+
+// one filesystem mutation, always written before the mutation happens
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Entry {
+    // first entry of every journal, process_start tells the process apart from a later one
+    // that got the same pid, journals of older versions don't have it
+    Started {
+        pid: u32,
+        timestamp: u64,
+        #[serde(default)]
+        process_start: Option<String>,
+    },
+    // a file or folder that didn't exist before the run
+    Created(PathBuf),
+    // a file or folder that gets moved, rolling back moves it back
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    // a file or folder that gets moved out of the way,
+    // it goes back on roll back and gets deleted on finish
+    BackedUp {
+        original: PathBuf,
+        backup: PathBuf,
+    },
+    // the finished vpk or loose folder waiting in the workspace to be installed
+    Packaged(PathBuf),
+    // the vpk or loose folder is in place, only cleanup is left
+    Installed(PathBuf),
+}
+
+// the journal of the run in progress, one json entry per line
+pub struct Journal {
+    file: PathBuf,
+}
+
+impl Journal {
+    pub fn begin() -> Result<Self> {
+        let folder = journal_folder()?;
+        std::fs::create_dir_all(&folder)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        let pid = std::process::id();
+        let journal = Journal {
+            file: folder.join(format!("run-{}-{}.jsonl", pid, timestamp)),
+        };
+        journal.record(&Entry::Started {
+            pid,
+            timestamp,
+            process_start: process::start(pid),
+        })?;
+        Ok(journal)
+    }
+
+    // a journal in file, without the Started entry
+    #[cfg(test)]
+    pub fn at(file: PathBuf) -> Self {
+        Journal { file }
+    }
+
+    // appends and syncs, so the entry survives a crash right after
+    pub fn record(&self, entry: &Entry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    // ends the run, an installed run only gets cleaned up and anything else is rolled back
+    pub fn end(self) -> Result<()> {
+        Run::load(self.file)?.roll_back()
+    }
+
+    // the run went through, nothing has to be recovered
+    pub fn finish(self) -> Result<()> {
+        if self.file.is_file() {
+            std::fs::remove_file(&self.file)?;
+        }
+        Ok(())
+    }
+}
+
+// a run that didn't finish, read back from its journal
+pub struct Run {
+    journal: Journal,
+    entries: Vec<Entry>,
+}
+
+impl Run {
+    fn load(file: PathBuf) -> Result<Self> {
+        let content = std::fs::read_to_string(&file)?;
+        let mut entries = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                // the last line can be cut off by the crash, its mutation never happened
+                Err(_) => break,
+            }
+        }
+        Ok(Run {
+            journal: Journal { file },
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.entries.iter().find_map(|entry| match entry {
+            Entry::Started { pid, .. } => Some(*pid),
+            _ => None,
+        })
+    }
+
+    // whether the process that started the run is still there, a live run must not be touched
+    pub fn is_running(&self) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            Entry::Started {
+                pid, process_start, ..
+            } => {
+                let current = process::start(*pid);
+                match process_start {
+                    Some(started) => current.as_ref() == Some(started),
+                    // without a start time any process with the pid counts, to be on the safe side
+                    None => current.is_some(),
+                }
+            }
+            _ => false,
+        })
+    }
+
+    // everything the run created or moved out of the way that is still on disk
+    pub fn leftovers(&self) -> Vec<PathBuf> {
+        let mut leftovers = Vec::new();
+        for entry in &self.entries {
+            let path = match entry {
+                Entry::Created(path) => path,
+                Entry::BackedUp { backup, .. } => backup,
+                _ => continue,
+            };
+            if path.exists() && !leftovers.contains(path) {
+                leftovers.push(path.clone());
+            }
+        }
+        leftovers
+    }
+
+    pub fn is_installed(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| matches!(entry, Entry::Installed(_)))
+    }
+
+    // the packaged vpk or loose folder, if it still exists
+    fn package(&self) -> Option<&Path> {
+        self.entries.iter().rev().find_map(|entry| match entry {
+            Entry::Packaged(path) if path.exists() => Some(path.as_path()),
+            _ => None,
+        })
+    }
+
+    // a run can be finished once its vpk got built
+    pub fn can_finish(&self) -> bool {
+        self.is_installed() || self.package().is_some()
+    }
+
+    // undoes every mutation in reverse order, an installed run has nothing left to undo but cleanup
+    pub fn roll_back(self) -> Result<()> {
+        if self.is_installed() {
+            return self.finish();
+        }
+        undo(&self.entries)?;
+        self.journal.finish()
+    }
+
+    // installs the packaged vpk or loose folder if that didn't happen yet, then cleans up
+    pub fn finish(self) -> Result<()> {
+        if !self.is_installed() {
+            let package = match self.package() {
+                Some(package) => package.to_path_buf(),
+                None => bail!("The run never got to build its vpk, it can only be rolled back"),
+            };
+            // restore whatever the interrupted install left and start it over
+            let install_start = self
+                .entries
+                .iter()
+                .rposition(|entry| matches!(entry, Entry::Packaged(_)))
+                .map(|i| i + 1)
+                .unwrap_or(self.entries.len());
+            undo(&self.entries[install_start..])?;
+            Sca::install_addon(&self.journal, &package)?;
+        }
+        // only the workspace and backups of the previous install can be left at this point
+        for path in self.leftovers() {
+            remove(&path)?;
+        }
+        self.journal.finish()
+    }
+}
+
+fn undo(entries: &[Entry]) -> Result<()> {
+    for entry in entries.iter().rev() {
+        match entry {
+            Entry::Renamed { from, to }
+            | Entry::BackedUp {
+                original: from,
+                backup: to,
+            } if to.exists() && !from.exists() => {
+                std::fs::rename(to, from).with_context(|| {
+                    format!("Can't move {} back to {}", to.display(), from.display())
+                })?;
+            }
+            Entry::Created(path) => remove(path)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    } else if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// journals of runs that never finished and whose process is gone, the others are still in
+// progress, in this process or another instance
+pub fn unfinished_runs() -> Result<Vec<Run>> {
+    let folder = journal_folder()?;
+    if !folder.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = std::fs::read_dir(folder)?
+        .filter_map(|res| res.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some(std::ffi::OsStr::new("jsonl")))
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+    let mut runs = Vec::new();
+    for file in files {
+        let run = Run::load(file)?;
+        if !run.is_running() {
+            runs.push(run);
+        }
+    }
+    Ok(runs)
+}
+
+fn journal_folder() -> Result<PathBuf> {
+    Ok(discovery::config_dir()?.join(JOURNAL_FOLDER_NAME))
+}
+
+// leftovers of versions that compiled next to the exe and renamed tf/models
+pub enum Legacy {
+    TempFolder(PathBuf),
+    RenamedModels { models: PathBuf, renamed: PathBuf },
+    OutputFolder(PathBuf),
+}
+
+impl Legacy {
+    pub fn describe(&self) -> String {
+        match self {
+            Legacy::TempFolder(path) => format!("Temp folder {}", path.display()),
+            Legacy::RenamedModels { models, renamed } => format!(
+                "Your models folder was moved to {}, it goes back to {}",
+                renamed.display(),
+                models.display()
+            ),
+            Legacy::OutputFolder(path) => format!("Compile output {}", path.display()),
+        }
+    }
+
+    pub fn clean(self) -> Result<()> {
+        match self {
+            Legacy::TempFolder(path) | Legacy::OutputFolder(path) => remove(&path),
+            Legacy::RenamedModels { models, renamed } => restore_models(&models, &renamed),
+        }
+    }
+}
+
+pub fn legacy_leftovers() -> Vec<Legacy> {
+    let mut leftovers = Vec::new();
+    if let Ok(exe_folder) = Sca::exe_folder() {
+        let temp_folder = exe_folder.join(LEGACY_TEMP_FOLDER_NAME);
+        if temp_folder.is_dir() {
+            leftovers.push(Legacy::TempFolder(temp_folder));
+        }
+    }
+    if let Ok(tf) = Sca::tf_folder() {
+        let models = tf.join("models");
+        let renamed = tf.join(LEGACY_TEMP_MODELS_NAME);
+        if renamed.is_dir() {
+            // restoring takes care of the compile output as well
+            leftovers.push(Legacy::RenamedModels { models, renamed });
+        } else if models.join(LEGACY_OUTPUT_FOLDER_NAME).is_dir() {
+            leftovers.push(Legacy::OutputFolder(models.join(LEGACY_OUTPUT_FOLDER_NAME)));
+        }
+    }
+    leftovers
+}
+
+// the models folder in place only holds compile output unless the user added something since,
+// so drop the output and move the user's files back without overwriting anything
+fn restore_models(models: &Path, renamed: &Path) -> Result<()> {
+    remove(&models.join(LEGACY_OUTPUT_FOLDER_NAME))?;
+    if models.is_dir() && std::fs::read_dir(models)?.next().is_none() {
+        std::fs::remove_dir(models)?;
+    }
+    if !models.exists() {
+        std::fs::rename(renamed, models)?;
+        return Ok(());
+    }
+    let entries = std::fs::read_dir(renamed)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    for entry in &entries {
+        let destination = models.join(entry.file_name().unwrap());
+        if destination.exists() {
+            bail!(
+                "Both {} and {} exist, move it back by hand",
+                entry.display(),
+                destination.display()
+            );
+        }
+    }
+    for entry in entries {
+        std::fs::rename(&entry, models.join(entry.file_name().unwrap()))?;
+    }
+    std::fs::remove_dir(renamed)?;
+    Ok(())
+}
+
+// everything left behind, by runs that didn't finish and by older versions
+#[derive(Default)]
+pub struct Recovery {
+    pub runs: Vec<Run>,
+    pub legacy: Vec<Legacy>,
+}
+
+impl Recovery {
+    pub fn scan() -> Result<Self> {
+        Ok(Recovery {
+            runs: unfinished_runs()?,
+            legacy: legacy_leftovers(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty() && self.legacy.is_empty()
+    }
+
+    pub fn can_finish(&self) -> bool {
+        self.runs.iter().all(|run| run.can_finish())
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for run in &self.runs {
+            let state = if run.is_installed() {
+                "its vpk was installed, only cleanup is left"
+            } else if run.can_finish() {
+                "its vpk was built but not installed"
+            } else {
+                "it stopped before its vpk was built"
+            };
+            lines.push(format!(
+                "Unfinished run {}, {}",
+                run.journal.file().display(),
+                state
+            ));
+            for leftover in run.leftovers() {
+                lines.push(format!("    left behind: {}", leftover.display()));
+            }
+        }
+        for legacy in &self.legacy {
+            lines.push(legacy.describe());
+        }
+        lines
+    }
+
+    pub fn roll_back(self) -> Result<()> {
+        for run in self.runs {
+            run.roll_back()?;
+        }
+        Self::clean_legacy(self.legacy)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if !self.can_finish() {
+            bail!("Not every run got to build its vpk, they can only be rolled back");
+        }
+        for run in self.runs {
+            run.finish()?;
+        }
+        Self::clean_legacy(self.legacy)
+    }
+
+    fn clean_legacy(legacy: Vec<Legacy>) -> Result<()> {
+        for leftover in legacy {
+            leftover.clean()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::test_folder;
+
+    // a journal in folder started by this process
+    fn journal(folder: &Path) -> Journal {
+        let journal = Journal {
+            file: folder.join("run.jsonl"),
+        };
+        let pid = std::process::id();
+        journal
+            .record(&Entry::Started {
+                pid,
+                timestamp: 0,
+                process_start: process::start(pid),
+            })
+            .unwrap();
+        journal
+    }
+
+    fn load(journal: &Journal) -> Run {
+        Run::load(journal.file().to_path_buf()).unwrap()
+    }
+
+    fn write(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn roll_back_undoes_everything() {
+        let folder = test_folder("journal_roll_back");
+        let journal = journal(&folder);
+        let (created, moved, renamed) = (
+            folder.join("workspace"),
+            folder.join("models"),
+            folder.join("models.moved"),
+        );
+        let (vpk, backup) = (folder.join("sca.vpk"), folder.join("sca.vpk.backup"));
+        write(&moved, "models");
+        write(&vpk, "old vpk");
+        journal.record(&Entry::Created(created.clone())).unwrap();
+        write(&created.join("a.mdl"), "compiled");
+        journal
+            .record(&Entry::Renamed {
+                from: moved.clone(),
+                to: renamed.clone(),
+            })
+            .unwrap();
+        std::fs::rename(&moved, &renamed).unwrap();
+        journal
+            .record(&Entry::BackedUp {
+                original: vpk.clone(),
+                backup: backup.clone(),
+            })
+            .unwrap();
+        std::fs::rename(&vpk, &backup).unwrap();
+        journal
+            .record(&Entry::Packaged(created.join("a.mdl")))
+            .unwrap();
+
+        let run = load(&journal);
+        assert_eq!(run.entries().len(), 5);
+        assert_eq!(run.pid(), Some(std::process::id()));
+        assert!(run.is_running() && !run.is_installed() && run.can_finish());
+        assert_eq!(run.leftovers(), [created.clone(), backup.clone()]);
+        run.roll_back().unwrap();
+        assert!(!created.exists() && !renamed.exists() && !backup.exists());
+        assert_eq!(read(&moved), "models");
+        assert_eq!(read(&vpk), "old vpk");
+        assert!(!journal.file().exists());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn half_written_journals() {
+        let folder = test_folder("journal_half_written");
+        let journal = journal(&folder);
+        let (created, untouched) = (folder.join("workspace"), folder.join("models"));
+        journal.record(&Entry::Created(created.clone())).unwrap();
+        std::fs::create_dir_all(&created).unwrap();
+        write(&untouched, "models");
+        // the crash came while writing the next entry, so its rename never happened
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(journal.file())
+            .unwrap();
+        write!(file, "{{\"Renamed\":{{\"from\":{:?}", untouched).unwrap();
+        drop(file);
+
+        let run = load(&journal);
+        assert_eq!(run.entries().len(), 2);
+        assert!(!run.can_finish());
+        assert!(run.finish().is_err());
+        assert!(created.exists() && journal.file().exists());
+        load(&journal).roll_back().unwrap();
+        assert!(!created.exists());
+        assert_eq!(read(&untouched), "models");
+        assert!(!journal.file().exists());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn finishing_an_installed_run_cleans_up() {
+        let folder = test_folder("journal_finish");
+        let journal = journal(&folder);
+        let workspace = folder.join("workspace");
+        let (vpk, backup) = (folder.join("sca.vpk"), folder.join("sca.vpk.backup"));
+        write(&vpk, "old vpk");
+        journal.record(&Entry::Created(workspace.clone())).unwrap();
+        write(&workspace.join("sca.vpk"), "new vpk");
+        journal
+            .record(&Entry::Packaged(workspace.join("sca.vpk")))
+            .unwrap();
+        journal
+            .record(&Entry::BackedUp {
+                original: vpk.clone(),
+                backup: backup.clone(),
+            })
+            .unwrap();
+        std::fs::rename(&vpk, &backup).unwrap();
+        std::fs::copy(workspace.join("sca.vpk"), &vpk).unwrap();
+        journal.record(&Entry::Installed(vpk.clone())).unwrap();
+
+        // rolling back an installed run keeps the install as well
+        load(&journal).roll_back().unwrap();
+        assert!(!workspace.exists() && !backup.exists());
+        assert_eq!(read(&vpk), "new vpk");
+        assert!(!journal.file().exists());
+
+        // and so does finishing it
+        let journal = self::journal(&folder);
+        write(&backup, "old vpk");
+        journal
+            .record(&Entry::BackedUp {
+                original: vpk.clone(),
+                backup: backup.clone(),
+            })
+            .unwrap();
+        journal.record(&Entry::Installed(vpk.clone())).unwrap();
+        let run = load(&journal);
+        assert!(run.is_installed() && run.can_finish());
+        run.finish().unwrap();
+        assert!(!backup.exists());
+        assert_eq!(read(&vpk), "new vpk");
+        assert!(!journal.file().exists());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn runs_of_other_processes() {
+        let folder = test_folder("journal_running");
+        let file = folder.join("run.jsonl");
+        let started = |pid, process_start: Option<&str>| {
+            let journal = Journal { file: file.clone() };
+            let _ = std::fs::remove_file(&file);
+            journal
+                .record(&Entry::Started {
+                    pid,
+                    timestamp: 0,
+                    process_start: process_start.map(str::to_owned),
+                })
+                .unwrap();
+            load(&journal).is_running()
+        };
+        let pid = std::process::id();
+        assert!(started(pid, process::start(pid).as_deref()));
+        // the pid got reused by a process started later
+        assert!(!started(pid, Some("0")));
+        // journals without a start time count as running while the pid is there
+        assert!(started(pid, None));
+        assert!(!started(u32::MAX - 1, None));
+        Journal { file }.finish().unwrap();
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
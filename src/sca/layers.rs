@@ -0,0 +1,433 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{Class, Origin, Sca};
+
+// one of the values of an Origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    X,
+    Y,
+    Z,
+    Yaw,
+    Pitch,
+    Roll,
+    Scale,
+}
+
+pub const FIELDS: [Field; 7] = [
+    Field::X,
+    Field::Y,
+    Field::Z,
+    Field::Yaw,
+    Field::Pitch,
+    Field::Roll,
+    Field::Scale,
+];
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::X => "x",
+            Field::Y => "y",
+            Field::Z => "z",
+            Field::Yaw => "yaw",
+            Field::Pitch => "pitch",
+            Field::Roll => "roll",
+            Field::Scale => "scale",
+        }
+    }
+
+    pub fn get(&self, origin: &Origin) -> f32 {
+        match self {
+            Field::X => origin.x,
+            Field::Y => origin.y,
+            Field::Z => origin.z,
+            Field::Yaw => origin.yaw,
+            Field::Pitch => origin.pitch,
+            Field::Roll => origin.roll,
+            Field::Scale => origin.scale,
+        }
+    }
+
+    pub fn set(&self, origin: &mut Origin, value: f32) {
+        match self {
+            Field::X => origin.x = value,
+            Field::Y => origin.y = value,
+            Field::Z => origin.z = value,
+            Field::Yaw => origin.yaw = value,
+            Field::Pitch => origin.pitch = value,
+            Field::Roll => origin.roll = value,
+            Field::Scale => origin.scale = value,
+        }
+    }
+}
+
+impl std::str::FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FIELDS
+            .iter()
+            .find(|field| field.name() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("{} isn't x, y, z, yaw, pitch, roll or scale", s))
+    }
+}
+
+// which layer a value comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    // none of them sets it
+    Default,
+    Global,
+    Class,
+    Weapon,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Source::Default => "default",
+            Source::Global => "all weapons",
+            Source::Class => "class",
+            Source::Weapon => "weapon",
+        })
+    }
+}
+
+// where a layer sits, every weapon gets the global layer, then the one of its class, then its own
+#[derive(Clone, Debug, PartialEq)]
+pub enum Level {
+    Global,
+    Class(Class),
+    // the class and animation folder name
+    Weapon(Class, String),
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Global => f.write_str("all weapons"),
+            Level::Class(class) => write!(f, "{}", class),
+            Level::Weapon(class, name) => write!(f, "{} {}", class, name),
+        }
+    }
+}
+
+// the values of an Origin a layer sets, the others come from the layer below
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct Layer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub z: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yaw: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roll: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+}
+
+impl Layer {
+    // sets the values of origin that aren't at their default
+    pub fn from_origin(origin: &Origin) -> Self {
+        let default = Origin::default();
+        let mut layer = Layer::default();
+        for field in FIELDS {
+            if field.get(origin) != field.get(&default) {
+                layer.set(field, Some(field.get(origin)));
+            }
+        }
+        layer
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Layer::default()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Layer::default();
+    }
+
+    pub fn get(&self, field: Field) -> Option<f32> {
+        match field {
+            Field::X => self.x,
+            Field::Y => self.y,
+            Field::Z => self.z,
+            Field::Yaw => self.yaw,
+            Field::Pitch => self.pitch,
+            Field::Roll => self.roll,
+            Field::Scale => self.scale,
+        }
+    }
+
+    // None inherits the value again
+    pub fn set(&mut self, field: Field, value: Option<f32>) {
+        let slot = match field {
+            Field::X => &mut self.x,
+            Field::Y => &mut self.y,
+            Field::Z => &mut self.z,
+            Field::Yaw => &mut self.yaw,
+            Field::Pitch => &mut self.pitch,
+            Field::Roll => &mut self.roll,
+            Field::Scale => &mut self.scale,
+        };
+        *slot = value;
+    }
+
+    // the values it sets, like x=1 yaw=-10
+    pub fn describe(&self) -> String {
+        FIELDS
+            .iter()
+            .filter_map(|field| {
+                self.get(*field)
+                    .map(|value| format!("{}={}", field.name(), value))
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+impl Sca {
+    pub fn layer(&self, level: &Level) -> Option<&Layer> {
+        match level {
+            Level::Global => Some(&self.global),
+            Level::Class(class) => self
+                .folders
+                .iter()
+                .find(|folder| folder.class == *class)
+                .map(|folder| &folder.layer),
+            Level::Weapon(class, name) => self
+                .folders
+                .iter()
+                .filter(|folder| folder.class == *class)
+                .flat_map(|folder| folder.animations.iter())
+                .find(|animation| animation.name == *name)
+                .map(|animation| &animation.layer),
+        }
+    }
+
+    // None if the SCA has no such class or animation
+    pub fn layer_mut(&mut self, level: &Level) -> Option<&mut Layer> {
+        match level {
+            Level::Global => Some(&mut self.global),
+            Level::Class(class) => self
+                .folders
+                .iter_mut()
+                .find(|folder| folder.class == *class)
+                .map(|folder| &mut folder.layer),
+            Level::Weapon(class, name) => self
+                .folders
+                .iter_mut()
+                .filter(|folder| folder.class == *class)
+                .flat_map(|folder| folder.animations.iter_mut())
+                .find(|animation| animation.name == *name)
+                .map(|animation| &mut animation.layer),
+        }
+    }
+
+    // the offset the layers add up to at level, and which of them every field comes from,
+    // by FIELDS
+    pub fn effective(&self, level: &Level) -> (Origin, [Source; 7]) {
+        let mut layers = vec![(Source::Global, Some(&self.global))];
+        match level {
+            Level::Global => {}
+            Level::Class(class) => {
+                layers.push((Source::Class, self.layer(&Level::Class(*class))));
+            }
+            Level::Weapon(class, _) => {
+                layers.push((Source::Class, self.layer(&Level::Class(*class))));
+                layers.push((Source::Weapon, self.layer(level)));
+            }
+        }
+        let mut origin = Origin::default();
+        let mut sources = [Source::Default; 7];
+        for (source, layer) in layers {
+            let layer = match layer {
+                Some(layer) => layer,
+                None => continue,
+            };
+            for (index, field) in FIELDS.iter().enumerate() {
+                if let Some(value) = layer.get(*field) {
+                    field.set(&mut origin, value);
+                    sources[index] = source;
+                }
+            }
+        }
+        (origin, sources)
+    }
+
+    // works out the origin of every animation from the layers, that's what gets compiled
+    pub fn resolve(&mut self) {
+        let origins = self
+            .folders
+            .iter()
+            .map(|folder| {
+                folder
+                    .animations
+                    .iter()
+                    .map(|animation| {
+                        let level = Level::Weapon(folder.class, animation.name.clone());
+                        self.effective(&level).0
+                    })
+                    .collect::<Vec<Origin>>()
+            })
+            .collect::<Vec<_>>();
+        for (folder, origins) in self.folders.iter_mut().zip(origins) {
+            for (animation, origin) in folder.animations.iter_mut().zip(origins) {
+                animation.origin = origin;
+            }
+        }
+    }
+
+    pub fn resolved(&self) -> Sca {
+        let mut sca = self.clone();
+        sca.resolve();
+        sca
+    }
+
+    // for settings from before layers, every modified origin becomes the weapon's own layer
+    pub fn layers_from_origins(&mut self) {
+        self.global.clear();
+        for folder in self.folders.iter_mut() {
+            folder.layer.clear();
+            for animation in folder.animations.iter_mut() {
+                animation.layer = Layer::from_origin(&animation.origin);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_sca;
+    use super::*;
+
+    fn pistol() -> Level {
+        Level::Weapon(Class::Scout, "Pistol".to_owned())
+    }
+
+    fn set(sca: &mut Sca, level: &Level, field: Field, value: f32) {
+        sca.layer_mut(level).unwrap().set(field, Some(value));
+    }
+
+    #[test]
+    fn nothing_set_is_the_default() {
+        let sca = test_sca();
+        for level in &[Level::Global, Level::Class(Class::Scout), pistol()] {
+            let (origin, sources) = sca.effective(level);
+            assert_eq!(origin, Origin::default());
+            assert_eq!(sources, [Source::Default; 7]);
+        }
+    }
+
+    #[test]
+    fn the_closest_layer_wins() {
+        let mut sca = test_sca();
+        set(&mut sca, &Level::Global, Field::X, 1.0);
+        set(&mut sca, &Level::Global, Field::Y, 1.0);
+        set(&mut sca, &Level::Global, Field::Z, 1.0);
+        set(&mut sca, &Level::Class(Class::Scout), Field::Y, 2.0);
+        set(&mut sca, &Level::Class(Class::Scout), Field::Z, 2.0);
+        set(&mut sca, &pistol(), Field::Z, 3.0);
+        set(&mut sca, &pistol(), Field::Scale, 0.5);
+
+        let (origin, sources) = sca.effective(&pistol());
+        assert_eq!((origin.x, origin.y, origin.z), (1.0, 2.0, 3.0));
+        assert_eq!(origin.scale, 0.5);
+        assert_eq!(origin.yaw, Origin::default().yaw);
+        use Source::{Default, Global, Weapon};
+        assert_eq!(
+            sources,
+            [
+                Global,
+                Source::Class,
+                Weapon,
+                Default,
+                Default,
+                Default,
+                Weapon
+            ]
+        );
+
+        // above the weapon its own layer doesn't count
+        let (origin, sources) = sca.effective(&Level::Class(Class::Scout));
+        assert_eq!((origin.x, origin.y, origin.z), (1.0, 2.0, 2.0));
+        assert_eq!(origin.scale, Origin::default().scale);
+        assert_eq!(&sources[..3], [Global, Source::Class, Source::Class]);
+        let (origin, sources) = sca.effective(&Level::Global);
+        assert_eq!((origin.x, origin.y, origin.z), (1.0, 1.0, 1.0));
+        assert_eq!(&sources[..4], [Global, Global, Global, Default]);
+
+        // the other class and its weapons only get the global layer
+        let shotgun = Level::Weapon(Class::Soldier, "Shotgun".to_owned());
+        let (origin, sources) = sca.effective(&shotgun);
+        assert_eq!((origin.x, origin.y, origin.z), (1.0, 1.0, 1.0));
+        assert_eq!(&sources[..3], [Global, Global, Global]);
+    }
+
+    #[test]
+    fn unset_values_inherit_again() {
+        let mut sca = test_sca();
+        set(&mut sca, &Level::Global, Field::Yaw, 10.0);
+        set(&mut sca, &pistol(), Field::Yaw, -10.0);
+        assert_eq!(sca.effective(&pistol()).0.yaw, -10.0);
+        sca.layer_mut(&pistol()).unwrap().set(Field::Yaw, None);
+        let (origin, sources) = sca.effective(&pistol());
+        assert_eq!((origin.yaw, sources[3]), (10.0, Source::Global));
+    }
+
+    #[test]
+    fn weapons_the_sca_doesnt_have() {
+        let mut sca = test_sca();
+        set(&mut sca, &Level::Class(Class::Scout), Field::X, 2.0);
+        let missing = Level::Weapon(Class::Scout, "Rocket Launcher".to_owned());
+        assert!(sca.layer(&missing).is_none() && sca.layer_mut(&missing).is_none());
+        let (origin, sources) = sca.effective(&missing);
+        assert_eq!((origin.x, sources[0]), (2.0, Source::Class));
+        assert!(sca.layer(&Level::Class(Class::Heavy)).is_none());
+    }
+
+    #[test]
+    fn resolve_compiles_what_the_layers_add_up_to() {
+        let mut sca = test_sca();
+        set(&mut sca, &Level::Global, Field::X, 1.0);
+        set(&mut sca, &Level::Class(Class::Scout), Field::Roll, 5.0);
+        set(&mut sca, &pistol(), Field::X, 4.0);
+        let resolved = sca.resolved();
+        // resolved leaves the sca itself alone
+        assert_eq!(sca.folders[0].animations[0].origin, Origin::default());
+        let origin = |class: usize, animation: usize| {
+            let origin = resolved.folders[class].animations[animation].origin;
+            (origin.x, origin.roll)
+        };
+        assert_eq!(origin(0, 0), (4.0, 5.0));
+        assert_eq!(origin(0, 1), (1.0, 5.0));
+        assert_eq!(origin(1, 0), (1.0, 0.0));
+        sca.resolve();
+        assert_eq!(
+            sca.folders[0].animations[0].origin,
+            resolved.folders[0].animations[0].origin
+        );
+    }
+
+    #[test]
+    fn origins_become_weapon_layers() {
+        let mut sca = test_sca();
+        set(&mut sca, &Level::Global, Field::X, 1.0);
+        sca.folders[0].animations[1].origin.pitch = 7.0;
+        sca.layers_from_origins();
+        assert!(sca.global.is_empty());
+        let bat = Level::Weapon(Class::Scout, "Bat".to_owned());
+        assert_eq!(sca.layer(&bat).unwrap().describe(), "pitch=7");
+        assert!(sca.layer(&pistol()).unwrap().is_empty());
+        assert_eq!(sca.effective(&bat).1[4], Source::Weapon);
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::path::Path;
+
+use super::{ClassAnimations, ExistingOrigin, Sca};
+use crate::fs;
+use crate::vpk::VpkReader;
+
+// where the manifest goes inside the vpk, the game never looks there
+pub const MANIFEST_PATH: &str = "v_customizer/manifest.json";
+// bump when the format changes in a way older versions can't read
+pub const MANIFEST_VERSION: u32 = 1;
+
+// the settings a vpk was built with, so it can be loaded back into the editor
+#[derive(Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    // the v_customizer that built the vpk
+    pub tool_version: String,
+    // sha256 of the SCA folder, see sca_hash
+    pub sca_hash: String,
+    pub existing_origin: ExistingOrigin,
+    pub classes: Vec<ClassAnimations>,
+}
+
+impl Manifest {
+    pub fn new(sca: &Sca) -> Result<Self> {
+        Ok(Manifest {
+            version: MANIFEST_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            sca_hash: sca_hash(Sca::sca_folder()?)?,
+            existing_origin: sca.existing_origin,
+            // only what got compiled, the layers it came from are in the profile
+            classes: sca
+                .folders
+                .iter()
+                .cloned()
+                .map(|mut class| {
+                    class.layer.clear();
+                    class
+                        .animations
+                        .iter_mut()
+                        .for_each(|animation| animation.layer.clear());
+                    class
+                })
+                .collect(),
+        })
+    }
+
+    pub fn from_vpk<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let vpk = VpkReader::open(&path)?;
+        let entry = vpk.find(MANIFEST_PATH).ok_or_else(|| {
+            anyhow!(
+                "{} has no settings in it, it wasn't built by v_customizer",
+                path.as_ref().display()
+            )
+        })?;
+        Self::from_json(&vpk.read(entry)?)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        // the version is checked first so a newer format gets a better error than a parse error
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+        let versioned: Versioned =
+            serde_json::from_slice(json).context("The settings in the vpk are broken")?;
+        if versioned.version > MANIFEST_VERSION {
+            bail!(
+                "The vpk was built by a newer v_customizer (settings version {}), update to import it",
+                versioned.version
+            );
+        }
+        serde_json::from_slice(json).context("The settings in the vpk are broken")
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+// sha256 over the path and content of every file in folder, in a fixed order,
+// so two SCA folders with the same files give the same hash
+pub fn sca_hash<P: AsRef<Path>>(folder: P) -> Result<String> {
+    let folder = folder.as_ref();
+    let files = fs::files(folder)?;
+    let mut files = files
+        .iter()
+        .map(|path| (fs::relative_path(path, folder), path))
+        .collect::<Vec<_>>();
+    files.sort();
+    let mut hasher = Sha256::new();
+    for (name, path) in files {
+        let content =
+            std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
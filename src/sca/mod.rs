@@ -1,11 +1,42 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::os::windows::process::CommandExt;
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-pub const TEMP_FOLDER_NAME: &'static str = "__v_customizer_temp__";
-const TEMP_MODELS_NAME: &'static str = "__TEMP_MODELS";
+use crate::fs;
+use crate::qc::{Command, Directive, Qc, Token};
+use crate::smd;
+use crate::vpk::{self, VpkReader, VpkWriter};
+
+pub mod cache;
+pub mod diagnostics;
+pub mod discovery;
+pub mod graph;
+pub mod history;
+pub mod install;
+pub mod journal;
+pub mod layers;
+pub mod manifest;
+pub mod platform;
+pub mod process;
+pub mod profile;
+pub mod recover;
+pub mod scheduler;
+pub mod vdf;
+pub mod verify;
+pub mod worker;
+pub mod workspace;
+
+use diagnostics::CompileLog;
+use history::History;
+use install::Patch;
+use journal::{Entry, Journal};
+use layers::{Layer, Level};
+use manifest::Manifest;
+use platform::{CancelToken, Tool};
+use workspace::Workspace;
+
 const CLASSES: [Class; 9] = [
     Class::Scout,
     Class::Soldier,
@@ -18,33 +49,152 @@ const CLASSES: [Class; 9] = [
     Class::Spy,
 ];
 
-#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+// where a weapon sits, x y z and yaw go into $origin,
+// pitch, roll and scale get baked into the smds since $origin can't express them
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
 pub struct Origin {
     pub x: f32,
     pub y: f32,
     pub z: f32,
-    pub z_rot: f32,
+    #[serde(alias = "z_rot")]
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+    pub scale: f32,
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Origin {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            yaw: 0.0,
+            pitch: 0.0,
+            roll: 0.0,
+            scale: 1.0,
+        }
+    }
 }
 
 impl Origin {
     pub fn reset(&mut self) {
-        self.x = 0.0;
-        self.y = 0.0;
-        self.z = 0.0;
-        self.z_rot = 0.0;
+        *self = Origin::default();
     }
 
-    fn is_modified(&self) -> bool {
-        if self.x == 0.0 && self.y == 0.0 && self.z == 0.0 && self.z_rot == 0.0 {
-            false
-        } else {
-            true
+    pub fn is_modified(&self) -> bool {
+        *self != Origin::default()
+    }
+
+    // the fields that aren't at their default, like x=1 yaw=-10
+    pub fn describe(&self) -> String {
+        let default = Origin::default();
+        [
+            ("x", self.x, default.x),
+            ("y", self.y, default.y),
+            ("z", self.z, default.z),
+            ("yaw", self.yaw, default.yaw),
+            ("pitch", self.pitch, default.pitch),
+            ("roll", self.roll, default.roll),
+            ("scale", self.scale, default.scale),
+        ]
+        .iter()
+        .filter(|(_, value, default)| value != default)
+        .map(|(field, value, _)| format!("{}={}", field, value))
+        .collect::<Vec<String>>()
+        .join(" ")
+    }
+
+    // what has to be done to the smds, None if $origin covers everything
+    fn smd_transform(&self) -> Option<smd::Transform> {
+        if self.pitch == 0.0 && self.roll == 0.0 && self.scale == 1.0 {
+            return None;
+        }
+        Some(smd::Transform::from_angles(
+            self.pitch, self.roll, self.scale,
+        ))
+    }
+
+    fn from_command(command: &Command) -> Result<Self> {
+        match command.directive()? {
+            Directive::Origin { position, rotation } => Ok(Origin {
+                x: position[0],
+                y: position[1],
+                z: position[2],
+                yaw: rotation,
+                ..Origin::default()
+            }),
+            _ => bail!("Line {} isn't an $origin", command.line()),
         }
     }
+
+    // the first $origin of a qc and the files it $includes, which is what the weapon gets
+    // without any changes
+    fn from_qc(qc_file: &Path) -> Result<Option<Self>> {
+        match find_origins(qc_file)?.first() {
+            Some((file, index)) => Self::from_command(&Qc::read(file)?.commands[*index]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // both offsets added together, scales multiply
+    fn compose(&self, other: &Origin) -> Self {
+        Origin {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+            yaw: self.yaw + other.yaw,
+            pitch: self.pitch + other.pitch,
+            roll: self.roll + other.roll,
+            scale: self.scale * other.scale,
+        }
+    }
+
+    // the $origin values
+    fn values(&self) -> [f32; 4] {
+        [self.x, self.y, self.z, self.yaw]
+    }
+
+    // writes the offsets into an existing $origin, keeping its formatting
+    fn write_to(&self, command: &mut Command) -> Result<()> {
+        let existing = command.values().len();
+        for (index, value) in self.values().iter().enumerate() {
+            if index < existing {
+                command.set_value(index, value.to_string())?;
+            } else {
+                command.push(Token::word(value.to_string()));
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+// what to do with an SCA qc that already has an $origin
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum ExistingOrigin {
+    // the offsets get added to the ones in the qc
+    #[default]
+    Compose,
+    // the offsets overwrite the ones in the qc
+    Replace,
+    // generating fails
+    Abort,
+}
+
+impl std::fmt::Display for ExistingOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExistingOrigin::Compose => "Add on top",
+            ExistingOrigin::Replace => "Replace",
+            ExistingOrigin::Abort => "Abort",
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
 pub enum Class {
+    #[default]
     Scout,
     Soldier,
     Pyro,
@@ -56,26 +206,34 @@ pub enum Class {
     Spy,
 }
 
-impl Default for Class {
-    fn default() -> Self {
-        Class::Scout
+impl std::fmt::Display for Class {
+    // assumes the folder names won't change
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Class::Scout => "Scout",
+            Class::Soldier => "Soldier",
+            Class::Pyro => "Pyro",
+            Class::Demo => "Demo",
+            Class::Heavy => "Heavy",
+            Class::Engineer => "Engineer",
+            Class::Medic => "Medic",
+            Class::Sniper => "Sniper",
+            Class::Spy => "Spy",
+        };
+        f.write_str(name)
     }
 }
 
-impl ToString for Class {
-    // assumes the folder names won't change
-    fn to_string(&self) -> String {
-        match self {
-            Class::Scout => "Scout".to_owned(),
-            Class::Soldier => "Soldier".to_owned(),
-            Class::Pyro => "Pyro".to_owned(),
-            Class::Demo => "Demo".to_owned(),
-            Class::Heavy => "Heavy".to_owned(),
-            Class::Engineer => "Engineer".to_owned(),
-            Class::Medic => "Medic".to_owned(),
-            Class::Sniper => "Sniper".to_owned(),
-            Class::Spy => "Spy".to_owned(),
-        }
+impl std::str::FromStr for Class {
+    type Err = anyhow::Error;
+
+    // the folder name, in any case
+    fn from_str(s: &str) -> Result<Self> {
+        CLASSES
+            .iter()
+            .find(|class| class.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| anyhow!("{} isn't a class", s))
     }
 }
 
@@ -95,17 +253,26 @@ impl AsRef<Path> for Class {
     }
 }
 
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct Animation {
     // animation folder name
     pub name: String,
-    // $origin positions
+    // $origin positions, what the layers add up to, see Sca::resolve
     pub origin: Origin,
+    // the weapon's own offsets, on top of the ones of its class
+    #[serde(default, skip_serializing_if = "Layer::is_empty")]
+    pub layer: Layer,
+    // the $origin the SCA qc already has, if any
+    #[serde(skip)]
+    pub baseline: Option<Origin>,
 }
 
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct ClassAnimations {
     pub class: Class,
+    // offsets every weapon of the class gets, on top of the global ones
+    #[serde(default, skip_serializing_if = "Layer::is_empty")]
+    pub layer: Layer,
     pub animations: Vec<Animation>,
 }
 
@@ -118,10 +285,30 @@ impl ClassAnimations {
     }
 }
 
-#[derive(Deserialize, Serialize, Default)]
+// offsets that were loaded for an animation folder the SCA doesn't have, kept until they get
+// moved to another animation or dropped
+#[derive(Clone)]
+pub struct Orphan {
+    pub class: Class,
+    pub name: String,
+    pub layer: Layer,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct Sca {
     // there should be 9 folders for the 9 classes
     pub folders: Vec<ClassAnimations>,
+    #[serde(default)]
+    pub existing_origin: ExistingOrigin,
+    // offsets every weapon gets
+    #[serde(default)]
+    pub global: Layer,
+    // what the last load_layers couldn't place, never compiled
+    #[serde(skip)]
+    pub orphans: Vec<Orphan>,
+    // the edits undo and redo go through
+    #[serde(skip)]
+    pub history: History,
 }
 
 impl Sca {
@@ -129,9 +316,7 @@ impl Sca {
     pub fn new() -> Result<Self> {
         Self::check_folders()?;
         let sca_dir = Self::sca_folder()?;
-        let mut sca = Sca {
-            folders: Vec::new(),
-        };
+        let mut sca = Sca::default();
         for class in CLASSES {
             let class_path = sca_dir.join(class);
             let class_folder = std::fs::read_dir(class_path)?
@@ -139,6 +324,7 @@ impl Sca {
                 .filter(|e| e.path().is_dir());
             sca.folders.push(ClassAnimations {
                 class,
+                layer: Layer::default(),
                 animations: class_folder
                     .map(|folder| Animation {
                         name: folder
@@ -149,13 +335,30 @@ impl Sca {
                             .unwrap()
                             .to_string(),
                         origin: Origin::default(),
+                        layer: Layer::default(),
+                        baseline: None,
                     })
                     .collect::<Vec<Animation>>(),
             });
         }
+        sca.load_baselines()?;
         Ok(sca)
     }
 
+    // reads the $origin every SCA qc already has,
+    // an animation without a readable qc just doesn't get a baseline
+    pub fn load_baselines(&mut self) -> Result<()> {
+        let sca_dir = Self::sca_folder()?;
+        for class in self.folders.iter_mut() {
+            for animation in class.animations.iter_mut() {
+                animation.baseline = Self::find_qc(sca_dir.join(class.class).join(&animation.name))
+                    .and_then(|qc_file| Origin::from_qc(&qc_file))
+                    .unwrap_or(None);
+            }
+        }
+        Ok(())
+    }
+
     // something is selected if its origin is isn't all 0.0
     pub fn get_selected_classes(&self) -> Vec<&ClassAnimations> {
         self.folders
@@ -169,161 +372,343 @@ impl Sca {
             .collect::<Vec<&ClassAnimations>>()
     }
 
-    pub fn get_selected_class_qcs(&self) -> Result<Vec<PathBuf>> {
+    pub fn get_selected_class_qcs(&self, workspace: &Workspace) -> Result<Vec<PathBuf>> {
         let mut result = Vec::new();
         for class in self.get_selected_classes() {
-            let class_folder = Self::exe_folder()?
-                .join(TEMP_FOLDER_NAME)
-                .join(&class.class);
-            let class_qc_dir = std::fs::read_dir(class_folder)?
-                .filter_map(|res| res.ok())
-                .filter(|res| res.path().extension() == Some(std::ffi::OsStr::new("qc")))
-                .next()
-                .ok_or(anyhow!("Can't find class qc"))?
-                .path();
-            result.push(class_qc_dir);
+            let class_folder = workspace.source_folder().join(class.class);
+            result.push(Self::find_qc(class_folder)?);
         }
         Ok(result)
     }
 
-    // pub fn compile_class(&self) -> Result<()> {
-    //     for qc_file in self.get_temp_folder_qcs(false)? {
-    //         Self::compile(qc_file)?;
-    //     }
-    //     for class_qc_dir in self.get_selected_class_qcs()? {
-    //         Self::compile(class_qc_dir)?;
-    //     }
-    //     Ok(())
-    // }
-
-    // compiles using studiomdl.exe
-    pub fn compile<P: AsRef<Path>>(qc_file: P) -> Result<()> {
-        let output = std::process::Command::new(Self::studiomdl_exe()?)
-            .arg("-game")
-            .arg(Self::tf_folder()?)
-            .args(["-nop4", "-verbose"])
-            .arg(qc_file.as_ref())
-            .creation_flags(0x00000008)
-            .output()?;
-        println!("{}", std::str::from_utf8(&output.stdout).unwrap());
-        if !output.status.success() {
-            bail!("studiomdl.exe didn't exit with exit code 0");
+    // the qc in folder that compiles a model, .qci and other helper files don't have a $modelname
+    pub fn find_qc<P: AsRef<Path>>(folder: P) -> Result<PathBuf> {
+        let folder = folder.as_ref();
+        let mut qcs = std::fs::read_dir(folder)?
+            .filter_map(|res| res.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension() == Some(std::ffi::OsStr::new("qc")))
+            .collect::<Vec<PathBuf>>();
+        qcs.sort();
+        let mut models = Vec::new();
+        for qc in qcs {
+            if Qc::read(&qc)?.find("$modelname").next().is_some() {
+                models.push(qc);
+            }
+        }
+        match models.len() {
+            0 => bail!("Can't find a qc with a $modelname in {}", folder.display()),
+            1 => Ok(models.remove(0)),
+            _ => bail!("More than one qc in {} has a $modelname", folder.display()),
         }
-        Ok(())
     }
 
-    // converts to vpk and moves to custom
-    pub fn convert_to_vpk() -> Result<()> {
-        let vpk_exe = Self::studiomdl_exe()?.with_file_name("vpk.exe");
-        let temp_model_folder = Self::tf_folder()?
-            .join("models")
-            .join("__TEMP")
-            .join("0_ViewmodelCustomized");
-        let output = std::process::Command::new(vpk_exe)
-            .arg(&temp_model_folder)
-            .creation_flags(0x00000008)
-            .output()?;
-        println!("{}", std::str::from_utf8(&output.stdout).unwrap());
-        if !output.status.success() {
-            bail!("vpk.exe didn't exit with exit code 0");
-        }
-        std::fs::rename(
-            temp_model_folder.with_file_name("0_ViewmodelCustomized.vpk"),
-            Self::tf_folder()?
-                .join("custom")
-                .join("0_ViewmodelCustomized.vpk"),
+    // compiles using studiomdl.exe into game_folder, through wine/proton when not on windows
+    // the process gets killed when cancel is triggered
+    // runs studiomdl on qc_file and keeps what it printed, a failed compile is still Ok,
+    // only not being able to run it at all is an error
+    pub fn compile<P: AsRef<Path>, Q: AsRef<Path>>(
+        game_folder: P,
+        qc_file: Q,
+        weapon: String,
+        cancel: &CancelToken,
+    ) -> Result<CompileLog> {
+        let qc_file = qc_file.as_ref();
+        let studiomdl = Tool::new(Self::studiomdl_exe()?, discovery::runner()?);
+        let output = platform::output(
+            studiomdl
+                .command()?
+                .arg("-game")
+                .arg(studiomdl.path_arg(game_folder))
+                .args(["-nop4", "-verbose"])
+                .arg(studiomdl.path_arg(qc_file)),
+            cancel,
         )?;
-        Ok(())
+        Ok(CompileLog::new(qc_file.to_path_buf(), weapon, &output))
     }
 
-    pub fn create_temp_models_folder() -> Result<()> {
-        let models_folder = Self::tf_folder()?.join("models");
-        if !models_folder.exists() {
-            std::fs::create_dir_all(&models_folder)?;
+    // packs the workspace output and the settings that made it into a vpk and moves it to custom,
+    // with a patch whatever the installed vpk has that this run didn't touch goes in as well
+    pub fn convert_to_vpk(&self, workspace: &Workspace, patch: Option<&Patch>) -> Result<()> {
+        let output_folder = workspace.output_folder();
+        let mut writer = VpkWriter::new(vpk::Version::V2);
+        // a patch that only resets weapons has nothing compiled
+        if output_folder.is_dir() {
+            writer.add_folder(&output_folder)?;
         }
-        std::fs::rename(
-            &models_folder,
-            models_folder.with_file_name(TEMP_MODELS_NAME),
-        )?;
-        Ok(())
+        if let Some(patch) = patch {
+            patch.carry_over(&output_folder, |path, data| {
+                if writer.contains(path) {
+                    return Ok(());
+                }
+                writer.add_data(path, data)
+            })?;
+        }
+        writer.add_data(manifest::MANIFEST_PATH, Manifest::new(self)?.to_json()?)?;
+        let package = writer
+            .write(workspace.root(), workspace::ADDON_NAME)?
+            .remove(0);
+        let problems = VpkReader::open(&package)?.verify();
+        if !problems.is_empty() {
+            bail!("The vpk came out broken:\n{}", problems.join("\n"));
+        }
+        workspace
+            .journal()
+            .record(&Entry::Packaged(package.clone()))?;
+        Self::install_addon(workspace.journal(), package)
     }
 
-    pub fn delete_temp_models_folder() -> Result<()> {
-        let models_folder = Self::tf_folder()?.join("models");
-        let temp_models_folder = models_folder.clone().with_file_name(TEMP_MODELS_NAME);
-        if temp_models_folder.exists() {
-            std::fs::remove_dir_all(&models_folder)?;
-            std::fs::rename(&temp_models_folder, &models_folder)?;
+    // copies the workspace output and the settings that made it into a folder and moves that to
+    // custom, the game loads it the same as the vpk
+    pub fn install_loose(&self, workspace: &Workspace, patch: Option<&Patch>) -> Result<()> {
+        let output_folder = workspace.output_folder();
+        let package = workspace.root().join(workspace::ADDON_NAME);
+        std::fs::create_dir_all(&package)?;
+        if output_folder.is_dir() {
+            fs::copy_folder(&output_folder, &package)?;
+        }
+        let add = |path: &str, data: Vec<u8>| -> Result<()> {
+            let file = package.join(path);
+            if let Some(parent) = file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(file, data)?;
+            Ok(())
+        };
+        if let Some(patch) = patch {
+            patch.carry_over(&output_folder, add)?;
+        }
+        add(manifest::MANIFEST_PATH, Manifest::new(self)?.to_json()?)?;
+        workspace
+            .journal()
+            .record(&Entry::Packaged(package.clone()))?;
+        Self::install_addon(workspace.journal(), package)
+    }
+
+    // installs a packaged vpk or loose folder into custom and moves the other one out of the way,
+    // so the game never loads both
+    pub fn install_addon<P: AsRef<Path>>(journal: &Journal, package: P) -> Result<()> {
+        let package = package.as_ref();
+        let (destination, other) = if package.is_dir() {
+            (Self::installed_loose()?, Self::installed_vpk()?)
+        } else {
+            (Self::installed_vpk()?, Self::installed_loose()?)
+        };
+        Self::install_file(journal, package, destination, Some(&other))
+    }
+
+    // the workspace is usually on another drive, so copy next to the destination first,
+    // every step is journaled so an interrupted install can be rolled back or finished,
+    // source can be a file or a folder and replaces gets removed along with the install
+    pub fn install_file<P: AsRef<Path>, Q: AsRef<Path>>(
+        journal: &Journal,
+        source: P,
+        destination: Q,
+        replaces: Option<&Path>,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let destination = destination.as_ref();
+        // suffixes go after the whole name, the vpk and the loose folder only differ by extension
+        let partial = with_suffix(destination, "partial");
+        let backup = with_suffix(destination, "backup");
+        journal.record(&Entry::Created(partial.clone()))?;
+        if source.is_dir() {
+            if partial.exists() {
+                std::fs::remove_dir_all(&partial)?;
+            }
+            fs::copy_folder(source, &partial)?;
+        } else {
+            std::fs::copy(source, &partial)?;
+        }
+        let mut backups = Vec::new();
+        let replaced = replaces.filter(|replaces| replaces.exists());
+        for (original, backup) in std::iter::once((destination, backup))
+            .chain(replaced.map(|replaced| (replaced, with_suffix(replaced, "removed"))))
+        {
+            if !original.exists() {
+                continue;
+            }
+            journal.record(&Entry::BackedUp {
+                original: original.to_path_buf(),
+                backup: backup.clone(),
+            })?;
+            std::fs::rename(original, &backup)?;
+            backups.push(backup);
+        }
+        journal.record(&Entry::Renamed {
+            from: partial.clone(),
+            to: destination.to_path_buf(),
+        })?;
+        std::fs::rename(&partial, destination)?;
+        journal.record(&Entry::Installed(destination.to_path_buf()))?;
+        for backup in backups {
+            if backup.is_dir() {
+                std::fs::remove_dir_all(&backup)?;
+            } else {
+                std::fs::remove_file(&backup)?;
+            }
         }
         Ok(())
     }
 
     // gets the animation .qc files in the temp folder that were copied from the SCA folder
-    pub fn get_temp_folder_qcs(&self, selected_only: bool) -> Result<Vec<PathBuf>> {
+    pub fn get_temp_folder_qcs(
+        &self,
+        workspace: &Workspace,
+        selected_only: bool,
+    ) -> Result<Vec<PathBuf>> {
         let mut result = Vec::new();
         for class in self.get_selected_classes() {
             let animations = if selected_only {
                 class.get_selected_animations()
             } else {
-                class
-                    .animations
-                    .iter()
-                    .map(|animation| animation)
-                    .collect::<Vec<&Animation>>()
+                class.animations.iter().collect::<Vec<&Animation>>()
             };
             for animation in animations {
-                let anim_folder_dir = Self::exe_folder()?
-                    .join(TEMP_FOLDER_NAME)
-                    .join(&class.class)
+                let anim_folder_dir = workspace
+                    .source_folder()
+                    .join(class.class)
                     .join(&animation.name);
-                // assumes there will on be one qc file in each animation folder
-                let qc_file_dir = std::fs::read_dir(anim_folder_dir)?
-                    .filter_map(|res| res.ok())
-                    .filter(|res| res.path().extension() == Some(std::ffi::OsStr::new("qc")))
-                    .next()
-                    .ok_or(anyhow!("Can't find class qc"))?
-                    .path();
-                result.push(qc_file_dir);
+                result.push(Self::find_qc(anim_folder_dir)?);
             }
         }
         Ok(result)
     }
 
-    // adds $origin to top of each selected weapon's qc file
-    pub fn append_origins(&self) -> Result<()> {
+    // adds $origin to top of each selected weapon's qc file, qcs that already have one,
+    // themselves or in a file they $include, get handled as existing_origin says
+    pub fn append_origins(&self, workspace: &Workspace) -> Result<()> {
+        // included files can be shared between weapons, they get changed once, from what they
+        // were before, and then every weapon has to agree with that change
+        let mut changed: HashMap<PathBuf, (Qc, String)> = HashMap::new();
+        let source = std::fs::canonicalize(workspace.source_folder())?;
+        let shown = |file: &Path| {
+            file.strip_prefix(&source)
+                .unwrap_or(file)
+                .display()
+                .to_string()
+        };
         for class in self.get_selected_classes() {
             for animation in class.get_selected_animations() {
-                let anim_folder_dir = Self::exe_folder()?
-                    .join(TEMP_FOLDER_NAME)
-                    .join(&class.class)
+                let anim_folder_dir = workspace
+                    .source_folder()
+                    .join(class.class)
                     .join(&animation.name);
-                let qc_file_dir = std::fs::read_dir(anim_folder_dir)?
-                    .filter_map(|res| res.ok())
-                    .filter(|res| res.path().extension() == Some(std::ffi::OsStr::new("qc")))
-                    .next()
-                    .ok_or(anyhow!("Can't find class qc"))?
-                    .path();
-                let qc_file_content = std::fs::read_to_string(&qc_file_dir)?;
-                std::fs::write(
-                    &qc_file_dir,
-                    format!(
-                        "$origin {} {} {} {}\n{}",
-                        animation.origin.x,
-                        animation.origin.y,
-                        animation.origin.z,
-                        animation.origin.z_rot,
-                        qc_file_content,
-                    ),
-                )?;
+                let qc_file = Self::find_qc(anim_folder_dir)?;
+                let existing = find_origins(&qc_file)?;
+                if existing.is_empty() {
+                    let mut qc = Qc::read(&qc_file)?;
+                    qc.insert(
+                        0,
+                        Command::new(
+                            "$origin",
+                            animation
+                                .origin
+                                .values()
+                                .iter()
+                                .map(|value| Token::word(value.to_string()))
+                                .collect(),
+                        ),
+                    );
+                    qc.write(&qc_file)?;
+                    continue;
+                }
+                if self.existing_origin == ExistingOrigin::Abort {
+                    let (file, _) = &existing[0];
+                    bail!(
+                        "The {} qc of {} already has an $origin{}, choose {} or {} to generate anyway",
+                        animation.name,
+                        class.class,
+                        if *file == qc_file {
+                            String::new()
+                        } else {
+                            format!(" in {}", shown(file))
+                        },
+                        ExistingOrigin::Compose,
+                        ExistingOrigin::Replace
+                    );
+                }
+                let mut files: Vec<PathBuf> = Vec::new();
+                for (file, _) in &existing {
+                    if !files.contains(file) {
+                        files.push(file.clone());
+                    }
+                }
+                for file in files {
+                    let original = match changed.get(&file) {
+                        Some((original, _)) => original.clone(),
+                        None => Qc::read(&file)?,
+                    };
+                    let mut qc = original.clone();
+                    // every $origin gets changed, studiomdl uses the last one before each model part
+                    for (_, index) in existing.iter().filter(|(other, _)| *other == file) {
+                        let command = &mut qc.commands[*index];
+                        let origin = match self.existing_origin {
+                            ExistingOrigin::Compose => {
+                                Origin::from_command(command)?.compose(&animation.origin)
+                            }
+                            _ => animation.origin,
+                        };
+                        origin.write_to(command)?;
+                    }
+                    let text = qc.to_string();
+                    match changed.get(&file) {
+                        Some((_, written)) if *written == text => {}
+                        Some(_) => bail!(
+                            "{} has an $origin and is included by weapons with different offsets, \
+                             {} {} would need it changed differently",
+                            shown(&file),
+                            class.class,
+                            animation.name
+                        ),
+                        None => {
+                            std::fs::write(&file, &text)?;
+                            changed.insert(file, (original, text));
+                        }
+                    }
+                }
             }
         }
         Ok(())
     }
 
-    // copys SCA folder and its selected classes
-    pub fn copy_sca(&self) -> Result<()> {
+    // tilts and scales the smds and root $definebones of every selected weapon that needs it
+    pub fn transform_smds(&self, workspace: &Workspace) -> Result<()> {
+        for class in self.get_selected_classes() {
+            for animation in class.get_selected_animations() {
+                let transform = match animation.origin.smd_transform() {
+                    Some(transform) => transform,
+                    None => continue,
+                };
+                let anim_folder_dir = workspace
+                    .source_folder()
+                    .join(class.class)
+                    .join(&animation.name);
+                // assumes the smds of a weapon are all in its animation folder
+                for entry in std::fs::read_dir(&anim_folder_dir)? {
+                    let path = entry?.path();
+                    if path.extension() != Some(std::ffi::OsStr::new("smd")) {
+                        continue;
+                    }
+                    let content = std::fs::read_to_string(&path)?;
+                    let transformed = smd::transform(&content, &transform)
+                        .with_context(|| format!("Can't transform {}", path.display()))?;
+                    std::fs::write(&path, transformed)?;
+                }
+                let qc_file = Self::find_qc(&anim_folder_dir)?;
+                let mut qc = Qc::read(&qc_file)?;
+                if transform_root_bones(&mut qc, &transform)? {
+                    qc.write(&qc_file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // copys SCA folder and its selected classes into the workspace
+    pub fn copy_sca(&self, workspace: &Workspace) -> Result<()> {
         let sca_dir = Self::sca_folder()?;
-        let temp_folder = Self::exe_folder()?.join(TEMP_FOLDER_NAME);
+        let temp_folder = workspace.source_folder();
         std::fs::create_dir_all(&temp_folder)?;
         let selected_classes = self.get_selected_classes();
         let classes = selected_classes
@@ -335,7 +720,7 @@ impl Sca {
             .map(|classes| Path::new(&sca_dir).join(classes))
             .collect::<Vec<PathBuf>>();
         for class in &classes {
-            std::fs::create_dir_all(&temp_folder.join(class))?
+            std::fs::create_dir_all(temp_folder.join(class))?
         }
         for (class_dir, class) in class_dirs.iter().zip(classes.iter()) {
             for res in std::fs::read_dir(class_dir)? {
@@ -345,13 +730,13 @@ impl Sca {
                         std::fs::copy(
                             &entry,
                             // assumes there won't be an empty file name
-                            &temp_folder.join(&class).join(&entry.file_name().unwrap()),
+                            temp_folder.join(class).join(entry.file_name().unwrap()),
                         )?;
                     }
                     // assumes there are only two nested folders
                     false => {
                         let animation_folder =
-                            &temp_folder.join(&class).join(&entry.file_name().unwrap());
+                            &temp_folder.join(class).join(entry.file_name().unwrap());
                         std::fs::create_dir_all(animation_folder)?;
                         for res in std::fs::read_dir(&entry)? {
                             let entry = res?.path();
@@ -360,7 +745,7 @@ impl Sca {
                             }
                             std::fs::copy(
                                 &entry,
-                                &animation_folder.join(&entry.file_name().unwrap()),
+                                animation_folder.join(entry.file_name().unwrap()),
                             )?;
                         }
                     }
@@ -371,34 +756,152 @@ impl Sca {
     }
 
     pub fn reset_origin<T: AsRef<str>>(&mut self, class: &Class, name: T) {
-        for folder in self.folders.iter_mut() {
-            if folder.class == *class {
-                for animation in folder.animations.iter_mut() {
-                    if animation.name == name.as_ref() {
-                        animation.origin.reset();
-                        break;
+        self.clear_layer(&Level::Weapon(*class, name.as_ref().to_owned()));
+    }
+
+    // clears every layer, in one step of the history
+    pub fn reset_all_origin(&mut self) {
+        self.clear_all_layers();
+    }
+
+    // the $origin, pitch, roll and scale the compiled model of animation ends up with
+    pub fn compiled_origin(&self, animation: &Animation) -> Origin {
+        match animation.baseline {
+            _ if !animation.origin.is_modified() => animation.baseline.unwrap_or_default(),
+            Some(baseline) if self.existing_origin == ExistingOrigin::Compose => {
+                baseline.compose(&animation.origin)
+            }
+            _ => animation.origin,
+        }
+    }
+
+    // loads the settings a vpk was built with, everything else gets reset,
+    // returns what didn't fit this SCA
+    pub fn import_vpk<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<String>> {
+        let manifest = Manifest::from_vpk(path)?;
+        let mut warnings = Vec::new();
+        if manifest.sca_hash != manifest::sca_hash(Self::sca_folder()?)? {
+            warnings.push(
+                "The vpk was built from a different SCA, weapons might not sit the same".to_owned(),
+            );
+        }
+        warnings.extend(self.load_origins(manifest.existing_origin, &manifest.classes));
+        Ok(warnings)
+    }
+
+    // replaces every offset with the origins in classes, which become the weapons' own layers,
+    // for settings that don't have layers
+    pub fn load_origins(
+        &mut self,
+        existing_origin: ExistingOrigin,
+        classes: &[ClassAnimations],
+    ) -> Vec<String> {
+        let mut imported = Sca {
+            folders: classes.to_vec(),
+            ..Sca::default()
+        };
+        imported.layers_from_origins();
+        self.load_layers(existing_origin, &Layer::default(), &imported.folders)
+    }
+
+    // replaces every layer with the ones in global and classes, matched by class and folder name,
+    // the weapons with a layer this SCA doesn't have become orphans and get returned as warnings
+    pub fn load_layers(
+        &mut self,
+        existing_origin: ExistingOrigin,
+        global: &Layer,
+        classes: &[ClassAnimations],
+    ) -> Vec<String> {
+        let mut warnings = Vec::new();
+        self.reset_all_origin();
+        // the edits before belong to other offsets
+        self.history.clear();
+        self.orphans.clear();
+        self.existing_origin = existing_origin;
+        self.global = *global;
+        for class in classes {
+            if let Some(folder) = self
+                .folders
+                .iter_mut()
+                .find(|folder| folder.class == class.class)
+            {
+                folder.layer = class.layer;
+            }
+            for imported in &class.animations {
+                let animation = self
+                    .folders
+                    .iter_mut()
+                    .filter(|folder| folder.class == class.class)
+                    .flat_map(|folder| folder.animations.iter_mut())
+                    .find(|animation| animation.name == imported.name);
+                match animation {
+                    Some(animation) => animation.layer = imported.layer,
+                    None if !imported.layer.is_empty() => {
+                        warnings.push(format!(
+                            "{} {} isn't in this SCA",
+                            class.class, imported.name
+                        ));
+                        self.orphans.push(Orphan {
+                            class: class.class,
+                            name: imported.name.clone(),
+                            layer: imported.layer,
+                        });
                     }
+                    None => {}
                 }
-                break;
             }
         }
+        self.resolve();
+        warnings
     }
 
-    pub fn reset_all_origin(&mut self) {
-        self.folders.iter_mut().for_each(|folder| {
-            folder
-                .animations
-                .iter_mut()
-                .for_each(|animation| animation.origin.reset())
-        });
+    // gives animation, of the same class, the offsets of the orphan at index
+    pub fn remap_orphan(&mut self, index: usize, animation: &str) -> Result<()> {
+        let orphan = self
+            .orphans
+            .get(index)
+            .ok_or_else(|| anyhow!("There's no orphaned setting {}", index))?;
+        let (class, layer) = (orphan.class, orphan.layer);
+        let target = self
+            .folders
+            .iter_mut()
+            .filter(|folder| folder.class == class)
+            .flat_map(|folder| folder.animations.iter_mut())
+            .find(|target| target.name == animation)
+            .ok_or_else(|| anyhow!("{} {} isn't in this SCA", class, animation))?;
+        target.layer = layer;
+        self.orphans.remove(index);
+        self.resolve();
+        Ok(())
+    }
+
+    pub fn drop_orphan(&mut self, index: usize) {
+        if index < self.orphans.len() {
+            self.orphans.remove(index);
+        }
     }
 
-    pub fn apply_to_all_origin(&mut self, origin: &Origin) {
-        for folder in self.folders.iter_mut() {
-            for animation in folder.animations.iter_mut() {
-                animation.origin = origin.clone();
+    // the folders with the orphans back in their classes, what gets saved so orphans aren't
+    // lost before somebody decides about them
+    pub fn settings(&self) -> Vec<ClassAnimations> {
+        let mut classes = self.resolved().folders;
+        for orphan in &self.orphans {
+            let animation = Animation {
+                name: orphan.name.clone(),
+                origin: Origin::default(),
+                layer: orphan.layer,
+                baseline: None,
+            };
+            match classes.iter_mut().find(|class| class.class == orphan.class) {
+                Some(class) => class.animations.push(animation),
+                None => classes.push(ClassAnimations {
+                    class: orphan.class,
+                    layer: Layer::default(),
+                    animations: vec![animation],
+                }),
             }
         }
+        classes
     }
 
     pub fn check_folders() -> Result<()> {
@@ -407,8 +910,8 @@ impl Sca {
             bail!("Can't find SCA folder");
         } else {
             for class in CLASSES {
-                if !sca_dir.join(&class).is_dir() {
-                    bail!("Can't find {} folder", class.to_string());
+                if !sca_dir.join(class).is_dir() {
+                    bail!("Can't find {} folder", class);
                 }
             }
         }
@@ -422,29 +925,143 @@ impl Sca {
     }
 
     pub fn studiomdl_exe() -> Result<PathBuf> {
-        let mut exe_folder = Self::exe_folder()?;
-        // assumes program folder is in custom folder
-        (0..3).for_each(|_| {
-            exe_folder.pop();
-        });
-        let smdl = exe_folder.join("bin").join("studiomdl.exe");
-        if !smdl.exists() {
-            bail!("Can't find studiomdl.exe, program's folder has to be in your custom folder!");
-        }
-        Ok(smdl)
+        discovery::studiomdl_exe()
     }
 
     pub fn tf_folder() -> Result<PathBuf> {
-        let mut tf_folder = Self::exe_folder()?;
-        tf_folder.pop();
-        tf_folder.pop();
-        if !tf_folder.exists() {
-            bail!("Can't find tf folder, program's folder has to be in your custom folder!")
+        discovery::tf_folder()
+    }
+
+    // where generate puts the vpk
+    pub fn installed_vpk() -> Result<PathBuf> {
+        Ok(Self::tf_folder()?
+            .join("custom")
+            .join(format!("{}.vpk", workspace::ADDON_NAME)))
+    }
+
+    // where generate puts the loose files instead
+    pub fn installed_loose() -> Result<PathBuf> {
+        Ok(Self::tf_folder()?
+            .join("custom")
+            .join(workspace::ADDON_NAME))
+    }
+
+    pub fn sca_folder() -> Result<PathBuf> {
+        discovery::sca_folder()
+    }
+}
+
+// scout with Pistol and Bat and soldier with Shotgun, without any offsets, for tests of the
+// layers that don't need an SCA folder
+#[cfg(test)]
+pub fn test_sca() -> Sca {
+    let class = |class, names: &[&str]| ClassAnimations {
+        class,
+        layer: Layer::default(),
+        animations: names
+            .iter()
+            .map(|name| Animation {
+                name: (*name).to_owned(),
+                ..Animation::default()
+            })
+            .collect(),
+    };
+    Sca {
+        folders: vec![
+            class(Class::Scout, &["Pistol", "Bat"]),
+            class(Class::Soldier, &["Shotgun"]),
+        ],
+        ..Sca::default()
+    }
+}
+
+// path with .suffix added to its file name, unlike with_extension it keeps the extension
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// every $origin of the qc and the files it $includes, in the order studiomdl reads them,
+// as the file and the index of the command in it
+fn find_origins(qc_file: &Path) -> Result<Vec<(PathBuf, usize)>> {
+    let mut origins = Vec::new();
+    collect_origins(qc_file, &mut vec![qc_file.to_path_buf()], &mut origins)?;
+    Ok(origins)
+}
+
+fn collect_origins(
+    file: &Path,
+    stack: &mut Vec<PathBuf>,
+    origins: &mut Vec<(PathBuf, usize)>,
+) -> Result<()> {
+    let qc = Qc::read(file)?;
+    for (index, command) in qc.commands.iter().enumerate() {
+        if command.is("$origin") {
+            origins.push((file.to_path_buf(), index));
+        } else if let Ok(Directive::Include(included)) = command.directive() {
+            // relative to the file with the $include, like the graph resolves them, and
+            // canonical so weapons that share the file agree on its path
+            let included = file.parent().unwrap_or(Path::new("")).join(included);
+            let included = std::fs::canonicalize(&included).with_context(|| {
+                format!(
+                    "{} includes {}, which doesn't exist",
+                    file.display(),
+                    included.display()
+                )
+            })?;
+            if stack.contains(&included) {
+                bail!(
+                    "{} includes itself through {}",
+                    included.display(),
+                    file.display()
+                );
+            }
+            stack.push(included.clone());
+            collect_origins(&included, stack, origins)?;
+            stack.pop();
         }
-        Ok(tf_folder)
     }
+    Ok(())
+}
 
-    fn sca_folder() -> Result<PathBuf> {
-        Ok(Self::exe_folder()?.join("SCA"))
+// $definebone angles are degrees in pitch yaw roll order
+fn define_bone_matrix(rotation: [f32; 3]) -> smd::Matrix {
+    let [pitch, yaw, roll] = rotation.map(f32::to_radians);
+    smd::Matrix::from_euler(roll, pitch, yaw)
+}
+
+// root bones have "" as parent, returns whether anything changed
+fn transform_root_bones(qc: &mut Qc, transform: &smd::Transform) -> Result<bool> {
+    let mut changed = false;
+    for command in qc.commands.iter_mut() {
+        let (position, rotation) = match command.directive()? {
+            Directive::DefineBone {
+                parent: "",
+                position,
+                rotation,
+                ..
+            } => (position, rotation),
+            Directive::DefineBone { position, .. } => {
+                let position = transform.child_bone(position);
+                for (index, value) in position.iter().enumerate() {
+                    command.set_value(2 + index, smd::number(*value))?;
+                }
+                changed = true;
+                continue;
+            }
+            _ => continue,
+        };
+        let (position, matrix) = transform.root_bone(position, &define_bone_matrix(rotation));
+        let [roll, pitch, yaw] = matrix.to_euler().map(f32::to_degrees);
+        for (index, value) in [position[0], position[1], position[2], pitch, yaw, roll]
+            .iter()
+            .enumerate()
+        {
+            command.set_value(2 + index, smd::number(*value))?;
+        }
+        changed = true;
     }
+    Ok(changed)
 }
@@ -0,0 +1,417 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::discovery;
+
+// selects the runner, e.g. "native", "wine", "wine:/usr/bin/wine64" or "proton:/path/to/proton"
+pub const RUNNER_ENV: &str = "V_CUSTOMIZER_RUNNER";
+// the prefix proton runs in and the steam install it belongs to, `proton run` needs both
+const COMPAT_DATA_ENV: &str = "STEAM_COMPAT_DATA_PATH";
+const COMPAT_CLIENT_ENV: &str = "STEAM_COMPAT_CLIENT_INSTALL_PATH";
+
+// DETACHED_PROCESS, keeps studiomdl.exe from opening a console window
+#[cfg(windows)]
+const CREATION_FLAGS: u32 = 0x00000008;
+// how often a running tool gets checked for cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+// how studiomdl.exe gets launched
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Runner {
+    // runs the binary directly, either on windows or a native build of the tool
+    Native,
+    // runs the .exe through wine, holds the wine binary
+    Wine(PathBuf),
+    // runs the .exe through proton's `run` verb, holds the proton script
+    Proton(PathBuf),
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Runner::Native
+        } else {
+            Runner::Wine(PathBuf::from("wine"))
+        }
+    }
+}
+
+impl std::str::FromStr for Runner {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, path) = match s.find(':') {
+            Some(i) => (&s[..i], Some(PathBuf::from(&s[i + 1..]))),
+            None => (s, None),
+        };
+        match kind.to_lowercase().as_str() {
+            "native" => Ok(Runner::Native),
+            "wine" => Ok(Runner::Wine(path.unwrap_or_else(|| PathBuf::from("wine")))),
+            "proton" => Ok(Runner::Proton(path.ok_or_else(|| {
+                anyhow!("proton runner needs a path, e.g. proton:/path/to/proton")
+            })?)),
+            _ => bail!("Unknown runner {}, expected native, wine or proton", s),
+        }
+    }
+}
+
+impl Runner {
+    fn translates_paths(&self) -> bool {
+        !matches!(self, Runner::Native)
+    }
+}
+
+// a tool executable together with the runner it is launched through
+pub struct Tool {
+    exe: PathBuf,
+    runner: Runner,
+}
+
+impl Tool {
+    pub fn new<P: AsRef<Path>>(exe: P, runner: Runner) -> Self {
+        let exe = exe.as_ref().to_path_buf();
+        // windows runs everything natively, and native builds of the tools don't need a runner
+        let runner = if cfg!(windows) || !is_windows_exe(&exe) {
+            Runner::Native
+        } else {
+            runner
+        };
+        Tool { exe, runner }
+    }
+
+    pub fn exe(&self) -> &Path {
+        &self.exe
+    }
+
+    pub fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    // creates the command, arguments still have to be added by the caller
+    pub fn command(&self) -> Result<Command> {
+        let mut command = match &self.runner {
+            Runner::Native => Command::new(&self.exe),
+            Runner::Wine(wine) => {
+                let mut command = Command::new(wine);
+                command.arg(&self.exe);
+                command
+            }
+            Runner::Proton(proton) => {
+                let mut command = Command::new(proton);
+                command.arg("run").arg(&self.exe);
+                proton_env(&mut command, proton)?;
+                command
+            }
+        };
+        if self.runner.translates_paths() && std::env::var_os("WINEDEBUG").is_none() {
+            command.env("WINEDEBUG", "-all");
+        }
+        Self::hide_window(&mut command);
+        Ok(command)
+    }
+
+    // converts a path argument into one the tool understands, wine maps / to the Z: drive
+    pub fn path_arg<P: AsRef<Path>>(&self, path: P) -> OsString {
+        let path = path.as_ref();
+        if !self.runner.translates_paths() || !path.is_absolute() {
+            return path.as_os_str().to_owned();
+        }
+        to_wine_path(path)
+    }
+
+    #[cfg(windows)]
+    fn hide_window(command: &mut Command) {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(CREATION_FLAGS);
+    }
+
+    #[cfg(not(windows))]
+    fn hide_window(_command: &mut Command) {}
+}
+
+// what's set in the environment already wins, the prefix gets created if it isn't there yet
+fn proton_env(command: &mut Command, proton: &Path) -> Result<()> {
+    let is_set = |var| std::env::var_os(var).filter(|v| !v.is_empty()).is_some();
+    if !is_set(COMPAT_DATA_ENV) {
+        let prefix = discovery::proton_prefix()?;
+        std::fs::create_dir_all(&prefix)
+            .with_context(|| format!("Can't create the proton prefix {}", prefix.display()))?;
+        command.env(COMPAT_DATA_ENV, prefix);
+    }
+    if !is_set(COMPAT_CLIENT_ENV) {
+        let steam = steam_for(proton, &discovery::steam_roots()).ok_or_else(|| {
+            anyhow!(
+                "Proton needs a Steam install and none was found, set {} to the Steam folder",
+                COMPAT_CLIENT_ENV
+            )
+        })?;
+        command.env(COMPAT_CLIENT_ENV, steam);
+    }
+    Ok(())
+}
+
+// the steam install proton is in, or else the first one there is
+fn steam_for(proton: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    let proton = proton
+        .canonicalize()
+        .unwrap_or_else(|_| proton.to_path_buf());
+    roots
+        .iter()
+        .find(|root| {
+            let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+            proton.starts_with(root)
+        })
+        .or_else(|| roots.first())
+        .cloned()
+}
+
+fn is_windows_exe(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("exe"))
+        .unwrap_or(false)
+}
+
+// /home/user/tf -> Z:\home\user\tf
+pub fn to_wine_path<P: AsRef<Path>>(path: P) -> OsString {
+    let mut result = OsString::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                    result.push(format!("{}:", letter as char))
+                }
+                _ => result.push(prefix.as_os_str()),
+            },
+            Component::RootDir => {
+                if result.is_empty() {
+                    result.push("Z:");
+                }
+            }
+            Component::CurDir => continue,
+            Component::ParentDir => {
+                result.push("\\");
+                result.push("..");
+            }
+            Component::Normal(part) => {
+                result.push("\\");
+                result.push(part);
+            }
+        }
+    }
+    if result == OsStr::new("Z:") {
+        result.push("\\");
+    }
+    result
+}
+
+// shared flag that stops a running pipeline, clones share the flag
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // a token that also gets cancelled with this one, but cancelling it leaves this one alone
+    pub fn child(&self) -> Self {
+        CancelToken {
+            flag: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.is_cancelled())
+    }
+
+    // errors with Cancelled once cancel got called
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+// the error of a cancelled run, check for it with error.is::<Cancelled>()
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// like Command::output, but kills the process and everything it started once cancelled
+pub fn output(command: &mut Command, cancel: &CancelToken) -> Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    own_process_group(command);
+    let mut child = command.spawn()?;
+    // both pipes have to be drained while waiting, a full pipe blocks the tool
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancel.is_cancelled() {
+            kill_tree(&mut child);
+            return Err(Cancelled.into());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+// wine and proton start the actual tool as a child, so the whole group has to go
+#[cfg(unix)]
+fn own_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_command: &mut Command) {}
+
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) {
+    let _ = Command::new("kill")
+        .arg("-KILL")
+        .arg(format!("-{}", child.id()))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut Child) {
+    let mut taskkill = Command::new("taskkill");
+    taskkill
+        .args(["/T", "/F", "/PID"])
+        .arg(child.id().to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    Tool::hide_window(&mut taskkill);
+    let _ = taskkill.status();
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wine_paths_go_on_z() {
+        assert_eq!(
+            to_wine_path("/home/user/tf"),
+            OsString::from("Z:\\home\\user\\tf")
+        );
+        assert_eq!(to_wine_path("/"), OsString::from("Z:\\"));
+        assert_eq!(
+            to_wine_path("/games/./tf/../tf/a b.qc"),
+            OsString::from("Z:\\games\\tf\\..\\tf\\a b.qc")
+        );
+    }
+
+    #[test]
+    fn parses_runners() {
+        assert_eq!("native".parse::<Runner>().unwrap(), Runner::Native);
+        assert_eq!("Native".parse::<Runner>().unwrap(), Runner::Native);
+        assert_eq!(
+            "wine".parse::<Runner>().unwrap(),
+            Runner::Wine(PathBuf::from("wine"))
+        );
+        assert_eq!(
+            "wine:/usr/bin/wine64".parse::<Runner>().unwrap(),
+            Runner::Wine(PathBuf::from("/usr/bin/wine64"))
+        );
+        assert_eq!(
+            "proton:/steam/Proton 8.0/proton".parse::<Runner>().unwrap(),
+            Runner::Proton(PathBuf::from("/steam/Proton 8.0/proton"))
+        );
+        assert!("proton".parse::<Runner>().is_err());
+        assert!("".parse::<Runner>().is_err());
+        assert!("dosbox:/usr/bin/dosbox".parse::<Runner>().is_err());
+    }
+
+    #[test]
+    fn proton_uses_its_own_steam() {
+        let roots = [
+            PathBuf::from("/home/a/.steam/steam"),
+            PathBuf::from("/games/Steam"),
+        ];
+        assert_eq!(
+            steam_for(
+                Path::new("/games/Steam/steamapps/common/Proton 8.0/proton"),
+                &roots
+            ),
+            Some(PathBuf::from("/games/Steam"))
+        );
+        assert_eq!(
+            steam_for(Path::new("/opt/proton-ge/proton"), &roots),
+            Some(PathBuf::from("/home/a/.steam/steam"))
+        );
+        assert_eq!(steam_for(Path::new("/opt/proton-ge/proton"), &[]), None);
+    }
+
+    #[test]
+    fn only_exes_get_a_runner() {
+        let wine = Runner::Wine(PathBuf::from("wine"));
+        let tool = Tool::new("/tools/studiomdl", wine.clone());
+        assert_eq!(*tool.runner(), Runner::Native);
+        assert_eq!(tool.path_arg("/tf/a.qc"), OsString::from("/tf/a.qc"));
+        let tool = Tool::new("/tools/studiomdl.EXE", wine.clone());
+        if cfg!(windows) {
+            assert_eq!(*tool.runner(), Runner::Native);
+        } else {
+            assert_eq!(*tool.runner(), wine);
+            assert_eq!(tool.path_arg("/tf/a.qc"), OsString::from("Z:\\tf\\a.qc"));
+            // relative paths mean the same to wine
+            assert_eq!(tool.path_arg("a.qc"), OsString::from("a.qc"));
+        }
+    }
+}
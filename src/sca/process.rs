@@ -0,0 +1,76 @@
+// something that identifies the process with pid, None if there is none, comparing it tells a
+// process apart from a later one that got the same pid
+#[cfg(target_os = "linux")]
+pub fn start(pid: u32) -> Option<String> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the name in parentheses can have spaces, the start time is the 20th field after it
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19).map(str::to_owned)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn start(pid: u32) -> Option<String> {
+    use std::process::{Command, Stdio};
+    let output = Command::new("ps")
+        .args(["-o", "lstart=", "-p"])
+        .arg(pid.to_string())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let start = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    Some(start).filter(|start| output.status.success() && !start.is_empty())
+}
+
+#[cfg(windows)]
+pub fn start(pid: u32) -> Option<String> {
+    use std::ffi::c_void;
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn OpenProcess(access: u32, inherit: i32, process_id: u32) -> *mut c_void;
+        fn GetExitCodeProcess(process: *mut c_void, code: *mut u32) -> i32;
+        fn GetProcessTimes(
+            process: *mut c_void,
+            creation: *mut FileTime,
+            exit: *mut FileTime,
+            kernel: *mut FileTime,
+            user: *mut FileTime,
+        ) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const STILL_ACTIVE: u32 = 259;
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return None;
+        }
+        let mut code = 0;
+        let (mut creation, mut exit, mut kernel, mut user) = Default::default();
+        let alive = GetExitCodeProcess(process, &mut code) != 0 && code == STILL_ACTIVE;
+        let timed = GetProcessTimes(process, &mut creation, &mut exit, &mut kernel, &mut user) != 0;
+        CloseHandle(process);
+        let creation: FileTime = creation;
+        Some(((creation.high as u64) << 32 | creation.low as u64).to_string())
+            .filter(|_| alive && timed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_processes_apart() {
+        let current = start(std::process::id());
+        assert!(current.is_some());
+        assert_eq!(start(std::process::id()), current);
+        // above what any system hands out
+        assert_eq!(start(u32::MAX - 1), None);
+    }
+}
@@ -0,0 +1,451 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::layers::Layer;
+use super::{discovery, ClassAnimations, ExistingOrigin, Sca};
+use crate::fs;
+
+// bump when the format changes, and teach migrate how to get there from the version before
+pub const PROFILE_VERSION: u32 = 1;
+// the profile there is when nobody made one yet
+pub const DEFAULT_PROFILE_NAME: &str = "Default";
+const PROFILES_FOLDER_NAME: &str = "profiles";
+const PROFILE_EXTENSION: &str = "json";
+// holds the name of the profile the gui and the command line work on
+const ACTIVE_FILE_NAME: &str = "active";
+// characters windows doesn't allow in file names
+const FORBIDDEN: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+// devices windows won't make a file of, with any extension
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// the offsets of every weapon, in a file of their own so they can be switched between,
+// shared and edited without the gui
+#[derive(Deserialize, Serialize)]
+pub struct Profile {
+    pub version: u32,
+    pub name: String,
+    // the v_customizer that last saved it
+    pub tool_version: String,
+    // seconds since the unix epoch
+    pub created: u64,
+    pub modified: u64,
+    pub existing_origin: ExistingOrigin,
+    // the offsets every weapon gets, the classes and animations have their own layers
+    pub global: Layer,
+    pub classes: Vec<ClassAnimations>,
+}
+
+impl Profile {
+    pub fn new(name: &str, sca: &Sca) -> Self {
+        let now = now();
+        Profile {
+            version: PROFILE_VERSION,
+            name: name.to_owned(),
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            created: now,
+            modified: now,
+            existing_origin: sca.existing_origin,
+            global: sca.global,
+            classes: sca.settings(),
+        }
+    }
+
+    // reads a profile of any version up to PROFILE_VERSION
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+        let broken = || format!("{} isn't a v_customizer profile", path.display());
+        let json = serde_json::from_slice(&json).with_context(broken)?;
+        let json = migrate(json, path)?;
+        serde_json::from_value(json).with_context(broken)
+    }
+
+    // the old file stays until the new one is complete
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(folder) = path
+            .parent()
+            .filter(|folder| !folder.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(folder)?;
+        }
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Can't write {}", path.display()))?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    // puts the offsets into sca, returns what didn't fit
+    pub fn apply(&self, sca: &mut Sca) -> Vec<String> {
+        sca.load_layers(self.existing_origin, &self.global, &self.classes)
+    }
+}
+
+// brings json from whatever version it was saved with up to PROFILE_VERSION, one step at a time
+fn migrate(json: Value, path: &Path) -> Result<Value> {
+    let broken = || anyhow!("{} isn't a v_customizer profile", path.display());
+    let version = json
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or_else(broken)?;
+    if version > PROFILE_VERSION as u64 {
+        bail!(
+            "{} was saved by a newer v_customizer (profile version {}), update to load it",
+            path.display(),
+            version
+        );
+    }
+    // nothing older than the first version to bring up yet
+    Ok(json)
+}
+
+// the named profiles in the config folder, every one is <name>.json
+pub struct Profiles {
+    folder: PathBuf,
+}
+
+impl Profiles {
+    pub fn open() -> Result<Self> {
+        Self::at(discovery::config_dir()?.join(PROFILES_FOLDER_NAME))
+    }
+
+    fn at(folder: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&folder)
+            .with_context(|| format!("Can't create the profiles folder {}", folder.display()))?;
+        Ok(Profiles { folder })
+    }
+
+    // checks the name first so it can't lead out of the profiles folder
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        check_name(name)?;
+        Ok(self.folder.join(format!("{}.{}", name, PROFILE_EXTENSION)))
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.path(name).is_ok_and(|path| path.is_file())
+    }
+
+    // every profile, sorted by name
+    pub fn names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.folder)? {
+            let path = entry?.path();
+            if path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == PROFILE_EXTENSION)
+            {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+        names.sort_by_key(|name| name.to_lowercase());
+        Ok(names)
+    }
+
+    pub fn load(&self, name: &str) -> Result<Profile> {
+        if !self.exists(name) {
+            bail!("There's no profile called {}", name);
+        }
+        Profile::read(self.path(name)?)
+    }
+
+    // writes the offsets of sca to the profile, which doesn't have to exist yet
+    pub fn save(&self, name: &str, sca: &Sca) -> Result<()> {
+        let path = self.path(name)?;
+        let mut profile = Profile::new(name, sca);
+        if let Ok(previous) = self.load(name) {
+            profile.created = previous.created;
+        }
+        profile.write(path)
+    }
+
+    // a new profile with the offsets of sca
+    pub fn create(&self, name: &str, sca: &Sca) -> Result<()> {
+        self.check_free(name)?;
+        self.save(name, sca)
+    }
+
+    pub fn duplicate(&self, name: &str, copy: &str) -> Result<()> {
+        self.check_free(copy)?;
+        let mut profile = self.load(name)?;
+        let now = now();
+        profile.name = copy.to_owned();
+        profile.created = now;
+        profile.modified = now;
+        profile.write(self.path(copy)?)
+    }
+
+    // name if there's no such profile yet, otherwise the first of "name 2", "name 3", ... that's
+    // free, characters a profile name can't have become _ and windows' devices get one too
+    pub fn free_name(&self, name: &str) -> String {
+        let name = name.replace(FORBIDDEN, "_");
+        let name = name.trim_matches(|c: char| c == '.' || c.is_whitespace());
+        let name = if name.is_empty() {
+            DEFAULT_PROFILE_NAME.to_owned()
+        } else if reserved(name) {
+            let device = name.find('.').unwrap_or(name.len());
+            format!("{}_{}", &name[..device], &name[device..])
+        } else {
+            name.to_owned()
+        };
+        (1..)
+            .map(|number| match number {
+                1 => name.to_owned(),
+                _ => format!("{} {}", name, number),
+            })
+            .find(|candidate| !self.exists(candidate))
+            .unwrap()
+    }
+
+    // the active profile stays active under its new name
+    pub fn rename(&self, name: &str, new_name: &str) -> Result<()> {
+        let (path, new_path) = (self.path(name)?, self.path(new_name)?);
+        // with only the case changing it can still be the same file, on windows for one
+        if !fs::same_file(&path, &new_path) {
+            self.check_free(new_name)?;
+        }
+        let mut profile = self.load(name)?;
+        let active = self.active()? == name;
+        std::fs::rename(&path, &new_path)?;
+        profile.name = new_name.to_owned();
+        profile.write(&new_path)?;
+        if active {
+            self.set_active(new_name)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        std::fs::remove_file(self.path(name)?)
+            .with_context(|| format!("Can't delete the profile {}", name))
+    }
+
+    // the profile last switched to, if that's gone the default one or else the first one there is
+    pub fn active(&self) -> Result<String> {
+        let saved = std::fs::read_to_string(self.folder.join(ACTIVE_FILE_NAME)).ok();
+        if let Some(name) = saved.map(|name| name.trim().to_owned()) {
+            if self.exists(&name) {
+                return Ok(name);
+            }
+        }
+        if self.exists(DEFAULT_PROFILE_NAME) {
+            return Ok(DEFAULT_PROFILE_NAME.to_owned());
+        }
+        Ok(self
+            .names()?
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| DEFAULT_PROFILE_NAME.to_owned()))
+    }
+
+    pub fn set_active(&self, name: &str) -> Result<()> {
+        if !self.exists(name) {
+            bail!("There's no profile called {}", name);
+        }
+        std::fs::write(self.folder.join(ACTIVE_FILE_NAME), name)?;
+        Ok(())
+    }
+
+    fn check_free(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        if self.exists(name) {
+            bail!("There already is a profile called {}", name);
+        }
+        Ok(())
+    }
+}
+
+// names end up as file names
+fn check_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        bail!("A profile needs a name");
+    }
+    if name != name.trim()
+        || name.starts_with('.')
+        || name.ends_with('.')
+        || name.contains(FORBIDDEN)
+    {
+        bail!(
+            "{} can't be a profile name, it can't start or end with a dot or a space or have any of {}",
+            name,
+            FORBIDDEN.iter().collect::<String>()
+        );
+    }
+    if reserved(name) {
+        bail!(
+            "{} can't be a profile name, windows keeps it for a device",
+            name
+        );
+    }
+    Ok(())
+}
+
+// windows ignores case and everything from the first dot on when it looks for devices
+fn reserved(name: &str) -> bool {
+    let device = name.split('.').next().unwrap_or(name).trim_end();
+    RESERVED
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device))
+}
+
+fn now() -> u64 {
+    seconds(SystemTime::now())
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::layers::{Field, Level};
+    use super::super::test_sca;
+    use super::*;
+    use crate::fs::test_folder;
+
+    fn profiles(name: &str) -> Profiles {
+        Profiles::at(test_folder(name)).unwrap()
+    }
+
+    // the global x of the profile
+    fn x(profiles: &Profiles, name: &str) -> Option<f32> {
+        profiles.load(name).unwrap().global.x
+    }
+
+    #[test]
+    fn create_and_duplicate() {
+        let profiles = profiles("profile_create");
+        let mut sca = test_sca();
+        sca.set_field(&Level::Global, Field::X, Some(3.0));
+        profiles.create("Mine", &sca).unwrap();
+        assert!(profiles.create("Mine", &sca).is_err());
+        let profile = profiles.load("Mine").unwrap();
+        assert_eq!(
+            (profile.version, profile.name.as_str()),
+            (PROFILE_VERSION, "Mine")
+        );
+        assert_eq!(profile.global.x, Some(3.0));
+
+        profiles.duplicate("Mine", "copy").unwrap();
+        assert_eq!(x(&profiles, "copy"), Some(3.0));
+        assert_eq!(profiles.load("copy").unwrap().name, "copy");
+        assert!(profiles.duplicate("Mine", "copy").is_err());
+        assert!(profiles.duplicate("Nothing", "Other").is_err());
+        assert_eq!(profiles.names().unwrap(), ["copy", "Mine"]);
+
+        // saving again keeps when it was made
+        let created = profiles.load("Mine").unwrap().created;
+        sca.set_field(&Level::Global, Field::X, Some(4.0));
+        profiles.save("Mine", &sca).unwrap();
+        assert_eq!(x(&profiles, "Mine"), Some(4.0));
+        assert_eq!(profiles.load("Mine").unwrap().created, created);
+        let mut loaded = test_sca();
+        assert!(profiles.load("Mine").unwrap().apply(&mut loaded).is_empty());
+        assert_eq!(loaded.global.x, Some(4.0));
+    }
+
+    #[test]
+    fn rename_and_delete() {
+        let profiles = profiles("profile_rename");
+        let sca = test_sca();
+        profiles.create("One", &sca).unwrap();
+        profiles.create("Two", &sca).unwrap();
+        profiles.set_active("One").unwrap();
+        assert!(profiles.rename("One", "Two").is_err());
+        assert!(profiles.rename("Three", "Four").is_err());
+
+        profiles.rename("One", "First").unwrap();
+        assert_eq!(profiles.names().unwrap(), ["First", "Two"]);
+        assert_eq!(profiles.load("First").unwrap().name, "First");
+        assert_eq!(profiles.active().unwrap(), "First");
+        // only the case, which has to work where that's the same file too
+        profiles.rename("First", "FIRST").unwrap();
+        assert_eq!(profiles.names().unwrap(), ["FIRST", "Two"]);
+        assert_eq!(profiles.active().unwrap(), "FIRST");
+        // renaming another profile doesn't change which one is active
+        profiles.rename("Two", "Second").unwrap();
+        assert_eq!(profiles.active().unwrap(), "FIRST");
+
+        profiles.delete("Second").unwrap();
+        assert_eq!(profiles.names().unwrap(), ["FIRST"]);
+        assert!(profiles.delete("Second").is_err());
+        assert!(profiles.load("Second").is_err());
+    }
+
+    #[test]
+    fn active_falls_back() {
+        let profiles = profiles("profile_active");
+        let sca = test_sca();
+        assert_eq!(profiles.active().unwrap(), DEFAULT_PROFILE_NAME);
+        assert!(profiles.set_active("Mine").is_err());
+        profiles.create("b", &sca).unwrap();
+        profiles.create("A", &sca).unwrap();
+        assert_eq!(profiles.active().unwrap(), "A");
+        profiles.create(DEFAULT_PROFILE_NAME, &sca).unwrap();
+        assert_eq!(profiles.active().unwrap(), DEFAULT_PROFILE_NAME);
+        profiles.set_active("b").unwrap();
+        assert_eq!(profiles.active().unwrap(), "b");
+        profiles.delete("b").unwrap();
+        assert_eq!(profiles.active().unwrap(), DEFAULT_PROFILE_NAME);
+    }
+
+    #[test]
+    fn names_windows_takes() {
+        for name in &["Mine", "my profile", "v2.1", "CONSOLE", "com10", "_nul"] {
+            assert!(check_name(name).is_ok(), "{}", name);
+        }
+        for name in &[
+            "", " ", " Mine", "Mine ", ".Mine", "Mine.", "a/b", "..\\up", "a:b", "CON", "con",
+            "Nul.json", "aux .txt", "COM1", "lpt9",
+        ] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+        let profiles = profiles("profile_names");
+        assert!(profiles.path("../outside").is_err());
+        assert!(profiles.create("nul", &test_sca()).is_err());
+    }
+
+    #[test]
+    fn free_names() {
+        let profiles = profiles("profile_free");
+        let sca = test_sca();
+        assert_eq!(profiles.free_name("Mine"), "Mine");
+        profiles.create("Mine", &sca).unwrap();
+        profiles.create("Mine 2", &sca).unwrap();
+        assert_eq!(profiles.free_name("Mine"), "Mine 3");
+        assert_eq!(profiles.free_name(" a/b:c. "), "a_b_c");
+        assert_eq!(profiles.free_name(".."), DEFAULT_PROFILE_NAME);
+        assert_eq!(profiles.free_name("con"), "con_");
+        assert_eq!(profiles.free_name("aux.vpk"), "aux_.vpk");
+        for name in &[" a/b:c. ", "..", "con", "aux.vpk", "pak01_dir.vpk"] {
+            assert!(check_name(&profiles.free_name(name)).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn newer_profiles_are_refused() {
+        let folder = test_folder("profile_newer");
+        let path = folder.join("Mine.json");
+        let mut profile = Profile::new("Mine", &test_sca());
+        profile.write(&path).unwrap();
+        assert!(Profile::read(&path).is_ok());
+        profile.version = PROFILE_VERSION + 1;
+        profile.write(&path).unwrap();
+        let error = Profile::read(&path).err().unwrap().to_string();
+        assert!(error.contains("newer"), "{}", error);
+        std::fs::write(&path, "{}").unwrap();
+        assert!(Profile::read(&path).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::path::{Path, PathBuf};
+
+use super::install;
+use super::layers::Layer;
+use super::{define_bone_matrix, ExistingOrigin, Origin, Sca};
+use crate::fs;
+use crate::mdl::Mdl;
+use crate::qc::{Directive, Qc};
+use crate::smd::{self, Matrix};
+use crate::vpk::VpkReader;
+
+// studiomdl turns every model this far around z, on top of the $origin rotation
+const STUDIOMDL_YAW: f32 = 90.0;
+
+// compiled models, from a vpk or a folder with models/ in it
+pub enum Pack {
+    Vpk(VpkReader),
+    Folder(PathBuf),
+}
+
+impl Pack {
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            Ok(Pack::Folder(path.to_path_buf()))
+        } else {
+            Ok(Pack::Vpk(VpkReader::open(path)?))
+        }
+    }
+
+    // every file in the pack, with / between folders
+    pub fn paths(&self) -> Result<Vec<String>> {
+        match self {
+            Pack::Vpk(vpk) => Ok(vpk
+                .entries()
+                .iter()
+                .map(|entry| entry.path.clone())
+                .collect()),
+            Pack::Folder(folder) => Ok(fs::files(folder)?
+                .iter()
+                .map(|path| fs::relative_path(path, folder))
+                .collect()),
+        }
+    }
+
+    // None if the pack doesn't have the file
+    pub fn read(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Pack::Vpk(vpk) => vpk.find(path).map(|entry| vpk.read(entry)).transpose(),
+            Pack::Folder(folder) => {
+                let file = folder.join(path);
+                if file.is_file() {
+                    Ok(Some(std::fs::read(file)?))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+// works out the origins a pack of compiled viewmodels was built with, by comparing its root bones
+// to the ones in the SCA, returns sca with those origins and a note for every weapon that failed,
+// fails if not a single weapon could be recovered
+pub fn recover<P: AsRef<Path>>(sca: &Sca, pack: P) -> Result<(Sca, Vec<String>)> {
+    let path = pack.as_ref();
+    let pack = Pack::open(path)?;
+    let sca_dir = Sca::sca_folder()?;
+    let mut recovered = sca.clone();
+    recovered.reset_all_origin();
+    // the offsets come out relative to the $origin the SCA qcs already have
+    recovered.existing_origin = ExistingOrigin::Compose;
+    let mut notes = Vec::new();
+    let mut found = 0;
+    for class in recovered.folders.iter_mut() {
+        for animation in class.animations.iter_mut() {
+            let folder = sca_dir.join(class.class).join(&animation.name);
+            let baseline = animation.baseline.unwrap_or_default();
+            match recover_animation(&pack, &folder, &baseline) {
+                Ok(Some(origin)) => {
+                    animation.layer = Layer::from_origin(&origin);
+                    found += 1;
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    notes.push(format!("{} {}: {:#}", class.class, animation.name, error))
+                }
+            }
+        }
+    }
+    if found == 0 && notes.is_empty() {
+        bail!("{} has none of the SCA's models", path.display());
+    }
+    if found == 0 {
+        bail!(
+            "Nothing could be recovered from {}: {}",
+            path.display(),
+            notes.join(", ")
+        );
+    }
+    recovered.resolve();
+    Ok((recovered, notes))
+}
+
+// None if the pack doesn't have the model of the weapon in folder
+fn recover_animation(pack: &Pack, folder: &Path, baseline: &Origin) -> Result<Option<Origin>> {
+    let qc = Qc::read(Sca::find_qc(folder)?)?;
+    let model = qc
+        .model_name()
+        .ok_or_else(|| anyhow!("The qc has no $modelname"))?;
+    let model_path = install::pack_path(model);
+    let data = match pack.read(&model_path)? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let mdl = Mdl::parse(&data).with_context(|| format!("Can't parse {}", model_path))?;
+    let reference = reference_bones(&qc, folder)?;
+    solve(&reference, &mdl, baseline).map(Some)
+}
+
+// the pose studiomdl starts from, the $definebones if the qc has them,
+// otherwise the first frame of the first $sequence
+pub fn reference_bones(qc: &Qc, folder: &Path) -> Result<Vec<smd::Bone>> {
+    let mut bones = Vec::new();
+    let mut first_sequence = None;
+    for command in &qc.commands {
+        match command.directive()? {
+            Directive::DefineBone {
+                name,
+                parent,
+                position,
+                rotation,
+            } => bones.push(smd::Bone {
+                name: name.to_owned(),
+                parent: Some(parent.to_owned()).filter(|parent| !parent.is_empty()),
+                position,
+                rotation: define_bone_matrix(rotation),
+            }),
+            Directive::Sequence {
+                file: Some(file), ..
+            } if first_sequence.is_none() => first_sequence = Some(file),
+            _ => {}
+        }
+    }
+    if !bones.is_empty() {
+        return Ok(bones);
+    }
+    let file = first_sequence.ok_or_else(|| anyhow!("The qc has no bones or sequences"))?;
+    let mut smd_path = folder.join(file);
+    if smd_path.extension().is_none() {
+        smd_path.set_extension("smd");
+    }
+    let text = std::fs::read_to_string(&smd_path)
+        .with_context(|| format!("Can't read {}", smd_path.display()))?;
+    smd::skeleton(&text).with_context(|| format!("Can't read {}", smd_path.display()))
+}
+
+// the compiled root bone is the reference one after the smd transform, moved by -$origin
+// and turned by the $origin yaw plus studiomdl's own 90 degrees:
+//   rotation = Rz(90 + yaw) · Rx(pitch) · Ry(-roll) · reference rotation
+//   position = Rz(90 + yaw) · (transformed reference position - $origin)
+pub fn solve(reference: &[smd::Bone], mdl: &Mdl, baseline: &Origin) -> Result<Origin> {
+    let (bone, compiled) = reference
+        .iter()
+        .filter(|bone| bone.parent.is_none())
+        .find_map(|bone| {
+            mdl.bone(&bone.name)
+                .filter(|compiled| compiled.parent.is_none())
+                .map(|compiled| (bone, compiled))
+        })
+        .ok_or_else(|| anyhow!("The model has none of the SCA's root bones"))?;
+    let total = compiled.matrix().mul(&bone.rotation.transpose());
+    let m = &total.0;
+    let pitch = m[2][1].clamp(-1.0, 1.0).asin();
+    let roll = -(-m[2][0]).atan2(m[2][2]);
+    let turn = (-m[0][1]).atan2(m[1][1]);
+    let scale = child_scale(reference, mdl);
+    let transform = smd::Transform::from_angles(pitch.to_degrees(), roll.to_degrees(), scale);
+    let moved = transform.point(bone.position);
+    let unturned = Matrix::from_euler(0.0, 0.0, turn)
+        .transpose()
+        .apply(compiled.position);
+    let yaw = turn.to_degrees() - STUDIOMDL_YAW;
+    Ok(Origin {
+        x: round(moved[0] - unturned[0] - baseline.x),
+        y: round(moved[1] - unturned[1] - baseline.y),
+        z: round(moved[2] - unturned[2] - baseline.z),
+        yaw: round(wrap_degrees(yaw - baseline.yaw)),
+        pitch: round(pitch.to_degrees()),
+        roll: round(roll.to_degrees()),
+        scale: round(scale),
+    })
+}
+
+// child bones keep their direction and only move away from their parent,
+// the median of how much further they are is the scale
+fn child_scale(reference: &[smd::Bone], mdl: &Mdl) -> f32 {
+    let length = |v: [f32; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let mut ratios = reference
+        .iter()
+        .filter(|bone| bone.parent.is_some() && length(bone.position) > 0.01)
+        .filter_map(|bone| {
+            mdl.bone(&bone.name)
+                .filter(|compiled| compiled.parent.is_some())
+                .map(|compiled| length(compiled.position) / length(bone.position))
+        })
+        .collect::<Vec<f32>>();
+    if ratios.is_empty() {
+        return 1.0;
+    }
+    ratios.sort_by(|a, b| a.total_cmp(b));
+    ratios[ratios.len() / 2]
+}
+
+// into -180..=180
+fn wrap_degrees(degrees: f32) -> f32 {
+    let wrapped = (degrees + 180.0).rem_euclid(360.0) - 180.0;
+    if wrapped == -180.0 {
+        180.0
+    } else {
+        wrapped
+    }
+}
+
+// the sliders don't need more, and it hides float noise like 2.9999998
+fn round(value: f32) -> f32 {
+    (value * 1000.0).round() / 1000.0
+}
+
+// what studiomdl makes of reference with $origin at baseline + origin, for tests of what
+// reads compiled models
+#[cfg(test)]
+pub fn compile(reference: &[smd::Bone], origin: &Origin, baseline: &Origin) -> Mdl {
+    let transform = smd::Transform::from_angles(origin.pitch, origin.roll, origin.scale);
+    let yaw = (STUDIOMDL_YAW + baseline.yaw + origin.yaw).to_radians();
+    let turn = Matrix::from_euler(0.0, 0.0, yaw);
+    let offset = [
+        baseline.x + origin.x,
+        baseline.y + origin.y,
+        baseline.z + origin.z,
+    ];
+    let bones = reference
+        .iter()
+        .map(|bone| {
+            let (position, rotation) = match &bone.parent {
+                None => {
+                    let (position, rotation) = transform.root_bone(bone.position, &bone.rotation);
+                    let position = [0, 1, 2].map(|i| position[i] - offset[i]);
+                    (turn.apply(position), turn.mul(&rotation))
+                }
+                Some(_) => (transform.child_bone(bone.position), bone.rotation),
+            };
+            crate::mdl::Bone {
+                name: bone.name.clone(),
+                parent: bone.parent.as_ref().map(|parent| {
+                    reference
+                        .iter()
+                        .position(|bone| bone.name == *parent)
+                        .unwrap()
+                }),
+                position,
+                rotation: rotation.to_euler(),
+            }
+        })
+        .collect();
+    Mdl {
+        version: 48,
+        checksum: 0,
+        name: "weapons/v_test.mdl".to_owned(),
+        bones,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference() -> Vec<smd::Bone> {
+        let bone = |name: &str, parent: Option<&str>, position, rotation: [f32; 3]| smd::Bone {
+            name: name.to_owned(),
+            parent: parent.map(str::to_owned),
+            position,
+            rotation: Matrix::from_euler(rotation[0], rotation[1], rotation[2]),
+        };
+        vec![
+            bone("root", None, [2.0, -3.0, 40.0], [0.1, -0.2, 1.5]),
+            bone("arm", Some("root"), [0.0, 10.0, 0.0], [0.3, 0.0, 0.0]),
+            bone("hand", Some("arm"), [4.0, 0.0, 3.0], [0.0, 0.0, 0.0]),
+            bone("thumb", Some("hand"), [0.0, 2.0, 0.0], [0.0, 0.0, 0.5]),
+            // too short to say anything about the scale
+            bone("hand_end", Some("hand"), [0.001, 0.0, 0.0], [0.0, 0.0, 0.0]),
+        ]
+    }
+
+    fn assert_solves(origin: Origin, baseline: Origin) {
+        let reference = reference();
+        let solved = solve(
+            &reference,
+            &compile(&reference, &origin, &baseline),
+            &baseline,
+        )
+        .unwrap();
+        let pairs = [
+            (solved.x, origin.x),
+            (solved.y, origin.y),
+            (solved.z, origin.z),
+            (solved.yaw, origin.yaw),
+            (solved.pitch, origin.pitch),
+            (solved.roll, origin.roll),
+            (solved.scale, origin.scale),
+        ];
+        for (solved_value, value) in pairs {
+            assert!(
+                (solved_value - value).abs() < 0.002,
+                "solved {} instead of {}",
+                solved.describe(),
+                origin.describe()
+            );
+        }
+    }
+
+    #[test]
+    fn default_origin() {
+        assert_solves(Origin::default(), Origin::default());
+    }
+
+    #[test]
+    fn every_value() {
+        assert_solves(
+            Origin {
+                x: 1.5,
+                y: -2.0,
+                z: 3.25,
+                yaw: 10.0,
+                pitch: 5.0,
+                roll: -7.0,
+                scale: 1.2,
+            },
+            Origin::default(),
+        );
+    }
+
+    #[test]
+    fn with_baseline() {
+        let baseline = Origin {
+            x: 0.5,
+            y: 1.0,
+            z: -1.0,
+            yaw: -90.0,
+            ..Origin::default()
+        };
+        assert_solves(
+            Origin {
+                x: -4.0,
+                y: 0.75,
+                z: 2.0,
+                yaw: 30.0,
+                pitch: -12.0,
+                roll: 20.0,
+                scale: 0.8,
+            },
+            baseline,
+        );
+    }
+
+    #[test]
+    fn yaw_wraps() {
+        let turned = |yaw| Origin {
+            x: 1.0,
+            yaw,
+            ..Origin::default()
+        };
+        let baseline = Origin {
+            yaw: 20.0,
+            ..Origin::default()
+        };
+        assert_solves(turned(180.0), Origin::default());
+        assert_solves(turned(-179.0), Origin::default());
+        // 195 degrees in total
+        assert_solves(turned(175.0), baseline);
+        assert_solves(turned(-175.0), baseline);
+    }
+
+    #[test]
+    fn scale_is_the_median() {
+        let reference = reference();
+        let mut mdl = compile(&reference, &Origin::default(), &Origin::default());
+        assert_eq!(child_scale(&reference, &mdl), 1.0);
+        // one bone that moved on its own doesn't change it
+        mdl.bones[1].position = [0.0, 30.0, 0.0];
+        mdl.bones[2].position = [6.0, 0.0, 4.5];
+        mdl.bones[3].position = [0.0, 3.0, 0.0];
+        mdl.bones[4].position = [1.0, 0.0, 0.0];
+        assert_eq!(child_scale(&reference, &mdl), 1.5);
+        mdl.bones.truncate(1);
+        assert_eq!(child_scale(&reference, &mdl), 1.0);
+    }
+
+    #[test]
+    fn needs_a_root_bone() {
+        let reference = reference();
+        let mut mdl = compile(&reference, &Origin::default(), &Origin::default());
+        mdl.bones[0].name = "other".to_owned();
+        assert!(solve(&reference, &mdl, &Origin::default()).is_err());
+    }
+
+    #[test]
+    fn reads_the_model_the_sca_installs() {
+        let folder = fs::test_folder("recover_animation");
+        let weapon = folder.join("SCA").join("Scout").join("Pistol");
+        std::fs::create_dir_all(&weapon).unwrap();
+        std::fs::write(
+            weapon.join("pistol.qc"),
+            "$modelname \"__TEMP/0_ViewmodelCustomized/models/weapons/c_models/c_scout_arms.mdl\"\n\
+             $definebone \"root\" \"\" 2 -3 40 10 20 30 0 0 0 0 0 0\n\
+             $definebone \"arm\" \"root\" 0 10 0 0 0 0 0 0 0 0 0 0\n",
+        )
+        .unwrap();
+        let pack = Pack::Folder(folder.join("pack"));
+        assert!(recover_animation(&pack, &weapon, &Origin::default())
+            .unwrap()
+            .is_none());
+        let origin = Origin {
+            x: 1.0,
+            yaw: 15.0,
+            ..Origin::default()
+        };
+        let qc = Qc::read(weapon.join("pistol.qc")).unwrap();
+        let reference = reference_bones(&qc, &weapon).unwrap();
+        let mdl = compile(&reference, &origin, &Origin::default());
+        let installed = folder.join("pack/models/weapons/c_models/c_scout_arms.mdl");
+        std::fs::create_dir_all(installed.parent().unwrap()).unwrap();
+        std::fs::write(&installed, mdl.to_bytes()).unwrap();
+        let recovered = recover_animation(&pack, &weapon, &Origin::default())
+            .unwrap()
+            .unwrap();
+        assert!((recovered.x - 1.0).abs() < 0.002 && (recovered.yaw - 15.0).abs() < 0.002);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn pack_paths_of_sca_models() {
+        assert_eq!(
+            install::pack_path(
+                "__TEMP/0_ViewmodelCustomized/models/weapons/c_models/c_scout_arms.mdl"
+            ),
+            "models/weapons/c_models/c_scout_arms.mdl"
+        );
+        assert_eq!(
+            install::pack_path("__temp\\0_viewmodelcustomized\\models\\weapons\\V_Bat.mdl"),
+            "models/weapons/V_Bat.mdl"
+        );
+        assert_eq!(
+            install::pack_path("weapons/v_pistol.mdl"),
+            "weapons/v_pistol.mdl"
+        );
+    }
+
+    #[test]
+    fn wraps_degrees() {
+        assert_eq!(wrap_degrees(190.0), -170.0);
+        assert_eq!(wrap_degrees(-180.0), 180.0);
+        assert_eq!(wrap_degrees(540.0), 180.0);
+        assert_eq!(wrap_degrees(-45.0), -45.0);
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::{bail, Result};
+
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use super::cache::Cache;
+use super::diagnostics::CompileLog;
+use super::graph::Graph;
+use super::platform::{CancelToken, Cancelled};
+use super::workspace::Workspace;
+use super::Sca;
+
+// how often idle jobs check for a cancel while they wait on dependencies
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+// what compile_all and retry report, the index is the qc's position in the graph
+pub enum Progress {
+    Started(usize),
+    // what studiomdl printed, for failed compiles too
+    Output(usize, CompileLog),
+    // the models came out of the cache instead of studiomdl, Finished follows
+    Cached(usize),
+    Finished(usize),
+}
+
+// what to do with the rest of the build once a qc fails
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnError {
+    // kill the other jobs and fail the run
+    Stop,
+    // compile everything that doesn't need the failed model
+    KeepGoing,
+}
+
+// how a qc of the graph came out
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Succeeded,
+    Failed(String),
+    // not compiled because the qc at this index, which it includes the model of, didn't compile
+    Skipped(usize),
+}
+
+// the status of every qc, by graph index
+pub struct Outcome {
+    statuses: Vec<Status>,
+}
+
+impl Outcome {
+    pub fn statuses(&self) -> &[Status] {
+        &self.statuses
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.statuses
+            .iter()
+            .all(|status| *status == Status::Succeeded)
+    }
+
+    // the qcs that compiled, their models are in the shared game folder
+    pub fn succeeded<'a>(&'a self, graph: &'a Graph) -> impl Iterator<Item = &'a Path> {
+        self.statuses
+            .iter()
+            .zip(graph.nodes())
+            .filter(|(status, _)| **status == Status::Succeeded)
+            .map(|(_, node)| node.qc.as_path())
+    }
+}
+
+struct State {
+    // the qcs this run compiles
+    wanted: Vec<bool>,
+    started: Vec<bool>,
+    finished: Vec<bool>,
+    running: usize,
+    failures: Vec<(usize, anyhow::Error)>,
+}
+
+impl State {
+    // the first wanted qc in build order that isn't started yet and has all its dependencies compiled
+    fn next(&self, graph: &Graph) -> Option<usize> {
+        graph.order().iter().copied().find(|&index| {
+            self.wanted[index]
+                && !self.started[index]
+                && graph.nodes()[index]
+                    .dependencies
+                    .iter()
+                    .all(|&dependency| self.finished[dependency])
+        })
+    }
+}
+
+// compiles the graph with up to jobs studiomdl processes at once
+//
+// every qc compiles into a game folder of its own, its output gets collected into the shared
+// game folder before anything that includes it starts, which can read it from there
+// with OnError::Stop the first failure stops the other jobs and the error of the earliest failed qc
+// gets returned, with OnError::KeepGoing failures only skip the qcs that include their model
+// qcs the cache has models for don't get compiled, and what does gets added to it
+pub fn compile_all<F: Fn(Progress) + Sync>(
+    workspace: &Workspace,
+    graph: &Graph,
+    cache: Option<&Cache>,
+    jobs: usize,
+    on_error: OnError,
+    cancel: &CancelToken,
+    report: F,
+) -> Result<Outcome> {
+    let mut outcome = Outcome {
+        statuses: vec![Status::Skipped(0); graph.len()],
+    };
+    let wanted = vec![true; graph.len()];
+    run(
+        workspace,
+        graph,
+        cache,
+        &mut outcome,
+        wanted,
+        jobs,
+        on_error,
+        cancel,
+        &report,
+    )?;
+    Ok(outcome)
+}
+
+// compiles the qc at index again, along with everything that got skipped because of it,
+// the models that already compiled are left alone, a skipped qc retries the one it waits on
+#[allow(clippy::too_many_arguments)]
+pub fn retry<F: Fn(Progress) + Sync>(
+    workspace: &Workspace,
+    graph: &Graph,
+    cache: Option<&Cache>,
+    outcome: &mut Outcome,
+    index: usize,
+    jobs: usize,
+    cancel: &CancelToken,
+    report: F,
+) -> Result<()> {
+    if outcome.statuses[index] == Status::Succeeded {
+        return Ok(());
+    }
+    let mut wanted = vec![false; graph.len()];
+    wanted[index] = true;
+    // it can only compile after the failed qc it was skipped for does
+    let mut failed = index;
+    for _ in 0..graph.len() {
+        match outcome.statuses[failed] {
+            Status::Skipped(dependency) if dependency != failed => failed = dependency,
+            _ => break,
+        }
+    }
+    if outcome.statuses[failed] != Status::Succeeded {
+        wanted[failed] = true;
+    }
+    // graph order has dependencies first, so one pass picks up dependents of dependents
+    for &other in graph.order() {
+        if outcome.statuses[other] != Status::Succeeded
+            && graph.nodes()[other]
+                .dependencies
+                .iter()
+                .any(|&dependency| wanted[dependency])
+        {
+            wanted[other] = true;
+        }
+    }
+    run(
+        workspace,
+        graph,
+        cache,
+        outcome,
+        wanted,
+        jobs,
+        OnError::KeepGoing,
+        cancel,
+        &report,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run<F: Fn(Progress) + Sync>(
+    workspace: &Workspace,
+    graph: &Graph,
+    cache: Option<&Cache>,
+    outcome: &mut Outcome,
+    wanted: Vec<bool>,
+    jobs: usize,
+    on_error: OnError,
+    cancel: &CancelToken,
+    report: &F,
+) -> Result<()> {
+    // stops the other jobs on a failure without cancelling the whole run
+    let abort = cancel.child();
+    let finished = outcome
+        .statuses
+        .iter()
+        .map(|status| *status == Status::Succeeded)
+        .collect::<Vec<bool>>();
+    let state = Mutex::new(State {
+        started: finished.clone(),
+        finished,
+        wanted,
+        running: 0,
+        failures: Vec::new(),
+    });
+    let changed = Condvar::new();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(graph.len()) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut state = state.lock().unwrap();
+                    loop {
+                        if abort.is_cancelled() {
+                            return;
+                        }
+                        if let Some(index) = state.next(graph) {
+                            state.started[index] = true;
+                            state.running += 1;
+                            break index;
+                        }
+                        // what's left waits on a failed qc, or there's nothing left
+                        if state.running == 0 {
+                            return;
+                        }
+                        state = changed.wait_timeout(state, WAIT_INTERVAL).unwrap().0;
+                    }
+                };
+                report(Progress::Started(index));
+                let qc = &graph.nodes()[index].qc;
+                let result = compile_job(workspace, cache, index, qc, &abort, report)
+                    .and_then(|game_folder| workspace.collect_job(&game_folder));
+                let mut state = state.lock().unwrap();
+                state.running -= 1;
+                match result {
+                    Ok(()) => {
+                        state.finished[index] = true;
+                        drop(state);
+                        report(Progress::Finished(index));
+                    }
+                    Err(error) => {
+                        // jobs killed because of another failure don't count
+                        if !error.is::<Cancelled>() {
+                            state.failures.push((index, error));
+                        }
+                        if on_error == OnError::Stop {
+                            abort.cancel();
+                        }
+                    }
+                }
+                changed.notify_all();
+            });
+        }
+    });
+    let mut state = state.into_inner().unwrap();
+    let mut failures = std::mem::take(&mut state.failures);
+    failures.sort_by_key(|(index, _)| *index);
+    if on_error == OnError::Stop && !failures.is_empty() {
+        return Err(failures.remove(0).1);
+    }
+    cancel.check()?;
+    for (index, error) in failures {
+        outcome.statuses[index] = Status::Failed(format!("{:#}", error));
+    }
+    for &index in graph.order() {
+        if !state.wanted[index] {
+            continue;
+        }
+        if state.finished[index] {
+            outcome.statuses[index] = Status::Succeeded;
+        } else if !state.started[index] {
+            let blocker = graph.nodes()[index]
+                .dependencies
+                .iter()
+                .copied()
+                .find(|&dependency| !state.finished[dependency])
+                .unwrap_or(index);
+            // point at the failed qc, not at another skipped one
+            outcome.statuses[index] = match outcome.statuses[blocker] {
+                Status::Skipped(failed) => Status::Skipped(failed),
+                _ => Status::Skipped(blocker),
+            };
+        }
+    }
+    Ok(())
+}
+
+fn compile_job<F: Fn(Progress)>(
+    workspace: &Workspace,
+    cache: Option<&Cache>,
+    index: usize,
+    qc: &Path,
+    cancel: &CancelToken,
+    report: &F,
+) -> Result<PathBuf> {
+    cancel.check()?;
+    let game_folder = workspace.create_job(index)?;
+    let models = game_folder.join("models");
+    if let Some(cache) = cache {
+        if cache.restore(index, &models)? {
+            report(Progress::Cached(index));
+            return Ok(game_folder);
+        }
+    }
+    let weapon = workspace.weapon_name(qc);
+    let log = match Sca::compile(&game_folder, qc, weapon.clone(), cancel) {
+        Ok(log) => log,
+        Err(error) if error.is::<Cancelled>() => return Err(error),
+        Err(error) => return Err(error.context(format!("Can't compile {}", weapon))),
+    };
+    let failure = if log.success() {
+        None
+    } else {
+        Some(log.failure())
+    };
+    report(Progress::Output(index, log));
+    if let Some(failure) = failure {
+        bail!("{} failed to compile: {}", weapon, failure);
+    }
+    if let Some(cache) = cache {
+        // a cache that can't be written to only makes the next run slower
+        let _ = cache.store(index, &models);
+    }
+    Ok(game_folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::discovery::{self, Overrides};
+    use super::super::journal::Journal;
+    use super::*;
+    use crate::fs::test_folder;
+
+    const OWN: &str = "__TEMP/0_ViewmodelCustomized/models/weapons";
+
+    fn write(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    // the pistol includes the model of the scout's arms, the bat has a qc of its own
+    fn source(workspace: &Workspace) -> Vec<PathBuf> {
+        let source = workspace.source_folder();
+        let arms = source.join("Scout/scout.qc");
+        write(&arms, &format!("$modelname \"{}/c_scout_arms.mdl\"\n", OWN));
+        let pistol = source.join("Scout/Pistol/weapon.qc");
+        write(
+            &pistol,
+            &format!(
+                "$modelname \"{0}/v_pistol.mdl\"\n$includemodel \"{0}/c_scout_arms.mdl\"\n",
+                OWN
+            ),
+        );
+        let bat = source.join("Scout/Bat/weapon.qc");
+        write(&bat, &format!("$modelname \"{}/v_bat.mdl\"\n", OWN));
+        vec![arms, pistol, bat]
+    }
+
+    #[test]
+    fn retrying_a_skipped_qc_retries_what_it_waits_on() {
+        let root = test_folder("scheduler_retry");
+        // a studiomdl that can't run, so every compile fails until the cache has the models
+        let studiomdl = root.join("studiomdl");
+        write(&studiomdl, "not a program");
+        std::fs::create_dir_all(root.join("tf")).unwrap();
+        let _guard = discovery::test_overrides(Overrides {
+            tf: Some(root.join("tf")),
+            studiomdl: Some(studiomdl),
+            ..Overrides::default()
+        });
+        let workspace = Workspace::at(root.join("workspace"), Journal::at(root.join("run.jsonl")));
+        let qcs = source(&workspace);
+        let graph = Graph::new(&qcs).unwrap();
+        let cache = Cache::open(root.join("cache"), &graph, &workspace.source_folder()).unwrap();
+        let cancel = CancelToken::new();
+
+        let mut outcome = compile_all(
+            &workspace,
+            &graph,
+            Some(&cache),
+            2,
+            OnError::KeepGoing,
+            &cancel,
+            |_| {},
+        )
+        .unwrap();
+        assert!(matches!(outcome.statuses()[0], Status::Failed(_)));
+        assert_eq!(outcome.statuses()[1], Status::Skipped(0));
+        assert!(matches!(outcome.statuses()[2], Status::Failed(_)));
+
+        // the arms and the pistol compile this time
+        for (index, model) in [(0, "c_scout_arms.mdl"), (1, "v_pistol.mdl")] {
+            let models = root.join("compiled").join(index.to_string());
+            write(&models.join(OWN).join(model), model);
+            cache.store(index, &models).unwrap();
+        }
+        let cached = Mutex::new(Vec::new());
+        retry(
+            &workspace,
+            &graph,
+            Some(&cache),
+            &mut outcome,
+            1,
+            2,
+            &cancel,
+            |progress| {
+                if let Progress::Cached(index) = progress {
+                    cached.lock().unwrap().push(index);
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(outcome.statuses()[0], Status::Succeeded);
+        assert_eq!(outcome.statuses()[1], Status::Succeeded);
+        // the bat wasn't part of the retry
+        assert!(matches!(outcome.statuses()[2], Status::Failed(_)));
+        let mut cached = cached.into_inner().unwrap();
+        cached.sort_unstable();
+        assert_eq!(cached, [0, 1]);
+        let models = workspace.models_folder().join(OWN);
+        assert!(models.join("c_scout_arms.mdl").is_file());
+        assert!(models.join("v_pistol.mdl").is_file());
+        assert!(!models.join("v_bat.mdl").exists());
+        assert_eq!(
+            outcome.succeeded(&graph).collect::<Vec<&Path>>(),
+            [qcs[0].as_path(), qcs[1].as_path()]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::{bail, Result};
+
+// a node of a valve KeyValues file (libraryfolders.vdf, appmanifest_*.acf, ...)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    // case insensitive lookup, valve doesn't care about key casing either
+    pub fn get<T: AsRef<str>>(&self, key: T) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key.as_ref()))
+                .map(|(_, v)| v),
+            Value::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            Value::Object(_) => None,
+        }
+    }
+
+    pub fn entries(&self) -> &[(String, Value)] {
+        match self {
+            Value::Object(entries) => entries,
+            Value::String(_) => &[],
+        }
+    }
+}
+
+// parses the whole file into an object holding the root keys
+pub fn parse<T: AsRef<str>>(text: T) -> Result<Value> {
+    let mut tokens = Tokens {
+        chars: text.as_ref().chars().peekable(),
+        peeked: None,
+    };
+    let entries = parse_entries(&mut tokens, false)?;
+    Ok(Value::Object(entries))
+}
+
+fn parse_entries(tokens: &mut Tokens, nested: bool) -> Result<Vec<(String, Value)>> {
+    let mut entries = Vec::new();
+    loop {
+        let key = match tokens.next()? {
+            None if nested => bail!("Unexpected end of file, missing }}"),
+            None => return Ok(entries),
+            Some(Token::Close) if nested => return Ok(entries),
+            Some(Token::Close) => bail!("Unexpected }}"),
+            Some(Token::Open) => bail!("Expected a key, found {{"),
+            Some(Token::Condition(_)) => bail!("Expected a key, found a [condition]"),
+            Some(Token::Text(key)) => key,
+        };
+        let value = match tokens.next()? {
+            Some(Token::Open) => Value::Object(parse_entries(tokens, true)?),
+            Some(Token::Text(value)) => Value::String(value),
+            _ => bail!("Missing value for key {}", key),
+        };
+        // a [$WIN32] after the value keeps the entry to the platforms it names
+        let applies = match tokens.next()? {
+            Some(Token::Condition(condition)) => holds(&condition),
+            token => {
+                tokens.peeked = token;
+                true
+            }
+        };
+        if applies {
+            entries.push((key, value));
+        }
+    }
+}
+
+// conditions like $WIN32, !$POSIX or $LINUX || $OSX, platforms it doesn't know never hold
+fn holds(condition: &str) -> bool {
+    let platform = |name: &str| match name {
+        "WIN32" | "WINDOWS" => cfg!(windows),
+        "POSIX" => cfg!(unix),
+        "LINUX" => cfg!(target_os = "linux"),
+        "OSX" => cfg!(target_os = "macos"),
+        _ => false,
+    };
+    condition.split("||").any(|all| {
+        all.split("&&").all(|term| {
+            let term = term.trim();
+            match term.strip_prefix('!') {
+                Some(term) => !platform(term.trim().trim_start_matches('$')),
+                None => platform(term.trim_start_matches('$')),
+            }
+        })
+    })
+}
+
+enum Token {
+    Open,
+    Close,
+    Text(String),
+    Condition(String),
+}
+
+struct Tokens<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    // a token read ahead that wasn't a condition
+    peeked: Option<Token>,
+}
+
+impl Tokens<'_> {
+    fn next(&mut self) -> Result<Option<Token>> {
+        if let Some(token) = self.peeked.take() {
+            return Ok(Some(token));
+        }
+        loop {
+            match self.chars.next() {
+                None => return Ok(None),
+                Some(c) if c.is_whitespace() => continue,
+                Some('{') => return Ok(Some(Token::Open)),
+                Some('}') => return Ok(Some(Token::Close)),
+                Some('/') if self.chars.peek() == Some(&'/') => {
+                    self.chars.by_ref().find(|c| *c == '\n');
+                }
+                Some('[') => {
+                    let condition = self.chars.by_ref().take_while(|c| *c != ']').collect();
+                    return Ok(Some(Token::Condition(condition)));
+                }
+                Some('"') => return self.quoted().map(|s| Some(Token::Text(s))),
+                Some(c) => {
+                    let mut text = c.to_string();
+                    while let Some(c) = self.chars.peek() {
+                        if c.is_whitespace() || matches!(c, '{' | '}' | '"') {
+                            break;
+                        }
+                        text.push(*c);
+                        self.chars.next();
+                    }
+                    return Ok(Some(Token::Text(text)));
+                }
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                None => bail!("Unterminated string"),
+                Some('"') => return Ok(text),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(c) => text.push(c),
+                    None => bail!("Unterminated string"),
+                },
+                Some(c) => text.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &Value, keys: &[&str]) -> Option<String> {
+        let mut value = value;
+        for key in keys {
+            value = value.get(key)?;
+        }
+        value.as_str().map(str::to_owned)
+    }
+
+    #[test]
+    fn nested_objects() {
+        let doc = parse(
+            r#"
+            // a comment
+            "AppState"
+            {
+                "appid"     "440"
+                "InstallDir"    "Team Fortress 2"
+                "UserConfig" { "language" "english" }
+                unquoted value
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            string(&doc, &["appstate", "installdir"]).unwrap(),
+            "Team Fortress 2"
+        );
+        assert_eq!(
+            string(&doc, &["AppState", "userconfig", "language"]).unwrap(),
+            "english"
+        );
+        assert_eq!(string(&doc, &["AppState", "unquoted"]).unwrap(), "value");
+        assert_eq!(doc.get("AppState").unwrap().entries().len(), 4);
+        assert!(doc.get("AppState").unwrap().as_str().is_none());
+    }
+
+    #[test]
+    fn escapes() {
+        let doc = parse(r#""path" "D:\\SteamLibrary\\steamapps" "quote" "a \"b\"\tc""#).unwrap();
+        assert_eq!(
+            string(&doc, &["path"]).unwrap(),
+            r"D:\SteamLibrary\steamapps"
+        );
+        assert_eq!(string(&doc, &["quote"]).unwrap(), "a \"b\"\tc");
+    }
+
+    #[test]
+    fn conditions() {
+        // conditions go after the value
+        assert!(parse(r#""object" [$WIN32] { "a" "b" }"#).is_err());
+        let doc = parse(
+            r#"
+            "path" "C:\\Steam" [$WIN32]
+            "path" "/home/a/.steam" [$POSIX]
+            "object" { "a" "b" } [!$WIN32]
+            "console" "1" [$X360]
+            "always" "1"
+            "#,
+        )
+        .unwrap();
+        let path = string(&doc, &["path"]).unwrap();
+        assert_eq!(
+            path,
+            if cfg!(windows) {
+                r"C:\Steam"
+            } else {
+                "/home/a/.steam"
+            }
+        );
+        assert_eq!(doc.get("object").is_some(), !cfg!(windows));
+        assert!(doc.get("console").is_none());
+        assert!(doc.get("always").is_some());
+        assert_eq!(
+            holds("$LINUX || $WIN32"),
+            cfg!(any(target_os = "linux", windows))
+        );
+        assert_eq!(
+            holds("$POSIX && !$OSX"),
+            cfg!(unix) && !cfg!(target_os = "macos")
+        );
+        assert!(!holds("$WIN32 && $POSIX"));
+    }
+
+    #[test]
+    fn broken_files() {
+        assert!(parse(r#""a" { "b" "c""#).is_err());
+        assert!(parse(r#""a" "b" }"#).is_err());
+        assert!(parse(r#""a""#).is_err());
+        assert!(parse(r#""a" "unterminated"#).is_err());
+        assert!(parse(r#"{ "a" "b" }"#).is_err());
+        assert_eq!(parse("").unwrap(), Value::Object(Vec::new()));
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::Result;
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use super::recover;
+use super::workspace::Workspace;
+use super::{Origin, Sca};
+use crate::mdl::{self, Mdl};
+use crate::qc::{Directive, Qc};
+
+// how far a compiled root bone may be off before the offset counts as missing
+const POSITION_TOLERANCE: f32 = 0.05;
+const ANGLE_TOLERANCE: f32 = 0.1;
+const SCALE_TOLERANCE: f32 = 0.005;
+
+// the files studiomdl writes next to a .mdl, with where their checksum is
+const COMPANIONS: [(&str, usize); 4] = [
+    ("vvd", 8),
+    ("dx90.vtx", 16),
+    ("dx80.vtx", 16),
+    ("sw.vtx", 16),
+];
+
+// one compiled model and everything that's wrong with it
+pub struct ModelCheck {
+    // class and animation, or just the class for the arms
+    pub weapon: String,
+    // the $modelname
+    pub model: String,
+    pub problems: Vec<String>,
+}
+
+pub struct Report {
+    pub checks: Vec<ModelCheck>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.failed().next().is_none()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &ModelCheck> {
+        self.checks
+            .iter()
+            .filter(|check| !check.problems.is_empty())
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in self.failed() {
+            writeln!(f, "{} ({}):", check.weapon, check.model)?;
+            for problem in &check.problems {
+                writeln!(f, "  {}", problem)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// reads back every model the compile should have produced and checks it's where the vpk gets
+// packed from, has the right name and checksums, and that the weapons sit where they were asked to
+// only the qcs in built are checked, the others didn't get compiled
+pub fn verify(sca: &Sca, workspace: &Workspace, built: &[PathBuf]) -> Result<Report> {
+    let sca_dir = Sca::sca_folder()?;
+    let mut checks = Vec::new();
+    for class in sca.get_selected_classes() {
+        // every animation of a selected class gets compiled, see get_temp_folder_qcs
+        for animation in &class.animations {
+            let source = sca_dir.join(class.class).join(&animation.name);
+            let compiled = workspace
+                .source_folder()
+                .join(class.class)
+                .join(&animation.name);
+            let qc = Sca::find_qc(&compiled)?;
+            if !built.contains(&qc) {
+                continue;
+            }
+            let weapon = format!("{} {}", class.class, animation.name);
+            let expected = sca.compiled_origin(animation);
+            checks.push(check(workspace, weapon, &qc, Some((&source, expected)))?);
+        }
+        let qc = Sca::find_qc(workspace.source_folder().join(class.class))?;
+        if built.contains(&qc) {
+            checks.push(check(workspace, class.class.to_string(), &qc, None)?);
+        }
+    }
+    Ok(Report { checks })
+}
+
+// the model of qc, with the origin it should have and the SCA folder to compare to
+fn check(
+    workspace: &Workspace,
+    weapon: String,
+    qc: &Path,
+    origin: Option<(&Path, Origin)>,
+) -> Result<ModelCheck> {
+    let qc = Qc::read(qc)?;
+    let model = qc
+        .commands
+        .iter()
+        .find_map(|command| match command.directive() {
+            Ok(Directive::ModelName(model)) => Some(model.to_owned()),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let mut check = ModelCheck {
+        weapon,
+        model: model.clone(),
+        problems: Vec::new(),
+    };
+    let path = workspace.models_folder().join(&model);
+    if !path.starts_with(workspace.output_folder()) {
+        check
+            .problems
+            .push("It's compiled outside of what goes into the vpk".to_owned());
+    }
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(_) => {
+            check
+                .problems
+                .push("studiomdl didn't write the model".to_owned());
+            return Ok(check);
+        }
+    };
+    let mdl = match Mdl::parse(&data) {
+        Ok(mdl) => mdl,
+        Err(error) => {
+            check.problems.push(format!("{:#}", error));
+            return Ok(check);
+        }
+    };
+    if normalize(&mdl.name) != normalize(mdl::stored_name(&model)) {
+        check
+            .problems
+            .push(format!("The model calls itself {}", mdl.name));
+    }
+    if mdl.checksum == 0 {
+        check.problems.push("The model has no checksum".to_owned());
+    }
+    for (extension, offset) in COMPANIONS {
+        let companion = path.with_extension(extension);
+        if let Ok(data) = std::fs::read(&companion) {
+            let checksum = data
+                .get(offset..offset + 4)
+                .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()));
+            if checksum != Some(mdl.checksum) {
+                check.problems.push(format!(
+                    "The .{} doesn't belong to the model, its checksum is different",
+                    extension
+                ));
+            }
+        }
+    }
+    if let Some((source, expected)) = origin {
+        let source_qc = Qc::read(Sca::find_qc(source)?)?;
+        let compiled = recover::reference_bones(&source_qc, source)
+            .and_then(|reference| recover::solve(&reference, &mdl, &Origin::default()));
+        match compiled {
+            Ok(compiled) => check.problems.extend(compare(&expected, &compiled)),
+            Err(error) => check
+                .problems
+                .push(format!("Can't check the offset: {:#}", error)),
+        }
+    }
+    Ok(check)
+}
+
+fn compare(expected: &Origin, compiled: &Origin) -> Vec<String> {
+    let mut problems = Vec::new();
+    let position_off = [
+        expected.x - compiled.x,
+        expected.y - compiled.y,
+        expected.z - compiled.z,
+    ]
+    .iter()
+    .any(|difference| difference.abs() > POSITION_TOLERANCE);
+    if position_off {
+        problems.push(format!(
+            "It sits at x {} y {} z {} instead of x {} y {} z {}",
+            compiled.x, compiled.y, compiled.z, expected.x, expected.y, expected.z
+        ));
+    }
+    for (name, expected, compiled) in [
+        ("yaw", expected.yaw, compiled.yaw),
+        ("pitch", expected.pitch, compiled.pitch),
+        ("roll", expected.roll, compiled.roll),
+    ] {
+        let difference = (expected - compiled + 180.0).rem_euclid(360.0) - 180.0;
+        if difference.abs() > ANGLE_TOLERANCE {
+            problems.push(format!(
+                "Its {} is {} instead of {}",
+                name, compiled, expected
+            ));
+        }
+    }
+    if (expected.scale - compiled.scale).abs() > SCALE_TOLERANCE {
+        problems.push(format!(
+            "Its scale is {} instead of {}",
+            compiled.scale, expected.scale
+        ));
+    }
+    problems
+}
+
+fn normalize(model: &str) -> String {
+    model.replace('\\', "/").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::discovery::{self, Overrides};
+    use super::super::journal::Journal;
+    use super::super::test_sca;
+    use super::*;
+    use crate::fs::{self, test_folder};
+
+    const OWN: &str = "__TEMP/0_ViewmodelCustomized/models/weapons";
+
+    fn write(path: &Path, data: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    // the scout of test_sca in an SCA folder, copied into a workspace like generate does,
+    // with the pistol moved 1 forward
+    struct Setup {
+        root: PathBuf,
+        sca: Sca,
+        workspace: Workspace,
+        // the qcs of the scout, the pistol and the bat in the workspace
+        qcs: [PathBuf; 3],
+    }
+
+    fn setup(name: &str) -> (Setup, std::sync::MutexGuard<'static, ()>) {
+        let root = test_folder(name);
+        let sca_dir = root.join("SCA");
+        write(
+            &sca_dir.join("Scout/scout.qc"),
+            format!("$modelname \"{}/c_models/c_scout_arms.mdl\"\n", OWN).as_bytes(),
+        );
+        for (folder, model) in [("Pistol", "v_pistol"), ("Bat", "v_bat")] {
+            let qc = format!(
+                "$modelname \"{}/{}.mdl\"\n\
+                 $definebone \"root\" \"\" 2 -3 40 10 20 30 0 0 0 0 0 0\n\
+                 $definebone \"arm\" \"root\" 0 10 0 0 0 0 0 0 0 0 0 0\n",
+                OWN, model
+            );
+            write(
+                &sca_dir.join("Scout").join(folder).join("weapon.qc"),
+                qc.as_bytes(),
+            );
+        }
+        let guard = discovery::test_overrides(Overrides {
+            sca: Some(sca_dir.clone()),
+            ..Overrides::default()
+        });
+        let workspace = Workspace::at(root.join("workspace"), Journal::at(root.join("run.jsonl")));
+        let source = workspace.source_folder();
+        fs::copy_folder(&sca_dir.join("Scout"), &source.join("Scout")).unwrap();
+        let mut sca = test_sca();
+        sca.folders[0].animations[0].origin.x = 1.0;
+        let qcs = [
+            source.join("Scout/scout.qc"),
+            source.join("Scout/Pistol/weapon.qc"),
+            source.join("Scout/Bat/weapon.qc"),
+        ];
+        let setup = Setup {
+            root,
+            sca,
+            workspace,
+            qcs,
+        };
+        (setup, guard)
+    }
+
+    impl Setup {
+        // what studiomdl would write for the qc at index with origin, as the model called model
+        fn compile(&self, index: usize, origin: &Origin, model: &str) -> PathBuf {
+            let qc = Qc::read(&self.qcs[index]).unwrap();
+            let reference = if index == 0 {
+                Vec::new()
+            } else {
+                recover::reference_bones(&qc, self.qcs[index].parent().unwrap()).unwrap()
+            };
+            let mut mdl = recover::compile(&reference, origin, &Origin::default());
+            mdl.name = format!("{}/{}.mdl", OWN, model);
+            mdl.checksum = 1234;
+            let path = self.workspace.models_folder().join(&mdl.name);
+            write(&path, &mdl.to_bytes());
+            let mut vvd = vec![0; 12];
+            vvd[8..].copy_from_slice(&1234i32.to_le_bytes());
+            write(&path.with_extension("vvd"), &vvd);
+            path
+        }
+
+        fn verify(&self, built: &[PathBuf]) -> Report {
+            verify(&self.sca, &self.workspace, built).unwrap()
+        }
+
+        fn problems(&self) -> Vec<String> {
+            let report = self.verify(&self.qcs[..2]);
+            report
+                .failed()
+                .flat_map(|check| check.problems.iter().cloned())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn models_where_they_should_be() {
+        let (setup, _guard) = setup("verify_ok");
+        setup.compile(0, &Origin::default(), "c_models/c_scout_arms");
+        let moved = Origin {
+            x: 1.0,
+            ..Origin::default()
+        };
+        setup.compile(1, &moved, "v_pistol");
+        let report = setup.verify(&setup.qcs[..2]);
+        assert!(report.is_ok(), "{}", report);
+        let weapons = report
+            .checks
+            .iter()
+            .map(|check| check.weapon.as_str())
+            .collect::<Vec<_>>();
+        // the bat wasn't built, so it isn't checked
+        assert_eq!(weapons, ["Scout Pistol", "Scout"]);
+        assert!(setup.verify(&[]).checks.is_empty());
+        std::fs::remove_dir_all(&setup.root).unwrap();
+    }
+
+    #[test]
+    fn models_that_went_wrong() {
+        let (setup, _guard) = setup("verify_wrong");
+        assert_eq!(
+            setup.problems(),
+            [
+                "studiomdl didn't write the model",
+                "studiomdl didn't write the model"
+            ]
+        );
+
+        // the offset never got into the model
+        setup.compile(0, &Origin::default(), "c_models/c_scout_arms");
+        let pistol = setup.compile(1, &Origin::default(), "v_pistol");
+        assert_eq!(
+            setup.problems(),
+            ["It sits at x 0 y 0 z 0 instead of x 1 y 0 z 0"]
+        );
+
+        // another model, a companion of yet another one and no checksum
+        let mut mdl = Mdl::parse(&std::fs::read(&pistol).unwrap()).unwrap();
+        let moved = Origin {
+            x: 1.0,
+            ..Origin::default()
+        };
+        let compiled = setup.compile(1, &moved, "v_pistol");
+        mdl = Mdl {
+            name: format!("{}/v_other.mdl", OWN),
+            checksum: 0,
+            bones: Mdl::parse(&std::fs::read(&compiled).unwrap())
+                .unwrap()
+                .bones,
+            ..mdl
+        };
+        std::fs::write(&pistol, mdl.to_bytes()).unwrap();
+        let problems = setup.problems();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("The model calls itself"));
+        assert_eq!(problems[1], "The model has no checksum");
+        assert_eq!(
+            problems[2],
+            "The .vvd doesn't belong to the model, its checksum is different"
+        );
+
+        std::fs::write(&pistol, b"IDST").unwrap();
+        assert_eq!(setup.problems().len(), 1);
+        std::fs::remove_dir_all(&setup.root).unwrap();
+    }
+
+    #[test]
+    fn models_outside_of_the_vpk() {
+        let (setup, _guard) = setup("verify_outside");
+        write(&setup.qcs[0], b"$modelname \"weapons/c_scout_arms.mdl\"\n");
+        let problems = setup.verify(&setup.qcs[..1]).checks.remove(0).problems;
+        assert_eq!(
+            problems,
+            [
+                "It's compiled outside of what goes into the vpk",
+                "studiomdl didn't write the model"
+            ]
+        );
+        std::fs::remove_dir_all(&setup.root).unwrap();
+    }
+
+    #[test]
+    fn tolerances() {
+        let expected = Origin {
+            x: 1.0,
+            yaw: 179.95,
+            scale: 1.1,
+            ..Origin::default()
+        };
+        let close = Origin {
+            x: 1.04,
+            yaw: -179.98,
+            scale: 1.104,
+            ..Origin::default()
+        };
+        assert!(compare(&expected, &close).is_empty());
+        let off = Origin {
+            x: 1.0,
+            y: 0.1,
+            yaw: 179.95,
+            roll: 0.2,
+            scale: 1.2,
+            ..Origin::default()
+        };
+        assert_eq!(
+            compare(&expected, &off),
+            [
+                "It sits at x 1 y 0.1 z 0 instead of x 1 y 0 z 0",
+                "Its roll is 0.2 instead of 0",
+                "Its scale is 1.2 instead of 1.1"
+            ]
+        );
+    }
+}
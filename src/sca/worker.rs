@@ -0,0 +1,359 @@
+use anyhow::{bail, Result};
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::cache::Cache;
+use super::diagnostics::CompileLog;
+use super::graph::Graph;
+use super::install::{Install, Output, Patch};
+use super::platform::{CancelToken, Cancelled};
+use super::scheduler::{self, OnError, Outcome, Progress, Status};
+use super::workspace::Workspace;
+use super::{discovery, verify, ClassAnimations, Origin, Sca};
+
+// how often a worker waiting on a Command checks for a cancel
+const COMMAND_INTERVAL: Duration = Duration::from_millis(50);
+
+// what the worker reports back, Done, Failed and Cancelled end the run
+pub enum Event {
+    // the qcs are collected, total is how many get compiled
+    Started {
+        total: usize,
+    },
+    // weapon is the class or "Class Animation" the qc belongs to
+    Compiling {
+        index: usize,
+        qc: PathBuf,
+        weapon: String,
+    },
+    // what studiomdl printed for the qc at index
+    Output {
+        index: usize,
+        log: CompileLog,
+    },
+    // the qc at index didn't change since an earlier run, its models come from the cache
+    Cached {
+        index: usize,
+        qc: PathBuf,
+        weapon: String,
+    },
+    Compiled {
+        index: usize,
+        qc: PathBuf,
+        weapon: String,
+    },
+    // with OnError::KeepGoing, how every qc came out when some didn't compile,
+    // the worker then waits for a retry, a package or a cancel
+    Summary(Vec<Item>),
+    // the compiled models get read back and checked
+    Verifying,
+    Packaging,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+impl Event {
+    pub fn is_final(&self) -> bool {
+        matches!(self, Event::Done | Event::Failed(_) | Event::Cancelled)
+    }
+}
+
+// one qc of a Summary
+#[derive(Clone, Debug)]
+pub struct Item {
+    // the qc's position in the graph, what Skipped and retry refer to
+    pub index: usize,
+    pub weapon: String,
+    pub status: Status,
+}
+
+// how a generate run goes about failures and what's installed
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub on_error: OnError,
+    pub install: Install,
+    pub output: Output,
+}
+
+// what to do after a Summary, cancelling discards the run
+pub enum Command {
+    // compiles the qc at index again, with everything skipped because of it
+    Retry(usize),
+    // packages and installs the weapons that compiled, without the others
+    Package,
+}
+
+// runs the whole generate pipeline (copy, origins, compile, vpk, cleanup) on its own thread
+pub struct Worker {
+    events: Receiver<Event>,
+    commands: Sender<Command>,
+    cancel: CancelToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    // notify gets called after every event, the gui uses it to request a repaint
+    pub fn spawn<F: Fn() + Send + Sync + 'static>(sca: Sca, options: Options, notify: F) -> Self {
+        let (sender, events) = channel();
+        let (commands, received) = channel();
+        let cancel = CancelToken::new();
+        let thread_cancel = cancel.clone();
+        let handle = std::thread::spawn(move || {
+            // what the layers add up to is what gets compiled
+            let sca = sca.resolved();
+            let reporter = Reporter { sender, notify };
+            let run = Run {
+                sca: &sca,
+                options,
+                commands: &received,
+                cancel: &thread_cancel,
+                reporter: &reporter,
+            };
+            let event = match generate(&run) {
+                Ok(()) => Event::Done,
+                Err(error) if error.is::<Cancelled>() => Event::Cancelled,
+                Err(error) => Event::Failed(format!("{:#}", error)),
+            };
+            reporter.send(event);
+        });
+        Worker {
+            events,
+            commands,
+            cancel,
+            handle: Some(handle),
+        }
+    }
+
+    // kills the running studiomdl, the workspace gets cleaned up before Cancelled is sent
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    // only does something while the worker waits after a Summary
+    pub fn retry(&self, index: usize) {
+        let _ = self.commands.send(Command::Retry(index));
+    }
+
+    pub fn package(&self) {
+        let _ = self.commands.send(Command::Package);
+    }
+
+    // every event that arrived since the last call, never blocks
+    pub fn poll(&self) -> Vec<Event> {
+        self.events.try_iter().collect()
+    }
+
+    // blocks until the next event, None once the worker is gone
+    pub fn recv(&self) -> Option<Event> {
+        self.events.recv().ok()
+    }
+
+    // cancels and waits for the cleanup to finish
+    pub fn stop(mut self) {
+        self.cancel();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Reporter<F: Fn()> {
+    sender: Sender<Event>,
+    notify: F,
+}
+
+impl<F: Fn()> Reporter<F> {
+    fn send(&self, event: Event) {
+        // the receiving side being gone just means nobody is listening anymore
+        let _ = self.sender.send(event);
+        (self.notify)();
+    }
+}
+
+// everything a generate run gets from the worker
+struct Run<'a, F: Fn()> {
+    sca: &'a Sca,
+    options: Options,
+    commands: &'a Receiver<Command>,
+    cancel: &'a CancelToken,
+    reporter: &'a Reporter<F>,
+}
+
+// the workspace always gets removed, which also rolls back an install that didn't go through
+fn generate<F: Fn() + Sync>(run: &Run<F>) -> Result<()> {
+    let workspace = Workspace::create()?;
+    let result = generate_in(run, &workspace);
+    let cleanup = workspace.remove();
+    result?;
+    cleanup
+}
+
+fn generate_in<F: Fn() + Sync>(run: &Run<F>, workspace: &Workspace) -> Result<()> {
+    let (cancel, reporter) = (run.cancel, run.reporter);
+    let patch = match run.options.install {
+        Install::Replace => None,
+        Install::Patch => Some(Patch::plan(run.sca)?),
+    };
+    // with a patch only the classes that changed get built
+    let sca = patch.as_ref().map_or(run.sca, Patch::build);
+    let only_removes = patch.as_ref().is_some_and(Patch::only_removes);
+    if patch.is_some() && sca.get_selected_classes().is_empty() && !only_removes {
+        bail!("Nothing changed since the installed vpk was built");
+    }
+    sca.copy_sca(workspace)?;
+    sca.append_origins(workspace)?;
+    sca.transform_smds(workspace)?;
+    let anim_qcs = sca.get_temp_folder_qcs(workspace, false)?;
+    let class_qcs = sca.get_selected_class_qcs(workspace)?;
+    let items = vec![anim_qcs, class_qcs]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<PathBuf>>();
+    // broken references show up here, before studiomdl runs even once
+    let graph = Graph::new(&items)?;
+    let cache = discovery::cache_folder()?
+        .map(|folder| Cache::open(folder, &graph, &workspace.source_folder()))
+        .transpose()?;
+    reporter.send(Event::Started { total: graph.len() });
+    let jobs = discovery::jobs()?;
+    let qc = |index: usize| graph.nodes()[index].qc.clone();
+    let weapon = |index: usize| workspace.weapon_name(&graph.nodes()[index].qc);
+    let progress = |progress| {
+        reporter.send(match progress {
+            Progress::Started(index) => Event::Compiling {
+                index,
+                qc: qc(index),
+                weapon: weapon(index),
+            },
+            Progress::Output(index, log) => Event::Output { index, log },
+            Progress::Cached(index) => Event::Cached {
+                index,
+                qc: qc(index),
+                weapon: weapon(index),
+            },
+            Progress::Finished(index) => Event::Compiled {
+                index,
+                qc: qc(index),
+                weapon: weapon(index),
+            },
+        })
+    };
+    let mut outcome = scheduler::compile_all(
+        workspace,
+        &graph,
+        cache.as_ref(),
+        jobs,
+        run.options.on_error,
+        cancel,
+        progress,
+    )?;
+    while !outcome.is_ok() {
+        reporter.send(Event::Summary(summary(workspace, &graph, &outcome)));
+        match next_command(run.commands, cancel)? {
+            Command::Retry(index) => scheduler::retry(
+                workspace,
+                &graph,
+                cache.as_ref(),
+                &mut outcome,
+                index,
+                jobs,
+                cancel,
+                progress,
+            )?,
+            Command::Package => break,
+        }
+    }
+    if let Some(cache) = &cache {
+        // only cleanup, it doesn't matter for this run
+        let _ = cache.prune();
+    }
+    cancel.check()?;
+    let built = outcome
+        .succeeded(&graph)
+        .map(Path::to_path_buf)
+        .collect::<Vec<PathBuf>>();
+    if built.is_empty() && !only_removes {
+        bail!("Nothing compiled, there's nothing to package");
+    }
+    reporter.send(Event::Verifying);
+    let report = verify::verify(sca, workspace, &built)?;
+    if !report.is_ok() {
+        bail!("Some models came out wrong:\n{}", report);
+    }
+    reporter.send(Event::Packaging);
+    let packaged = packaged_settings(run.sca, sca, workspace, &built, patch.as_ref())?;
+    match run.options.output {
+        Output::Vpk => packaged.convert_to_vpk(workspace, patch.as_ref()),
+        Output::Loose => packaged.install_loose(workspace, patch.as_ref()),
+    }
+}
+
+fn summary(workspace: &Workspace, graph: &Graph, outcome: &Outcome) -> Vec<Item> {
+    graph
+        .nodes()
+        .iter()
+        .zip(outcome.statuses())
+        .enumerate()
+        .map(|(index, (node, status))| Item {
+            index,
+            weapon: workspace.weapon_name(&node.qc),
+            status: status.clone(),
+        })
+        .collect()
+}
+
+// blocks until the gui decides what to do about the failures
+fn next_command(commands: &Receiver<Command>, cancel: &CancelToken) -> Result<Command> {
+    loop {
+        cancel.check()?;
+        match commands.recv_timeout(COMMAND_INTERVAL) {
+            Ok(command) => return Ok(command),
+            Err(RecvTimeoutError::Timeout) => {}
+            // the Worker is gone, nobody is going to decide
+            Err(RecvTimeoutError::Disconnected) => return Err(Cancelled.into()),
+        }
+    }
+}
+
+// sca with the weapons of build that didn't compile back at what the installed vpk has for them,
+// or reset without a patch, so the manifest in the vpk only has what's actually in it
+fn packaged_settings(
+    sca: &Sca,
+    build: &Sca,
+    workspace: &Workspace,
+    built: &[PathBuf],
+    patch: Option<&Patch>,
+) -> Result<Sca> {
+    let compiled = build
+        .get_selected_classes()
+        .iter()
+        .map(|class| class.class)
+        .collect::<Vec<_>>();
+    let mut packaged = sca.clone();
+    // classes that weren't built are either reset or still what's installed
+    for ClassAnimations {
+        class, animations, ..
+    } in packaged
+        .folders
+        .iter_mut()
+        .filter(|class| compiled.contains(&class.class))
+    {
+        for animation in animations.iter_mut() {
+            let folder = workspace.source_folder().join(*class).join(&animation.name);
+            if !built.contains(&Sca::find_qc(folder)?) {
+                animation.origin = patch.map_or_else(Origin::default, |patch| {
+                    patch.installed_origin(*class, &animation.name)
+                });
+            }
+        }
+    }
+    Ok(packaged)
+}
@@ -0,0 +1,185 @@
+use anyhow::{bail, Result};
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::journal::{Entry, Journal};
+use super::platform::Tool;
+use super::{discovery, Sca};
+use crate::fs;
+
+// every workspace of this program lives in here, inside the system temp folder
+pub const WORKSPACES_FOLDER_NAME: &str = "v_customizer";
+// the SCA qcs write their models to models/__TEMP/0_ViewmodelCustomized/...
+pub const OUTPUT_FOLDER_NAME: &str = "__TEMP";
+pub const ADDON_NAME: &str = "0_ViewmodelCustomized";
+const JOBS_FOLDER_NAME: &str = "jobs";
+
+// a throwaway game folder for one generate run
+//
+// studiomdl gets `-game <workspace>/game`, which has its own gameinfo.txt and models folder,
+// so the compile output never ends up in the user's real tf folder
+pub struct Workspace {
+    root: PathBuf,
+    // everything the run does outside of the workspace goes in here
+    journal: Journal,
+}
+
+impl Workspace {
+    pub fn create() -> Result<Self> {
+        let parent = std::env::temp_dir().join(WORKSPACES_FOLDER_NAME);
+        std::fs::create_dir_all(&parent)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or(0);
+        let root = parent.join(format!("run-{}-{}", std::process::id(), timestamp));
+        let journal = Journal::begin()?;
+        journal.record(&Entry::Created(root.clone()))?;
+        // create_dir fails if the folder exists, so two runs never share a workspace
+        if let Err(error) = std::fs::create_dir(&root) {
+            journal.finish()?;
+            return Err(error.into());
+        }
+        let workspace = Workspace { root, journal };
+        if let Err(error) = workspace.populate() {
+            workspace.remove()?;
+            return Err(error);
+        }
+        Ok(workspace)
+    }
+
+    // a workspace in root, without a game folder for studiomdl
+    #[cfg(test)]
+    pub fn at(root: PathBuf, journal: Journal) -> Self {
+        Workspace { root, journal }
+    }
+
+    fn populate(&self) -> Result<()> {
+        std::fs::create_dir_all(self.source_folder())?;
+        Self::create_game_folder(&self.game_folder(), &[])
+    }
+
+    fn create_game_folder(game_folder: &Path, search_paths: &[PathBuf]) -> Result<()> {
+        std::fs::create_dir_all(game_folder.join("models"))?;
+        let studiomdl = Tool::new(Sca::studiomdl_exe()?, discovery::runner()?);
+        let mut search_paths = search_paths.to_vec();
+        search_paths.push(Sca::tf_folder()?);
+        std::fs::write(
+            game_folder.join("gameinfo.txt"),
+            Self::gameinfo(&studiomdl, &search_paths),
+        )?;
+        Ok(())
+    }
+
+    // the other paths are only read from, studiomdl writes to the first game path
+    fn gameinfo(studiomdl: &Tool, search_paths: &[PathBuf]) -> String {
+        let search_paths = search_paths
+            .iter()
+            .map(|path| {
+                format!(
+                    "\t\t\tgame\t\"{}\"\n",
+                    studiomdl
+                        .path_arg(path)
+                        .to_string_lossy()
+                        .replace('\\', "/")
+                )
+            })
+            .collect::<String>();
+        format!(
+            "\"GameInfo\"\n\
+             {{\n\
+             \tgame\t\"v_customizer workspace\"\n\
+             \tFileSystem\n\
+             \t{{\n\
+             \t\tSteamAppId\t{}\n\
+             \t\tSearchPaths\n\
+             \t\t{{\n\
+             \t\t\tgame+mod\t\"|gameinfo_path|.\"\n\
+             {}\
+             \t\t}}\n\
+             \t}}\n\
+             }}\n",
+            discovery::TF2_APP_ID,
+            search_paths,
+        )
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    // the copied SCA classes
+    pub fn source_folder(&self) -> PathBuf {
+        self.root.join("src")
+    }
+
+    // what studiomdl gets as -game
+    pub fn game_folder(&self) -> PathBuf {
+        self.root.join("game")
+    }
+
+    pub fn models_folder(&self) -> PathBuf {
+        self.game_folder().join("models")
+    }
+
+    // "Scout Pistol" for the qc of an animation, "Scout" for the qc of a class
+    pub fn weapon_name(&self, qc: &Path) -> String {
+        qc.parent()
+            .and_then(|folder| folder.strip_prefix(self.source_folder()).ok())
+            .map(|folder| {
+                folder
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| qc.display().to_string())
+    }
+
+    // the folder that gets packed into the vpk, it holds models/...
+    pub fn output_folder(&self) -> PathBuf {
+        self.models_folder()
+            .join(OUTPUT_FOLDER_NAME)
+            .join(ADDON_NAME)
+    }
+
+    // a game folder of its own for one parallel compile, so jobs can't clobber each other's output,
+    // the models of finished jobs are readable from it through the shared game folder
+    pub fn create_job(&self, index: usize) -> Result<PathBuf> {
+        let game_folder = self
+            .root
+            .join(JOBS_FOLDER_NAME)
+            .join(index.to_string())
+            .join("game");
+        // a retried job starts over, without what its failed compile left behind
+        if game_folder.exists() {
+            std::fs::remove_dir_all(&game_folder)?;
+        }
+        Self::create_game_folder(&game_folder, &[self.game_folder()])?;
+        Ok(game_folder)
+    }
+
+    // moves the models a job compiled into the shared game folder, replacing older files
+    pub fn collect_job(&self, game_folder: &Path) -> Result<()> {
+        fs::move_folder(&game_folder.join("models"), &self.models_folder())
+    }
+
+    pub fn remove(self) -> Result<()> {
+        if !self
+            .root
+            .starts_with(std::env::temp_dir().join(WORKSPACES_FOLDER_NAME))
+        {
+            bail!(
+                "Refusing to delete {}, it isn't a workspace",
+                self.root.display()
+            );
+        }
+        self.journal.end()
+    }
+}
@@ -0,0 +1,450 @@
+use anyhow::{bail, Context, Result};
+
+use std::collections::HashSet;
+
+// a 3x3 rotation matrix, rows first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix(pub [[f32; 3]; 3]);
+
+impl Matrix {
+    // radians, rotated around x first, then y, then z, the way smd skeletons store bones
+    pub fn from_euler(x: f32, y: f32, z: f32) -> Self {
+        let (sx, cx) = x.sin_cos();
+        let (sy, cy) = y.sin_cos();
+        let (sz, cz) = z.sin_cos();
+        Matrix([
+            [cy * cz, sx * sy * cz - cx * sz, cx * sy * cz + sx * sz],
+            [cy * sz, sx * sy * sz + cx * cz, cx * sy * sz - sx * cz],
+            [-sy, sx * cy, cx * cy],
+        ])
+    }
+
+    pub fn to_euler(&self) -> [f32; 3] {
+        let m = &self.0;
+        let y = (-m[2][0]).clamp(-1.0, 1.0).asin();
+        if y.cos() > 1e-6 {
+            [m[2][1].atan2(m[2][2]), y, m[1][0].atan2(m[0][0])]
+        } else {
+            // x and z turn around the same axis here, so all of it goes to x
+            [(-m[1][2]).atan2(m[1][1]), y, 0.0]
+        }
+    }
+
+    pub fn mul(&self, other: &Matrix) -> Matrix {
+        let mut result = [[0.0; 3]; 3];
+        for (row, result_row) in result.iter_mut().enumerate() {
+            for (column, value) in result_row.iter_mut().enumerate() {
+                *value = (0..3).map(|i| self.0[row][i] * other.0[i][column]).sum();
+            }
+        }
+        Matrix(result)
+    }
+
+    // the inverse, since it's a rotation
+    pub fn transpose(&self) -> Matrix {
+        let m = &self.0;
+        Matrix([
+            [m[0][0], m[1][0], m[2][0]],
+            [m[0][1], m[1][1], m[2][1]],
+            [m[0][2], m[1][2], m[2][2]],
+        ])
+    }
+
+    pub fn apply(&self, vector: [f32; 3]) -> [f32; 3] {
+        let m = &self.0;
+        [
+            m[0][0] * vector[0] + m[0][1] * vector[1] + m[0][2] * vector[2],
+            m[1][0] * vector[0] + m[1][1] * vector[1] + m[1][2] * vector[2],
+            m[2][0] * vector[0] + m[2][1] * vector[1] + m[2][2] * vector[2],
+        ]
+    }
+}
+
+// a rotation and uniform scale of a whole model around its origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub rotation: Matrix,
+    pub scale: f32,
+}
+
+impl Transform {
+    // degrees, the way the game names them
+    //
+    // studiomdl turns smd space 90 degrees around z, so the game's pitch axis (y) is the smd
+    // x axis and its roll axis (x) is the smd -y axis, roll gets applied before pitch
+    pub fn from_angles(pitch: f32, roll: f32, scale: f32) -> Self {
+        let pitch = Matrix::from_euler(pitch.to_radians(), 0.0, 0.0);
+        let roll = Matrix::from_euler(0.0, -roll.to_radians(), 0.0);
+        Transform {
+            rotation: pitch.mul(&roll),
+            scale,
+        }
+    }
+
+    // a position in model space
+    pub fn point(&self, point: [f32; 3]) -> [f32; 3] {
+        self.rotation.apply(point.map(|value| value * self.scale))
+    }
+
+    // a direction in model space, normals don't scale
+    pub fn direction(&self, direction: [f32; 3]) -> [f32; 3] {
+        self.rotation.apply(direction)
+    }
+
+    // a root bone, in the parent space it has none of, so the whole bone turns
+    pub fn root_bone(&self, position: [f32; 3], rotation: &Matrix) -> ([f32; 3], Matrix) {
+        (self.point(position), self.rotation.mul(rotation))
+    }
+
+    // a child bone only moves further away from its parent
+    pub fn child_bone(&self, position: [f32; 3]) -> [f32; 3] {
+        position.map(|value| value * self.scale)
+    }
+}
+
+#[derive(PartialEq)]
+enum Section {
+    None,
+    Nodes,
+    Skeleton,
+    // counts the lines, every 4th is a material
+    Triangles(usize),
+    VertexAnimation,
+    Other,
+}
+
+// applies transform to everything in an smd that's in model space: the root bones of every
+// frame, the triangles and the vertex animations, lines that don't change are kept as they are
+pub fn transform(text: &str, transform: &Transform) -> Result<String> {
+    let mut roots = HashSet::new();
+    let mut section = Section::None;
+    let mut result = String::with_capacity(text.len());
+    for (index, line) in text.split_inclusive('\n').enumerate() {
+        let content = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[content.len()..];
+        let words = content.split_whitespace().collect::<Vec<&str>>();
+        let first = words.first().copied().unwrap_or("");
+        if words.is_empty() || first.starts_with("//") {
+            result.push_str(line);
+            continue;
+        }
+        let context = || format!("Line {}", index + 1);
+        let changed = match (&mut section, first) {
+            (Section::None, "nodes") => {
+                section = Section::Nodes;
+                None
+            }
+            (Section::None, "skeleton") => {
+                section = Section::Skeleton;
+                None
+            }
+            (Section::None, "triangles") => {
+                section = Section::Triangles(0);
+                None
+            }
+            (Section::None, "vertexanimation") => {
+                section = Section::VertexAnimation;
+                None
+            }
+            (Section::None, "version") => None,
+            (Section::None, _) => {
+                section = Section::Other;
+                None
+            }
+            (_, "end") => {
+                section = Section::None;
+                None
+            }
+            (Section::Nodes, _) => {
+                // the name is quoted and can have spaces, the parent is always last
+                if words.last() == Some(&"-1") {
+                    roots.insert(first.to_owned());
+                }
+                None
+            }
+            (Section::Skeleton, "time") | (Section::VertexAnimation, "time") => None,
+            (Section::Skeleton, bone) => {
+                let values = numbers(&words[1..], 6).with_context(context)?;
+                let position = [values[0], values[1], values[2]];
+                let (position, rotation) = if roots.contains(bone) {
+                    let rotation = Matrix::from_euler(values[3], values[4], values[5]);
+                    let (position, rotation) = transform.root_bone(position, &rotation);
+                    (position, rotation.to_euler())
+                } else {
+                    (
+                        transform.child_bone(position),
+                        [values[3], values[4], values[5]],
+                    )
+                };
+                Some(join(bone, &[&position[..], &rotation[..]].concat(), &[]))
+            }
+            (Section::Triangles(count), _) => {
+                let is_material = *count % 4 == 0;
+                *count += 1;
+                if is_material {
+                    None
+                } else {
+                    // parent bone, position, normal, uv and the optional weights
+                    let values = numbers(&words[1..], 6).with_context(context)?;
+                    let position = transform.point([values[0], values[1], values[2]]);
+                    let normal = transform.direction([values[3], values[4], values[5]]);
+                    Some(join(
+                        first,
+                        &[&position[..], &normal[..]].concat(),
+                        &words[7..],
+                    ))
+                }
+            }
+            (Section::VertexAnimation, vertex) => {
+                let values = numbers(&words[1..], 6).with_context(context)?;
+                let position = transform.point([values[0], values[1], values[2]]);
+                let normal = transform.direction([values[3], values[4], values[5]]);
+                Some(join(
+                    vertex,
+                    &[&position[..], &normal[..]].concat(),
+                    &words[7..],
+                ))
+            }
+            (Section::Other, _) => None,
+        };
+        match changed {
+            Some(content) => {
+                result.push_str(&content);
+                result.push_str(ending);
+            }
+            None => result.push_str(line),
+        }
+    }
+    Ok(result)
+}
+
+// the first count words as numbers
+fn numbers(words: &[&str], count: usize) -> Result<Vec<f32>> {
+    if words.len() < count {
+        bail!("Expected {} numbers, found {}", count, words.len());
+    }
+    words[..count]
+        .iter()
+        .map(|word| {
+            word.parse::<f32>()
+                .with_context(|| format!("{} isn't a number", word))
+        })
+        .collect()
+}
+
+// 6 decimals like the smd exporters write them, without -0.000000
+pub fn number(value: f32) -> String {
+    if value.abs() < 0.0000005 {
+        return "0.000000".to_owned();
+    }
+    format!("{:.6}", value)
+}
+
+fn join(first: &str, values: &[f32], rest: &[&str]) -> String {
+    let mut line = first.to_owned();
+    for value in values {
+        line.push(' ');
+        line.push_str(&number(*value));
+    }
+    for word in rest {
+        line.push(' ');
+        line.push_str(word);
+    }
+    line
+}
+
+// a bone in the first frame of an smd, which studiomdl uses as the reference pose
+#[derive(Clone, Debug)]
+pub struct Bone {
+    pub name: String,
+    pub parent: Option<String>,
+    pub position: [f32; 3],
+    pub rotation: Matrix,
+}
+
+// the bones of the nodes section, posed like the first skeleton frame
+pub fn skeleton(text: &str) -> Result<Vec<Bone>> {
+    // id, name and parent id as written
+    let mut nodes: Vec<(String, String, String)> = Vec::new();
+    let mut bones = Vec::new();
+    let mut section = Section::None;
+    let mut frames = 0;
+    for (index, line) in text.lines().enumerate() {
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        let first = words.first().copied().unwrap_or("");
+        if words.is_empty() || first.starts_with("//") {
+            continue;
+        }
+        match (&section, first) {
+            (Section::None, "nodes") => section = Section::Nodes,
+            (Section::None, "skeleton") => section = Section::Skeleton,
+            (Section::None, "version") => {}
+            (Section::None, _) => section = Section::Other,
+            (_, "end") => section = Section::None,
+            (Section::Nodes, id) => {
+                // the name is quoted and can have spaces, the parent is always last
+                let name = match (line.find('"'), line.rfind('"')) {
+                    (Some(start), Some(end)) if start < end => &line[start + 1..end],
+                    _ => words.get(1).copied().unwrap_or(""),
+                };
+                let parent = words.last().copied().unwrap_or("-1");
+                nodes.push((id.to_owned(), name.to_owned(), parent.to_owned()));
+            }
+            (Section::Skeleton, "time") => frames += 1,
+            (Section::Skeleton, id) if frames == 1 => {
+                let values =
+                    numbers(&words[1..], 6).with_context(|| format!("Line {}", index + 1))?;
+                let name_of = |id: &str| {
+                    nodes
+                        .iter()
+                        .find(|(node, _, _)| node == id)
+                        .map(|(_, name, _)| name.clone())
+                };
+                let (name, parent) = match nodes.iter().find(|(node, _, _)| node == id) {
+                    Some((_, name, parent)) => (name.clone(), name_of(parent)),
+                    None => bail!("Line {}: there's no node {}", index + 1, id),
+                };
+                bones.push(Bone {
+                    name,
+                    parent,
+                    position: [values[0], values[1], values[2]],
+                    rotation: Matrix::from_euler(values[3], values[4], values[5]),
+                });
+            }
+            _ => {}
+        }
+    }
+    if bones.is_empty() {
+        bail!("There's no skeleton");
+    }
+    Ok(bones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMD: &str = "version 1
+nodes
+0 \"root\" -1
+1 \"arm bone\" 0
+end
+skeleton
+time 0
+0 2.000000 -3.000000 40.000000 0.100000 -0.200000 1.500000
+1 0.000000 10.000000 0.000000 0.300000 0.000000 0.000000
+end
+triangles
+arms
+0 1.000000 2.000000 3.000000 0.000000 0.000000 1.000000 0.500000 0.500000 1 0 1.000000
+1 4.000000 5.000000 6.000000 1.000000 0.000000 0.000000 0.250000 0.750000
+0 7.000000 8.000000 9.000000 0.000000 1.000000 0.000000 0.000000 1.000000
+end
+";
+
+    // the numbers of a line of text, after the first word
+    fn line(text: &str, index: usize) -> Vec<f32> {
+        text.lines()
+            .nth(index)
+            .unwrap()
+            .split_whitespace()
+            .skip(1)
+            .take(6)
+            .map(|word| word.parse().unwrap())
+            .collect()
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4, "{:?} isn't {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn euler_round_trip() {
+        let angles = [
+            [0.0, 0.0, 0.0],
+            [0.1, -0.2, 1.5],
+            [-1.2, 0.7, -3.0],
+            [3.0, -1.5, 0.4],
+        ];
+        for [x, y, z] in angles {
+            let matrix = Matrix::from_euler(x, y, z);
+            let [x2, y2, z2] = matrix.to_euler();
+            assert_close(&[x2, y2, z2], &[x, y, z]);
+            // a rotation times its transpose is the identity
+            let identity = matrix.mul(&matrix.transpose());
+            assert_close(
+                &identity.0.concat(),
+                &[1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            );
+        }
+        // straight up, x and z turn around the same axis so only the matrix has to match
+        let matrix = Matrix::from_euler(0.4, std::f32::consts::FRAC_PI_2, 0.3);
+        let [x, y, z] = matrix.to_euler();
+        assert_close(&Matrix::from_euler(x, y, z).0.concat(), &matrix.0.concat());
+    }
+
+    #[test]
+    fn angles_turn_around_the_game_axes() {
+        // pitch turns around smd x, so y goes up
+        let pitch = Transform::from_angles(90.0, 0.0, 1.0);
+        assert_close(&pitch.point([0.0, 1.0, 0.0]), &[0.0, 0.0, 1.0]);
+        // roll turns around smd -y
+        let roll = Transform::from_angles(0.0, 90.0, 1.0);
+        assert_close(&roll.point([1.0, 0.0, 0.0]), &[0.0, 0.0, 1.0]);
+        let scaled = Transform::from_angles(0.0, 0.0, 2.0);
+        assert_close(&scaled.point([1.0, -2.0, 3.0]), &[2.0, -4.0, 6.0]);
+    }
+
+    #[test]
+    fn identity_changes_nothing() {
+        let identity = Transform::from_angles(0.0, 0.0, 1.0);
+        assert_eq!(transform(SMD, &identity).unwrap(), SMD);
+        let crlf = SMD.replace('\n', "\r\n");
+        assert_eq!(transform(&crlf, &identity).unwrap(), crlf);
+    }
+
+    #[test]
+    fn skeleton_moves_root_bones_only() {
+        let turned = Transform::from_angles(30.0, -15.0, 1.5);
+        let result = transform(SMD, &turned).unwrap();
+        let root = line(&result, 7);
+        let (position, rotation) =
+            turned.root_bone([2.0, -3.0, 40.0], &Matrix::from_euler(0.1, -0.2, 1.5));
+        assert_close(&root[..3], &position);
+        let rotation = rotation.to_euler();
+        assert_close(&root[3..], &rotation);
+        // the child keeps its rotation and only moves further from its parent
+        assert_close(&line(&result, 8), &[0.0, 15.0, 0.0, 0.3, 0.0, 0.0]);
+        // the rest stays as it was
+        assert!(result.starts_with(
+            "version 1\nnodes\n0 \"root\" -1\n1 \"arm bone\" 0\nend\nskeleton\ntime 0\n"
+        ));
+    }
+
+    #[test]
+    fn normals_turn_but_dont_scale() {
+        let turned = Transform::from_angles(90.0, 0.0, 2.0);
+        let result = transform(SMD, &turned).unwrap();
+        let vertex = line(&result, 12);
+        // position turned and scaled, normal only turned, uv and weights kept
+        assert_close(&vertex[..3], &[2.0, -6.0, 4.0]);
+        assert_close(&vertex[3..6], &[0.0, -1.0, 0.0]);
+        assert!(result.contains(" 0.500000 0.500000 1 0 1.000000\n"));
+        let vertex = line(&result, 14);
+        assert_close(&vertex[3..6], &[0.0, 0.0, 1.0]);
+        assert!(result.contains("\narms\n"));
+    }
+
+    #[test]
+    fn reads_the_reference_skeleton() {
+        let bones = skeleton(SMD).unwrap();
+        assert_eq!(bones.len(), 2);
+        assert_eq!(bones[1].name, "arm bone");
+        assert_eq!(bones[1].parent.as_deref(), Some("root"));
+        assert_eq!(bones[0].parent, None);
+        assert!(skeleton("version 1\nnodes\nend\n").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+pub mod reader;
+pub mod writer;
+
+pub use reader::{Entry, VpkReader};
+pub use writer::VpkWriter;
+
+pub const SIGNATURE: u32 = 0x55aa_1234;
+// the archive index of entries whose data follows the tree in the _dir.vpk itself
+pub const DIR_ARCHIVE_INDEX: u16 = 0x7fff;
+pub const ENTRY_TERMINATOR: u16 = 0xffff;
+// vpk.exe starts a new archive once one gets this big
+pub const DEFAULT_CHUNK_SIZE: u64 = 200 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V1,
+    // has a file data section size and md5/signature sections after the data, which stay empty
+    V2,
+}
+
+impl Version {
+    pub fn number(&self) -> u32 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+        }
+    }
+
+    pub fn header_size(&self) -> u32 {
+        match self {
+            Version::V1 => 12,
+            Version::V2 => 28,
+        }
+    }
+}
+
+// vpk paths use / and are lower case, the engine looks them up that way
+pub fn normalize_path<T: AsRef<str>>(path: T) -> String {
+    path.as_ref()
+        .replace('\\', "/")
+        .trim_start_matches('/')
+        .to_lowercase()
+}
+
+// splits a path into the extension, folder and file name the tree is built from,
+// missing parts are written as a single space
+pub fn split_path(path: &str) -> (String, String, String) {
+    let (folder, file) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    };
+    let (name, extension) = match file.rfind('.') {
+        Some(i) => (&file[..i], &file[i + 1..]),
+        None => (file, ""),
+    };
+    let or_space = |part: &str| {
+        if part.is_empty() {
+            " ".to_owned()
+        } else {
+            part.to_owned()
+        }
+    };
+    (or_space(extension), or_space(folder), or_space(name))
+}
+
+// the file names of a package, foo.vpk or foo_dir.vpk with foo_000.vpk, foo_001.vpk, ...
+pub fn dir_file_name(name: &str, chunked: bool) -> String {
+    if chunked {
+        format!("{}_dir.vpk", name)
+    } else {
+        format!("{}.vpk", name)
+    }
+}
+
+pub fn archive_file_name(name: &str, index: u16) -> String {
+    format!("{}_{:03}.vpk", name, index)
+}
@@ -0,0 +1,451 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+use super::{archive_file_name, normalize_path, Version, DIR_ARCHIVE_INDEX, SIGNATURE};
+
+// one file in a vpk
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    // normalized, like models/weapons/c_models/c_scattergun.mdl
+    pub path: String,
+    pub crc: u32,
+    // the first bytes of the file, stored in the tree
+    pub preload: Vec<u8>,
+    pub archive_index: u16,
+    pub offset: u32,
+    // what's left after the preload bytes
+    pub length: u32,
+}
+
+impl Entry {
+    pub fn size(&self) -> u64 {
+        self.preload.len() as u64 + self.length as u64
+    }
+}
+
+// an opened .vpk or _dir.vpk, the tree is read at once and the data only when it's asked for
+pub struct VpkReader {
+    path: PathBuf,
+    version: Version,
+    // where the data of DIR_ARCHIVE_INDEX entries starts in the dir file
+    data_offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl VpkReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let read = || -> Result<Self> {
+            let mut file = File::open(path)?;
+            let mut header = [0; 12];
+            file.read_exact(&mut header)
+                .context("The file is too short for a vpk")?;
+            if u32_at(&header, 0) != SIGNATURE {
+                bail!("It isn't a vpk");
+            }
+            let version = match u32_at(&header, 4) {
+                1 => Version::V1,
+                2 => Version::V2,
+                version => bail!("Unsupported vpk version {}", version),
+            };
+            let tree_size = u32_at(&header, 8);
+            // a broken header can claim a tree of gigabytes, don't allocate more than there is
+            if version.header_size() as u64 + tree_size as u64 > file.metadata()?.len() {
+                bail!("The tree is cut off");
+            }
+            file.seek(SeekFrom::Start(version.header_size() as u64))?;
+            let mut tree = vec![0; tree_size as usize];
+            file.read_exact(&mut tree).context("The tree is cut off")?;
+            Ok(VpkReader {
+                path: path.to_path_buf(),
+                version,
+                data_offset: version.header_size() as u64 + tree_size as u64,
+                entries: parse_tree(&tree)?,
+            })
+        };
+        read().with_context(|| format!("Can't read {}", path.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    // in the order of the tree
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn find(&self, path: &str) -> Option<&Entry> {
+        let path = normalize_path(path);
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    // the entries in folder and everything below it, or the single entry named folder,
+    // an empty folder is everything
+    pub fn entries_in(&self, folder: &str) -> Vec<&Entry> {
+        let folder = normalize_path(folder);
+        let folder = folder.trim_end_matches('/');
+        self.entries
+            .iter()
+            .filter(|entry| {
+                folder.is_empty()
+                    || entry.path == folder
+                    || entry
+                        .path
+                        .strip_prefix(folder)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+            .collect()
+    }
+
+    // the whole file, preload bytes first
+    pub fn read(&self, entry: &Entry) -> Result<Vec<u8>> {
+        let mut data = entry.preload.clone();
+        if entry.length == 0 {
+            return Ok(data);
+        }
+        let (archive, offset) = if entry.archive_index == DIR_ARCHIVE_INDEX {
+            (self.path.clone(), self.data_offset + entry.offset as u64)
+        } else {
+            (self.archive_path(entry.archive_index)?, entry.offset as u64)
+        };
+        let cut_off = || format!("{} is cut off in {}", entry.path, archive.display());
+        let mut file =
+            File::open(&archive).with_context(|| format!("Can't open {}", archive.display()))?;
+        if offset + entry.length as u64 > file.metadata()?.len() {
+            bail!(cut_off());
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let start = data.len();
+        data.resize(start + entry.length as usize, 0);
+        file.read_exact(&mut data[start..]).with_context(cut_off)?;
+        Ok(data)
+    }
+
+    // writes the entries in folder below destination, keeping their paths,
+    // returns how many files got written
+    pub fn extract<P: AsRef<Path>>(&self, folder: &str, destination: P) -> Result<usize> {
+        let destination = destination.as_ref();
+        let entries = self.entries_in(folder);
+        if entries.is_empty() {
+            bail!("{} has nothing in {}", self.path.display(), folder);
+        }
+        // a broken or malicious tree could point outside of destination otherwise,
+        // checked before anything gets written
+        for entry in &entries {
+            if !Path::new(&entry.path)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                bail!("{} isn't a safe path to extract", entry.path);
+            }
+        }
+        for entry in &entries {
+            let target = destination.join(&entry.path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&target, self.read(entry)?)
+                .with_context(|| format!("Can't write {}", target.display()))?;
+        }
+        Ok(entries.len())
+    }
+
+    // reads every entry and checks it against its crc,
+    // returns what's wrong, an empty list means the vpk is fine
+    pub fn verify(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match self.read(entry) {
+                Ok(data) if crc32fast::hash(&data) == entry.crc => None,
+                Ok(_) => Some(format!("{} doesn't match its crc", entry.path)),
+                Err(error) => Some(format!("{:#}", error)),
+            })
+            .collect()
+    }
+
+    // foo_dir.vpk keeps its data in foo_000.vpk, foo_001.vpk, ...
+    fn archive_path(&self, index: u16) -> Result<PathBuf> {
+        let stem = self
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_suffix("_dir"))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} isn't a _dir.vpk but points into archive {}",
+                    self.path.display(),
+                    index
+                )
+            })?;
+        Ok(self.path.with_file_name(archive_file_name(stem, index)))
+    }
+}
+
+// extensions, each with its folders, each with its files, every list ends with an empty string
+fn parse_tree(tree: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut i = 0;
+    loop {
+        let extension = string(tree, &mut i)?;
+        if extension.is_empty() {
+            break;
+        }
+        loop {
+            let folder = string(tree, &mut i)?;
+            if folder.is_empty() {
+                break;
+            }
+            loop {
+                let name = string(tree, &mut i)?;
+                if name.is_empty() {
+                    break;
+                }
+                let fields = tree
+                    .get(i..i + 18)
+                    .ok_or_else(|| anyhow!("The tree is cut off in {}", name))?;
+                i += 18;
+                let preload_size = u16_at(fields, 4) as usize;
+                let preload = tree
+                    .get(i..i + preload_size)
+                    .ok_or_else(|| anyhow!("The preload data of {} is cut off", name))?;
+                i += preload_size;
+                entries.push(Entry {
+                    // other tools write the tree with capitals or backslashes, find looks up
+                    // normalized paths
+                    path: normalize_path(join_path(&extension, &folder, &name)),
+                    crc: u32_at(fields, 0),
+                    preload: preload.to_vec(),
+                    archive_index: u16_at(fields, 6),
+                    offset: u32_at(fields, 8),
+                    length: u32_at(fields, 12),
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// the reverse of split_path, a single space stands for a missing part
+fn join_path(extension: &str, folder: &str, name: &str) -> String {
+    let mut path = String::new();
+    if folder != " " {
+        path.push_str(folder);
+        path.push('/');
+    }
+    if name != " " {
+        path.push_str(name);
+    }
+    if extension != " " {
+        path.push('.');
+        path.push_str(extension);
+    }
+    path
+}
+
+fn string(tree: &[u8], i: &mut usize) -> Result<String> {
+    let length = tree[(*i).min(tree.len())..]
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or_else(|| anyhow!("The tree is cut off"))?;
+    let string = String::from_utf8_lossy(&tree[*i..*i + length]).into_owned();
+    *i += length + 1;
+    Ok(string)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::VpkWriter;
+    use super::*;
+    use crate::fs::test_folder;
+
+    // a chunked vpk, so reads go through the archives
+    fn write(folder: &Path, extra: &[(&str, &str)]) -> PathBuf {
+        let mut vpk = VpkWriter::new(Version::V2).chunked(16);
+        let files = [
+            ("models/weapons/v_bat.mdl", "bat model"),
+            ("models/weapons/v_bat.vvd", "bat vertices"),
+            ("models/weapons/c_models/c_scattergun.mdl", "scattergun"),
+            ("materials/bat.vmt", "material"),
+        ];
+        for (path, data) in files.iter().chain(extra) {
+            vpk.add_data(path, data.as_bytes().to_vec()).unwrap();
+        }
+        vpk.write(folder, "pak").unwrap().remove(0)
+    }
+
+    #[test]
+    fn list_and_find() {
+        let folder = test_folder("reader_list");
+        let reader = VpkReader::open(write(&folder, &[])).unwrap();
+        assert_eq!(reader.entries().len(), 4);
+        let bat = reader.find("Models\\Weapons\\V_Bat.mdl").unwrap();
+        assert_eq!(bat.path, "models/weapons/v_bat.mdl");
+        assert_eq!(bat.size(), "bat model".len() as u64);
+        assert!(reader.find("models/weapons/v_bat").is_none());
+        let paths = |folder: &str| {
+            let mut paths = reader
+                .entries_in(folder)
+                .into_iter()
+                .map(|entry| entry.path.clone())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            paths("models/weapons/"),
+            [
+                "models/weapons/c_models/c_scattergun.mdl",
+                "models/weapons/v_bat.mdl",
+                "models/weapons/v_bat.vvd"
+            ]
+        );
+        // only whole folder names
+        assert!(paths("models/weap").is_empty());
+        assert_eq!(paths("materials/bat.vmt"), ["materials/bat.vmt"]);
+        assert_eq!(paths("").len(), 4);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn extract() {
+        let folder = test_folder("reader_extract");
+        let reader = VpkReader::open(write(&folder, &[])).unwrap();
+        let file = folder.join("file");
+        assert_eq!(reader.extract("materials/bat.vmt", &file).unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(file.join("materials/bat.vmt")).unwrap(),
+            "material"
+        );
+        let tree = folder.join("tree");
+        assert_eq!(reader.extract("models", &tree).unwrap(), 3);
+        assert_eq!(
+            std::fs::read_to_string(tree.join("models/weapons/c_models/c_scattergun.mdl")).unwrap(),
+            "scattergun"
+        );
+        assert!(!tree.join("materials").exists());
+        assert!(reader.extract("sound", &tree).is_err());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn extract_stays_in_destination() {
+        let folder = test_folder("reader_unsafe");
+        let reader = VpkReader::open(write(&folder, &[("../evil.txt", "evil")])).unwrap();
+        assert!(reader.find("../evil.txt").is_some());
+        let destination = folder.join("out").join("inner");
+        let error = reader.extract("", &destination).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "../evil.txt isn't a safe path to extract"
+        );
+        assert!(!folder.join("out").exists());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn verify_finds_a_flipped_byte() {
+        let folder = test_folder("reader_verify");
+        let dir = write(&folder, &[]);
+        assert!(VpkReader::open(&dir).unwrap().verify().is_empty());
+        let scattergun = VpkReader::open(&dir)
+            .unwrap()
+            .find("models/weapons/c_models/c_scattergun.mdl")
+            .cloned()
+            .unwrap();
+        let archive = folder.join(archive_file_name("pak", scattergun.archive_index));
+        let mut bytes = std::fs::read(&archive).unwrap();
+        bytes[scattergun.offset as usize + 2] ^= 0x20;
+        std::fs::write(&archive, bytes).unwrap();
+        assert_eq!(
+            VpkReader::open(&dir).unwrap().verify(),
+            ["models/weapons/c_models/c_scattergun.mdl doesn't match its crc"]
+        );
+        // and a missing archive
+        std::fs::remove_file(&archive).unwrap();
+        assert!(!VpkReader::open(&dir).unwrap().verify().is_empty());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn paths_written_by_other_tools() {
+        let folder = test_folder("reader_paths");
+        let dir = write(&folder, &[]);
+        let mut bytes = std::fs::read(&dir).unwrap();
+        let replace = |bytes: &mut Vec<u8>, from: &[u8], to: &[u8]| {
+            let at = bytes
+                .windows(from.len())
+                .position(|window| window == from)
+                .unwrap();
+            bytes[at..at + to.len()].copy_from_slice(to);
+        };
+        replace(
+            &mut bytes,
+            b"models/weapons/c_models\0",
+            b"Models\\Weapons\\C_Models\0",
+        );
+        replace(&mut bytes, b"c_scattergun\0", b"C_Scattergun\0");
+        std::fs::write(&dir, bytes).unwrap();
+        let reader = VpkReader::open(&dir).unwrap();
+        let scattergun = reader.find("models/weapons/c_models/c_scattergun.mdl");
+        assert_eq!(
+            scattergun.unwrap().path,
+            "models/weapons/c_models/c_scattergun.mdl"
+        );
+        assert_eq!(reader.entries_in("models/weapons/c_models").len(), 1);
+        assert!(reader.verify().is_empty());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn sizes_past_the_end() {
+        let folder = test_folder("reader_sizes");
+        let dir = write(&folder, &[]);
+        let bytes = std::fs::read(&dir).unwrap();
+        // a tree of 4 gigabytes
+        let mut huge = bytes.clone();
+        huge[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&dir, huge).unwrap();
+        let error = VpkReader::open(&dir).err().unwrap();
+        assert!(format!("{:#}", error).contains("The tree is cut off"));
+
+        std::fs::write(&dir, bytes).unwrap();
+        let reader = VpkReader::open(&dir).unwrap();
+        let mut bat = reader.find("models/weapons/v_bat.mdl").cloned().unwrap();
+        bat.length = u32::MAX;
+        let error = reader.read(&bat).unwrap_err().to_string();
+        assert!(
+            error.starts_with("models/weapons/v_bat.mdl is cut off"),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let folder = test_folder("reader_reject");
+        let path = folder.join("not.vpk");
+        std::fs::write(&path, b"definitely not a vpk").unwrap();
+        assert!(VpkReader::open(&path).is_err());
+        std::fs::write(&path, b"12").unwrap();
+        assert!(VpkReader::open(&path).is_err());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
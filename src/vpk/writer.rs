@@ -0,0 +1,476 @@
+use anyhow::{bail, Context, Result};
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::{
+    archive_file_name, dir_file_name, normalize_path, split_path, Version, DIR_ARCHIVE_INDEX,
+    ENTRY_TERMINATOR, SIGNATURE,
+};
+use crate::fs;
+
+enum Source {
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+impl Source {
+    fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            Source::File(path) => {
+                std::fs::read(path).with_context(|| format!("Can't read {}", path.display()))
+            }
+            Source::Data(data) => Ok(data.clone()),
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        match self {
+            Source::File(path) => Ok(std::fs::metadata(path)
+                .with_context(|| format!("Can't read {}", path.display()))?
+                .len()),
+            Source::Data(data) => Ok(data.len() as u64),
+        }
+    }
+}
+
+struct Entry {
+    source: Source,
+    preload: u16,
+}
+
+// where an entry ends up, worked out before anything gets written
+struct Layout {
+    crc: u32,
+    preload: Vec<u8>,
+    archive_index: u16,
+    offset: u32,
+    length: u32,
+}
+
+// builds a vpk out of files on disk or in memory
+//
+// the tree is sorted by extension, folder and file name, and the data follows in that order,
+// so the same files always give the same bytes
+pub struct VpkWriter {
+    version: Version,
+    chunk_size: Option<u64>,
+    entries: BTreeMap<(String, String, String), Entry>,
+}
+
+impl VpkWriter {
+    pub fn new(version: Version) -> Self {
+        VpkWriter {
+            version,
+            chunk_size: None,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    // puts the data into _000.vpk, _001.vpk, ... archives of at most chunk_size bytes
+    // next to a _dir.vpk, a single file bigger than that gets an archive of its own
+    pub fn chunked(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: &str, file: P) -> Result<()> {
+        self.add(path, Source::File(file.as_ref().to_path_buf()))
+    }
+
+    pub fn add_data(&mut self, path: &str, data: Vec<u8>) -> Result<()> {
+        self.add(path, Source::Data(data))
+    }
+
+    // every file under folder, with paths relative to it
+    pub fn add_folder<P: AsRef<Path>>(&mut self, folder: P) -> Result<()> {
+        let folder = folder.as_ref();
+        for path in fs::files(folder)? {
+            self.add_file(&fs::relative_path(&path, folder), &path)?;
+        }
+        Ok(())
+    }
+
+    // the first preload bytes of the file get stored in the tree, so the engine has them
+    // as soon as the directory is loaded
+    pub fn set_preload(&mut self, path: &str, preload: u16) -> Result<()> {
+        match self.entries.get_mut(&split_path(&normalize_path(path))) {
+            Some(entry) => entry.preload = preload,
+            None => bail!("{} isn't in the vpk", path),
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries
+            .contains_key(&split_path(&normalize_path(path)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn add(&mut self, path: &str, source: Source) -> Result<()> {
+        let normalized = normalize_path(path);
+        if normalized.is_empty() || normalized.ends_with('/') {
+            bail!("{} isn't a file path", path);
+        }
+        let key = split_path(&normalized);
+        if self.entries.contains_key(&key) {
+            bail!("{} is in the vpk twice", normalized);
+        }
+        self.entries.insert(key, Entry { source, preload: 0 });
+        Ok(())
+    }
+
+    // writes folder/name.vpk, or folder/name_dir.vpk and its archives when chunked,
+    // returns the files that got written with the _dir.vpk or .vpk first
+    pub fn write<P: AsRef<Path>>(&self, folder: P, name: &str) -> Result<Vec<PathBuf>> {
+        let folder = folder.as_ref();
+        let mut layouts = self.layout()?;
+        // the crcs and preload bytes get filled in as the files are read, the size stays
+        let tree_size = self.tree(&layouts).len() as u64;
+        let tree_size = u32::try_from(tree_size).context("The vpk tree is too big")?;
+        let dir_data_size = layouts
+            .iter()
+            .filter(|layout| layout.archive_index == DIR_ARCHIVE_INDEX)
+            .map(|layout| layout.length as u64)
+            .sum::<u64>();
+        let dir_data_size = u32::try_from(dir_data_size).context("The vpk is too big")?;
+
+        let dir_path = folder.join(dir_file_name(name, self.chunk_size.is_some()));
+        let mut written = vec![dir_path.clone()];
+        let mut dir = BufWriter::new(File::create(&dir_path)?);
+        // every file is read once, the header and tree go in front of its data at the end
+        dir.seek(SeekFrom::Start(
+            self.version.header_size() as u64 + tree_size as u64,
+        ))?;
+        let mut archive: Option<(u16, BufWriter<File>)> = None;
+        for (entry, layout) in self.entries.values().zip(layouts.iter_mut()) {
+            let data = entry.source.read()?;
+            if data.len() as u64 != layout.preload.len() as u64 + layout.length as u64 {
+                if let Source::File(path) = &entry.source {
+                    bail!("{} changed while the vpk was written", path.display());
+                }
+            }
+            layout.crc = crc32fast::hash(&data);
+            layout.preload = data[..layout.preload.len()].to_vec();
+            if layout.length == 0 {
+                continue;
+            }
+            let rest = &data[layout.preload.len()..];
+            if layout.archive_index == DIR_ARCHIVE_INDEX {
+                dir.write_all(rest)?;
+                continue;
+            }
+            if archive.as_ref().map(|(index, _)| *index) != Some(layout.archive_index) {
+                if let Some((_, mut file)) = archive.take() {
+                    file.flush()?;
+                }
+                let path = folder.join(archive_file_name(name, layout.archive_index));
+                archive = Some((layout.archive_index, BufWriter::new(File::create(&path)?)));
+                written.push(path);
+            }
+            archive.as_mut().unwrap().1.write_all(rest)?;
+        }
+        if let Some((_, mut file)) = archive {
+            file.flush()?;
+        }
+        dir.seek(SeekFrom::Start(0))?;
+        dir.write_all(&SIGNATURE.to_le_bytes())?;
+        dir.write_all(&self.version.number().to_le_bytes())?;
+        dir.write_all(&tree_size.to_le_bytes())?;
+        if self.version == Version::V2 {
+            dir.write_all(&dir_data_size.to_le_bytes())?;
+            // archive md5, other md5 and signature sections
+            for _ in 0..3 {
+                dir.write_all(&0u32.to_le_bytes())?;
+            }
+        }
+        dir.write_all(&self.tree(&layouts))?;
+        dir.flush()?;
+        Ok(written)
+    }
+
+    // decides where the data of every entry goes from the sizes alone, crc and preload bytes
+    // are placeholders until write reads the files
+    fn layout(&self) -> Result<Vec<Layout>> {
+        let mut layouts = Vec::new();
+        let mut dir_offset = 0u64;
+        let mut archive_index = 0u16;
+        let mut archive_offset = 0u64;
+        for entry in self.entries.values() {
+            let size = entry.source.size()?;
+            let preload = size.min(entry.preload as u64);
+            let length = size - preload;
+            let (index, offset) = match self.chunk_size {
+                Some(_) if length == 0 => (DIR_ARCHIVE_INDEX, 0),
+                Some(chunk_size) => {
+                    if archive_offset > 0 && archive_offset + length > chunk_size {
+                        archive_index += 1;
+                        archive_offset = 0;
+                        if archive_index >= DIR_ARCHIVE_INDEX {
+                            bail!("The vpk needs too many archives");
+                        }
+                    }
+                    archive_offset += length;
+                    (archive_index, archive_offset - length)
+                }
+                None => {
+                    dir_offset += length;
+                    (DIR_ARCHIVE_INDEX, dir_offset - length)
+                }
+            };
+            layouts.push(Layout {
+                crc: 0,
+                preload: vec![0; preload as usize],
+                archive_index: index,
+                offset: u32::try_from(offset).context("The vpk is too big")?,
+                length: u32::try_from(length).context("A file is too big for a vpk")?,
+            });
+        }
+        Ok(layouts)
+    }
+
+    // extensions, each with its folders, each with its files, every list ends with an empty string
+    fn tree(&self, layouts: &[Layout]) -> Vec<u8> {
+        let mut tree = Vec::new();
+        let mut current: Option<(&str, &str)> = None;
+        for ((extension, folder, name), layout) in self.entries.keys().zip(layouts) {
+            match current {
+                Some((current_extension, current_folder))
+                    if current_extension == extension && current_folder == folder => {}
+                Some((current_extension, _)) if current_extension == extension => {
+                    tree.push(0);
+                    push_string(&mut tree, folder);
+                }
+                previous => {
+                    if previous.is_some() {
+                        tree.extend([0, 0]);
+                    }
+                    push_string(&mut tree, extension);
+                    push_string(&mut tree, folder);
+                }
+            }
+            current = Some((extension, folder));
+            push_string(&mut tree, name);
+            tree.extend(layout.crc.to_le_bytes());
+            tree.extend((layout.preload.len() as u16).to_le_bytes());
+            tree.extend(layout.archive_index.to_le_bytes());
+            tree.extend(layout.offset.to_le_bytes());
+            tree.extend(layout.length.to_le_bytes());
+            tree.extend(ENTRY_TERMINATOR.to_le_bytes());
+            tree.extend(&layout.preload);
+        }
+        if current.is_some() {
+            tree.extend([0, 0]);
+        }
+        tree.push(0);
+        tree
+    }
+}
+
+fn push_string(buffer: &mut Vec<u8>, string: &str) {
+    buffer.extend(string.as_bytes());
+    buffer.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::VpkReader;
+    use super::*;
+    use crate::fs::test_folder;
+    use std::convert::TryInto;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn writer(version: Version) -> VpkWriter {
+        let mut vpk = VpkWriter::new(version);
+        vpk.add_data("models/weapons/v_bat.mdl", b"IDST bat".to_vec())
+            .unwrap();
+        vpk.add_data("Models\\Weapons\\v_bat.vvd", b"IDSV".to_vec())
+            .unwrap();
+        vpk.add_data("readme", b"no extension".to_vec()).unwrap();
+        vpk
+    }
+
+    fn check_contents(reader: &VpkReader) {
+        let paths = reader
+            .entries()
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<Vec<_>>();
+        // sorted by extension first
+        assert_eq!(
+            paths,
+            [
+                "readme",
+                "models/weapons/v_bat.mdl",
+                "models/weapons/v_bat.vvd"
+            ]
+        );
+        let bat = reader.find("models/weapons/v_bat.mdl").unwrap();
+        assert_eq!(reader.read(bat).unwrap(), b"IDST bat");
+        assert_eq!(bat.crc, crc32fast::hash(b"IDST bat"));
+        assert!(reader.verify().is_empty());
+    }
+
+    #[test]
+    fn v1() {
+        let folder = test_folder("writer_v1");
+        let written = writer(Version::V1).write(&folder, "pak").unwrap();
+        assert_eq!(written, [folder.join("pak.vpk")]);
+        let bytes = std::fs::read(&written[0]).unwrap();
+        assert_eq!(u32_at(&bytes, 0), SIGNATURE);
+        assert_eq!(u32_at(&bytes, 4), 1);
+        let data = "IDST batIDSVno extension".len();
+        assert_eq!(bytes.len(), 12 + u32_at(&bytes, 8) as usize + data);
+        let reader = VpkReader::open(&written[0]).unwrap();
+        assert_eq!(reader.version(), Version::V1);
+        check_contents(&reader);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn v2() {
+        let folder = test_folder("writer_v2");
+        let written = writer(Version::V2).write(&folder, "pak").unwrap();
+        let bytes = std::fs::read(&written[0]).unwrap();
+        assert_eq!(u32_at(&bytes, 4), 2);
+        let data = "IDST batIDSVno extension".len();
+        assert_eq!(u32_at(&bytes, 12) as usize, data);
+        assert_eq!(&bytes[16..28], &[0; 12]);
+        assert_eq!(bytes.len(), 28 + u32_at(&bytes, 8) as usize + data);
+        let reader = VpkReader::open(&written[0]).unwrap();
+        assert_eq!(reader.version(), Version::V2);
+        check_contents(&reader);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn same_files_same_bytes() {
+        let folder = test_folder("writer_deterministic");
+        let files = [
+            ("materials/a.vmt", "a"),
+            ("models/b.mdl", "bb"),
+            ("models/a.mdl", "ccc"),
+            ("models/sub/a.mdl", "dddd"),
+        ];
+        let mut forward = VpkWriter::new(Version::V2);
+        for (path, data) in files.iter() {
+            forward.add_data(path, data.as_bytes().to_vec()).unwrap();
+        }
+        let mut backward = VpkWriter::new(Version::V2);
+        for (path, data) in files.iter().rev() {
+            backward.add_data(path, data.as_bytes().to_vec()).unwrap();
+        }
+        // from disk as well
+        let on_disk = folder.join("disk");
+        std::fs::create_dir_all(on_disk.join("models/sub")).unwrap();
+        std::fs::create_dir_all(on_disk.join("materials")).unwrap();
+        for (path, data) in files.iter() {
+            std::fs::write(on_disk.join(path), data).unwrap();
+        }
+        let mut from_folder = VpkWriter::new(Version::V2);
+        from_folder.add_folder(&on_disk).unwrap();
+        let forward = forward.write(&folder, "forward").unwrap();
+        let backward = backward.write(&folder, "backward").unwrap();
+        let from_folder = from_folder.write(&folder, "folder").unwrap();
+        let bytes = std::fs::read(&forward[0]).unwrap();
+        assert_eq!(bytes, std::fs::read(&backward[0]).unwrap());
+        assert_eq!(bytes, std::fs::read(&from_folder[0]).unwrap());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn missing_files_fail_before_writing() {
+        let folder = test_folder("writer_missing");
+        let file = folder.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let mut vpk = VpkWriter::new(Version::V2);
+        vpk.add_file("a.txt", &file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let error = vpk.write(&folder, "pak").unwrap_err().to_string();
+        assert!(error.starts_with("Can't read"), "{}", error);
+        assert!(!folder.join("pak.vpk").exists());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn preload() {
+        let folder = test_folder("writer_preload");
+        let mut vpk = VpkWriter::new(Version::V2);
+        vpk.add_data("a.txt", b"0123456789".to_vec()).unwrap();
+        vpk.add_data("b.txt", b"012".to_vec()).unwrap();
+        vpk.set_preload("A.TXT", 4).unwrap();
+        // more than the file has
+        vpk.set_preload("b.txt", 16).unwrap();
+        assert!(vpk.set_preload("c.txt", 1).is_err());
+        let written = vpk.write(&folder, "pak").unwrap();
+        let reader = VpkReader::open(&written[0]).unwrap();
+        let a = reader.find("a.txt").unwrap();
+        assert_eq!(a.preload, b"0123");
+        assert_eq!(a.length, 6);
+        assert_eq!(a.offset, 0);
+        assert_eq!(reader.read(a).unwrap(), b"0123456789");
+        let b = reader.find("b.txt").unwrap();
+        assert_eq!(b.preload, b"012");
+        assert_eq!(b.length, 0);
+        assert_eq!(reader.read(b).unwrap(), b"012");
+        assert!(reader.verify().is_empty());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn chunks() {
+        let folder = test_folder("writer_chunks");
+        let mut vpk = VpkWriter::new(Version::V2).chunked(10);
+        for (name, size) in [("a", 4), ("b", 4), ("c", 20), ("d", 3), ("e", 0)] {
+            vpk.add_data(&format!("{}.bin", name), vec![name.as_bytes()[0]; size])
+                .unwrap();
+        }
+        let written = vpk.write(&folder, "pak").unwrap();
+        let names = written
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["pak_dir.vpk", "pak_000.vpk", "pak_001.vpk", "pak_002.vpk"]
+        );
+        assert_eq!(std::fs::metadata(&written[1]).unwrap().len(), 8);
+        // a file bigger than a chunk gets an archive of its own
+        assert_eq!(std::fs::metadata(&written[2]).unwrap().len(), 20);
+        let reader = VpkReader::open(&written[0]).unwrap();
+        let places = reader
+            .entries()
+            .iter()
+            .map(|entry| (entry.archive_index, entry.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            places,
+            [(0, 0), (0, 4), (1, 0), (2, 0), (DIR_ARCHIVE_INDEX, 0)]
+        );
+        assert_eq!(reader.read(reader.find("b.bin").unwrap()).unwrap(), b"bbbb");
+        assert!(reader.verify().is_empty());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_paths() {
+        let mut vpk = VpkWriter::new(Version::V1);
+        vpk.add_data("a.txt", Vec::new()).unwrap();
+        assert!(vpk.add_data("A.txt", Vec::new()).is_err());
+        assert!(vpk.add_data("folder/", Vec::new()).is_err());
+        assert!(vpk.add_data("", Vec::new()).is_err());
+    }
+}